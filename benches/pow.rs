@@ -0,0 +1,99 @@
+use std::fmt::Display;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rust_chain::{
+    block::Block,
+    hasher::{Hasher, Sha256Hasher},
+    pow::ProofOfWork,
+    transaction::Transaction,
+    Blockchainable,
+};
+use serde::{Deserialize, Serialize};
+
+const ADDRESS: &str = "1MBnzAR1oDnakY5fKLWvDeFD87PhPH9q1f";
+
+#[derive(Serialize, Deserialize)]
+struct Data(String);
+
+impl Display for Data {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Blockchainable for Data {
+    fn genesis_data() -> Self {
+        Data("Genesis".to_string())
+    }
+}
+
+fn template(difficulty_bits: u64) -> Block<Data> {
+    with_transactions(1, difficulty_bits)
+}
+
+/// Unsealed block holding `count` coinbases, standing in for `count` transactions
+fn with_transactions(count: usize, difficulty_bits: u64) -> Block<Data> {
+    let transactions = (0..count as u64)
+        .map(|i| Transaction::new_coinbase_tx(ADDRESS, &i.to_string(), Transaction::SUBSIDY, 0))
+        .collect::<Result<_, _>>()
+        .unwrap();
+    Block::template(transactions, Data::genesis_data(), None, 0, difficulty_bits)
+}
+
+/// Serial search from nonce 0 against the search split over every core
+fn mining(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mining");
+    group.sample_size(10);
+    for difficulty_bits in [12, 16] {
+        let block = template(difficulty_bits);
+        let pow = ProofOfWork::new(&block);
+        group.bench_with_input(
+            BenchmarkId::new("serial", difficulty_bits),
+            &pow,
+            |b, pow| b.iter(|| pow.run_deterministic()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("parallel", difficulty_bits),
+            &pow,
+            |b, pow| b.iter(|| pow.run()),
+        );
+    }
+    group.finish();
+}
+
+/// One nonce attempt hashing the whole serialized block, as proof of work did before
+/// `BlockHeader`, against one hashing the header prefix reused with the nonce appended
+fn nonce_hashing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nonce_hashing");
+    for count in [1, 100, 1000] {
+        let block = with_transactions(count, 16);
+        group.bench_with_input(
+            BenchmarkId::new("whole_block", count),
+            &block,
+            |b, block| {
+                let mut nonce = 0u64;
+                b.iter(|| {
+                    nonce += 1;
+                    let mut data = bincode::serialize(block).unwrap();
+                    data.extend_from_slice(&nonce.to_be_bytes());
+                    Sha256Hasher.hash(&data)
+                })
+            },
+        );
+        let prefix = block.header().prefix();
+        group.bench_with_input(BenchmarkId::new("header", count), &prefix, |b, prefix| {
+            let mut data = prefix.clone();
+            let mut nonce = 0u64;
+            b.iter(|| {
+                nonce += 1;
+                data.truncate(prefix.len());
+                data.extend_from_slice(&nonce.to_be_bytes());
+                Sha256Hasher.hash(&data)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, mining, nonce_hashing);
+criterion_main!(benches);
@@ -1,47 +1,270 @@
-use std::{
-    fmt::Display,
-    marker::PhantomData,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::{fmt::Display, time::SystemTime};
 
+use bincode::serialize;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 
-use crate::{pow::ProofOfWork, transaction::Transaction, Blockchainable};
+use crate::{
+    consensus::{Consensus, PowConsensus, SealResult},
+    error::BlockError,
+    hasher::{Hasher, Sha256Hasher},
+    merkle::merkle_root,
+    transaction::Transaction,
+    Blockchainable,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct Block<T> {
+    /// Stored as nanoseconds since Unix epoch
+    #[serde(with = "unix_nanos")]
     pub timestamp: SystemTime,
     pub transactions: Vec<Transaction>,
-    #[serde(with = "serde_bytes")]
+    /// Application data, committed to by the header through its hash
+    pub data: T,
+    /// Merkle root of `transactions`, what proof of work commits to
+    #[serde(with = "crate::hex_bytes")]
+    pub merkle_root: ByteBuf,
+    #[serde(with = "crate::hex_bytes::option")]
     pub previous_block_hash: Option<ByteBuf>,
-    #[serde(with = "serde_bytes")]
+    #[serde(with = "crate::hex_bytes::option")]
     pub hash: Option<ByteBuf>,
     pub nonce: Option<u64>,
-    phantom: PhantomData<T>,
+    /// Leading zero bits the block hash was mined with
+    pub difficulty_bits: u64,
+    /// Number of blocks below this one, genesis is at height 0
+    #[serde(default)]
+    pub height: u64,
+    /// Authority signature over the header of blocks sealed by `ProofOfAuthority`
+    #[serde(default, with = "crate::hex_bytes::option")]
+    pub signature: Option<ByteBuf>,
 }
 
 impl<T> Block<T> {
-    pub fn new(transactions: Vec<Transaction>, previous_block_hash: Option<ByteBuf>) -> Self
+    /// Block mined by `PowConsensus`
+    pub fn new(
+        transactions: Vec<Transaction>,
+        data: T,
+        previous_block_hash: Option<ByteBuf>,
+        height: u64,
+        difficulty_bits: u64,
+    ) -> Self
     where
         T: Blockchainable,
     {
-        let mut block = Block {
+        Self::new_with(
+            &PowConsensus,
+            transactions,
+            data,
+            previous_block_hash,
+            height,
+            difficulty_bits,
+        )
+    }
+
+    /// Block sealed by `consensus`, left without a hash when it could not be sealed
+    pub fn new_with(
+        consensus: &dyn Consensus<T>,
+        transactions: Vec<Transaction>,
+        data: T,
+        previous_block_hash: Option<ByteBuf>,
+        height: u64,
+        difficulty_bits: u64,
+    ) -> Self {
+        let mut block = Self::template(
+            transactions,
+            data,
+            previous_block_hash,
+            height,
+            difficulty_bits,
+        );
+        if let Some(seal) = consensus.seal(&block) {
+            block.apply_seal(seal);
+        }
+        block
+    }
+
+    pub fn apply_seal(&mut self, seal: SealResult) {
+        self.hash = Some(seal.hash);
+        self.nonce = seal.nonce;
+        self.signature = seal.signature;
+    }
+
+    /// Block ready to be mined, `hash` and `nonce` are not set yet
+    pub fn template(
+        transactions: Vec<Transaction>,
+        data: T,
+        previous_block_hash: Option<ByteBuf>,
+        height: u64,
+        difficulty_bits: u64,
+    ) -> Self {
+        Block {
             timestamp: SystemTime::now(),
+            merkle_root: merkle_root(&transactions),
             transactions,
-            previous_block_hash: previous_block_hash,
+            data,
+            previous_block_hash,
             hash: None,
             nonce: None,
-            phantom: PhantomData,
-        };
+            difficulty_bits,
+            height,
+            signature: None,
+        }
+    }
+}
+
+impl<T> Block<T>
+where
+    T: Blockchainable,
+{
+    /// SHA-256 of the bincode serialized `data`, how the header commits to it
+    pub fn data_hash(&self) -> ByteBuf {
+        let data = serialize(&self.data).expect("Serialization error!");
+        ByteBuf::from(Sha256Hasher.hash(&data))
+    }
 
-        let pow = ProofOfWork::new(&block);
-        if let Some((nonce, hash)) = pow.run() {
-            block.hash = Some(hash);
-            block.nonce = Some(nonce);
+    /// Header proof of work hashes, nonce is 0 for blocks not mined yet
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            timestamp: self.timestamp_unix(),
+            merkle_root: self.merkle_root.clone(),
+            data_hash: self.data_hash(),
+            previous_block_hash: self.previous_block_hash.clone(),
+            height: self.height,
+            difficulty_bits: self.difficulty_bits,
+            nonce: self.nonce.unwrap_or_default(),
         }
+    }
 
-        block
+    /// Size of the bincode serialized block, what `max_block_bytes` limits
+    pub fn size_bytes(&self) -> usize {
+        serialize(self).map(|block| block.len()).unwrap_or_default()
+    }
+
+    /// `validate_with` for chains sealed by `PowConsensus`
+    pub fn validate(&self, prev: Option<&Block<T>>) -> Result<(), BlockError> {
+        self.validate_with(prev, &PowConsensus)
+    }
+
+    /// Checks everything that only depends on the block and its parent `prev` (`None` for
+    /// genesis): hash link and height, merkle root, seal checked by `consensus`, timestamp
+    /// not older than the parent and a single coinbase as the first transaction, committing
+    /// to the block height with data no longer than `Transaction::MAX_COINBASE_DATA`
+    pub fn validate_with(
+        &self,
+        prev: Option<&Block<T>>,
+        consensus: &dyn Consensus<T>,
+    ) -> Result<(), BlockError> {
+        let height = prev.map_or(0, |prev| prev.height + 1);
+        match (prev, &self.previous_block_hash) {
+            (Some(prev), Some(prev_hash)) if prev.hash.as_ref() == Some(prev_hash) => {}
+            (None, None) => {}
+            (Some(_), None) => return Err(BlockError::MissingPreviousHash { height }),
+            (None, Some(_)) => return Err(BlockError::UnexpectedPreviousHash),
+            (Some(_), Some(_)) => return Err(BlockError::PreviousHashMismatch { height }),
+        }
+        if self.height != height {
+            return Err(BlockError::HeightMismatch {
+                expected: height,
+                actual: self.height,
+            });
+        }
+        if self.merkle_root != merkle_root(&self.transactions) {
+            return Err(BlockError::MerkleRootMismatch { height });
+        }
+        let sealed = match prev {
+            Some(_) => consensus.verify(self),
+            None => PowConsensus.verify(self),
+        };
+        if !sealed {
+            return Err(BlockError::InvalidSeal { height });
+        }
+        if prev.is_some_and(|prev| self.timestamp < prev.timestamp) {
+            return Err(BlockError::TimestampBeforeParent { height });
+        }
+        let count = self
+            .transactions
+            .iter()
+            .filter(|tx| tx.is_coinbase())
+            .count();
+        if count != 1 {
+            return Err(BlockError::CoinbaseCount { height, count });
+        }
+        if !self.transactions[0].is_coinbase() {
+            return Err(BlockError::CoinbaseNotFirst { height });
+        }
+        let len = self.transactions[0].vin[0].pub_key.len();
+        if len > Transaction::MAX_COINBASE_DATA {
+            return Err(BlockError::CoinbaseDataTooLong { height, len });
+        }
+        let actual = self.transactions[0].coinbase_height();
+        if actual != Some(height) {
+            return Err(BlockError::CoinbaseHeightMismatch { height, actual });
+        }
+        Ok(())
+    }
+}
+
+impl<T> Block<T> {
+    /// Timestamp as nanoseconds since Unix epoch, 0 for times before it
+    pub fn timestamp_unix(&self) -> u64 {
+        unix_nanos::from_time(&self.timestamp)
+    }
+}
+
+/// Fields of a block proof of work commits to. Transactions and data are only in through
+/// `merkle_root` and `data_hash`, so hashing a header costs the same however much a block holds
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockHeader {
+    /// Nanoseconds since Unix epoch
+    pub timestamp: u64,
+    pub merkle_root: ByteBuf,
+    pub data_hash: ByteBuf,
+    pub previous_block_hash: Option<ByteBuf>,
+    pub height: u64,
+    pub difficulty_bits: u64,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    /// Bytes proof of work hashes: `prefix` followed by the big endian nonce
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = self.prefix();
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+        bytes
+    }
+
+    /// Everything `bytes` holds before the nonce, what stays the same while mining
+    pub fn prefix(&self) -> Vec<u8> {
+        let fields = (
+            self.timestamp,
+            &self.merkle_root,
+            &self.data_hash,
+            &self.previous_block_hash,
+            self.height,
+        );
+        let mut bytes = serialize(&fields).expect("Serialization error!");
+        bytes.extend_from_slice(&self.difficulty_bits.to_be_bytes());
+        bytes
+    }
+}
+
+/// (De)serializes `SystemTime` as a plain `u64` of nanoseconds since Unix epoch
+mod unix_nanos {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn from_time(time: &SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64)
+    }
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(from_time(time))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        Ok(UNIX_EPOCH + Duration::from_nanos(u64::deserialize(deserializer)?))
     }
 }
 
@@ -50,19 +273,41 @@ where
     T: Blockchainable,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write_with(f, Transaction::describe)
+    }
+}
+
+impl<T> Block<T>
+where
+    T: Display,
+{
+    /// `Display` output with transactions rendered by `describe_tx` instead of
+    /// `Transaction::describe`
+    pub fn describe_with(&self, describe_tx: impl Fn(&Transaction) -> String) -> String {
+        let mut out = String::new();
+        self.write_with(&mut out, describe_tx)
+            .expect("Writing to a String never fails!");
+        out
+    }
+
+    fn write_with(
+        &self,
+        f: &mut impl std::fmt::Write,
+        describe_tx: impl Fn(&Transaction) -> String,
+    ) -> std::fmt::Result {
         writeln!(f, "Block")?;
-        writeln!(f, "\tDATA:")?;
+        writeln!(f, "\tDATA: {}", self.data)?;
+        writeln!(f, "\tTRANSACTIONS:")?;
         for (idx, t) in self.transactions.iter().enumerate() {
-            writeln!(f, "\t\tTX {idx}: {t}")?;
-        }
-        writeln!(
-            f,
-            "\tTIMESTAMP (NANOS): {}",
-            self.timestamp
-                .duration_since(UNIX_EPOCH)
-                .expect("Could not calculate elapsed time!")
-                .as_nanos()
-        )?;
+            let describe = describe_tx(t);
+            let mut lines = describe.lines();
+            writeln!(f, "\t\tTX {idx}: {}", lines.next().unwrap_or_default())?;
+            for line in lines {
+                writeln!(f, "\t\t{line}")?;
+            }
+        }
+        writeln!(f, "\tTIMESTAMP (NANOS): {}", self.timestamp_unix())?;
+        writeln!(f, "\tMERKLE ROOT: {}", hex::encode(&self.merkle_root))?;
         if let Some(prev) = self.previous_block_hash.clone() {
             writeln!(f, "\tPREVIOUS HASH: {}", hex::encode(prev))?
         } else {
@@ -78,7 +323,156 @@ where
         } else {
             writeln!(f, "\tNONCE: NOTHING YET")?
         }
+        if let Some(signature) = &self.signature {
+            writeln!(f, "\tSIGNATURE: {}", hex::encode(signature))?
+        }
+        writeln!(f, "\tDIFFICULTY BITS: {}", self.difficulty_bits)?;
+        writeln!(f, "\tHEIGHT: {}", self.height)?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::Wallet;
+
+    fn address() -> String {
+        String::from_utf8(Wallet::new().address().to_vec()).unwrap()
+    }
+
+    fn coinbase(height: u64) -> Transaction {
+        Transaction::new_coinbase_tx(&address(), "", Transaction::SUBSIDY, height).unwrap()
+    }
+
+    fn mined(
+        transactions: Vec<Transaction>,
+        prev: Option<&Block<String>>,
+        height: u64,
+    ) -> Block<String> {
+        let prev_hash = prev.and_then(|prev| prev.hash.clone());
+        Block::new(transactions, String::new(), prev_hash, height, 1)
+    }
+
+    #[test]
+    fn header_bytes_are_prefix_then_big_endian_nonce() {
+        let block = mined(vec![coinbase(0)], None, 0);
+        let header = block.header();
+        let nonce = block.nonce.unwrap();
+
+        assert_eq!(
+            header.bytes(),
+            [header.prefix(), nonce.to_be_bytes().to_vec()].concat()
+        );
+        let other = BlockHeader {
+            nonce: nonce + 1,
+            ..header.clone()
+        };
+        assert_eq!(other.prefix(), header.prefix());
+        assert_eq!(
+            block.hash.unwrap().to_vec(),
+            Sha256Hasher.hash(&header.bytes())
+        );
+    }
+
+    #[test]
+    fn more_transactions_make_a_bigger_block() {
+        let coinbase_only = mined(vec![coinbase(0)], None, 0);
+        let fuller = mined((0..4).map(coinbase).collect(), None, 0);
+
+        assert!(coinbase_only.size_bytes() > 0);
+        assert!(coinbase_only.size_bytes() < fuller.size_bytes());
+        assert_eq!(fuller.size_bytes(), serialize(&fuller).unwrap().len());
+    }
+
+    #[test]
+    fn valid_child_passes() {
+        let genesis = mined(vec![coinbase(0)], None, 0);
+        let child = mined(vec![coinbase(1)], Some(&genesis), 1);
+        assert_eq!(genesis.validate(None), Ok(()));
+        assert_eq!(child.validate(Some(&genesis)), Ok(()));
+    }
+
+    #[test]
+    fn wrong_previous_hash_and_wrong_height_are_told_apart() {
+        let genesis = mined(vec![coinbase(0)], None, 0);
+        let other = mined(vec![coinbase(0)], None, 0);
+
+        let unlinked = mined(vec![coinbase(1)], Some(&other), 1);
+        assert_eq!(
+            unlinked.validate(Some(&genesis)),
+            Err(BlockError::PreviousHashMismatch { height: 1 })
+        );
+
+        let misplaced = mined(vec![coinbase(1)], Some(&genesis), 2);
+        assert_eq!(
+            misplaced.validate(Some(&genesis)),
+            Err(BlockError::HeightMismatch {
+                expected: 1,
+                actual: 2
+            })
+        );
+    }
+
+    #[test]
+    fn coinbase_count_and_coinbase_height_are_told_apart() {
+        let genesis = mined(vec![coinbase(0)], None, 0);
+
+        let two = mined(vec![coinbase(1), coinbase(1)], Some(&genesis), 1);
+        assert_eq!(
+            two.validate(Some(&genesis)),
+            Err(BlockError::CoinbaseCount {
+                height: 1,
+                count: 2
+            })
+        );
+
+        let stale = mined(vec![coinbase(0)], Some(&genesis), 1);
+        assert_eq!(
+            stale.validate(Some(&genesis)),
+            Err(BlockError::CoinbaseHeightMismatch {
+                height: 1,
+                actual: Some(0)
+            })
+        );
+    }
+
+    #[test]
+    fn blocks_need_exactly_one_coinbase_first() {
+        let genesis = mined(vec![coinbase(0)], None, 0);
+        let mut spend = coinbase(1);
+        spend.vin[0].txid = ByteBuf::from(vec![1; 32]);
+        spend.vin[0].vout = Some(0);
+
+        let none = mined(vec![spend.clone()], Some(&genesis), 1);
+        assert_eq!(
+            none.validate(Some(&genesis)),
+            Err(BlockError::CoinbaseCount {
+                height: 1,
+                count: 0
+            })
+        );
+        let two = mined(vec![coinbase(1), coinbase(1)], Some(&genesis), 1);
+        assert!(matches!(
+            two.validate(Some(&genesis)),
+            Err(BlockError::CoinbaseCount { count: 2, .. })
+        ));
+        let last = mined(vec![spend, coinbase(1)], Some(&genesis), 1);
+        assert_eq!(
+            last.validate(Some(&genesis)),
+            Err(BlockError::CoinbaseNotFirst { height: 1 })
+        );
+    }
+
+    #[test]
+    fn tampered_transactions_break_merkle_root() {
+        let genesis = mined(vec![coinbase(0)], None, 0);
+        let mut child = mined(vec![coinbase(1)], Some(&genesis), 1);
+        child.transactions = vec![coinbase(1)];
+        assert_eq!(
+            child.validate(Some(&genesis)),
+            Err(BlockError::MerkleRootMismatch { height: 1 })
+        );
+    }
+}
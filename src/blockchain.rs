@@ -1,232 +1,3327 @@
-use std::{collections::HashMap, marker::PhantomData};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io::{Read, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use bincode::{deserialize, serialize};
+use num_bigint::BigUint;
+use rayon::ThreadPoolBuilder;
 use serde_bytes::ByteBuf;
-use sled::Db;
+use sled::{
+    transaction::{ConflictableTransactionError, Transactional},
+    Db, Event, Subscriber, Tree,
+};
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    block::Block, transaction::{TXOutput, Transaction}, wallet::Wallet, Blockchainable
+    block::Block,
+    checkpoint::CheckpointSet,
+    consensus::{Consensus, PowConsensus, SealResult},
+    error::{BlockError, ChainError},
+    genesis::GenesisConfig,
+    hasher::{Hasher, Sha256Hasher},
+    mempool::Mempool,
+    pow::ProofOfWork,
+    transaction::{checked_sum, ScriptType, TXOutput, Transaction},
+    utxo_set::UTXOSet,
+    wallet::{Address, Wallet, Wallets},
+    Blockchainable,
 };
 
+/// Node settings a chain is opened with that genesis does not fix
+#[derive(Clone, Debug, Default)]
+pub struct ChainConfig {
+    /// `(height, hex block hash)` pairs checked on top of the hardcoded checkpoints
+    pub checkpoints: Vec<(u64, String)>,
+}
+
 pub struct Blockchain<T> {
     pub tip: ByteBuf,
     pub db: Db,
-    phantom: PhantomData<T>,
+    checkpoints: CheckpointSet,
+    /// Difficulty genesis is mined at when the chain is created, later blocks follow genesis
+    pub difficulty_bits: u64,
+    /// Number of blocks between difficulty adjustments
+    pub retarget_window: u64,
+    /// Desired time between two blocks
+    pub target_block_time: Duration,
+    /// Wallets file signing keys for `send` are looked up in
+    pub wallets_file: PathBuf,
+    /// Address rewarded for blocks made by `mine_block`
+    pub miner_address: Option<String>,
+    /// Most mempool transactions put into one block
+    pub max_block_txs: usize,
+    /// Largest serialized size of a block, coinbase included
+    pub max_block_bytes: usize,
+    /// Most transactions waiting in the mempool
+    pub mempool_max_txs: usize,
+    /// Most serialized transaction bytes waiting in the mempool
+    pub mempool_max_bytes: usize,
+    /// Number of blocks after which the block reward halves
+    pub halving_interval: u64,
+    /// How far ahead of local clock a block timestamp may be
+    pub max_future_drift: Duration,
+    /// Lowest fee per 1000 bytes `estimate_fee` suggests
+    pub min_fee_rate: u64,
+    /// Blocks a coinbase must be buried under before it is spent: the coinbase of block `h`
+    /// may be spent from block `h + coinbase_maturity` on, 0 and 1 allow the very next block
+    pub coinbase_maturity: u64,
+    /// Id from genesis every signature is bound to, see `GenesisConfig::chain_id`
+    pub chain_id: u32,
+    /// How blocks past genesis are sealed and checked, `PowConsensus` by default
+    pub consensus: Arc<dyn Consensus<T>>,
+    /// Set by `open_existing_read_only`
+    read_only: bool,
+    phantom: PhantomData<fn() -> T>,
 }
 
 impl<T> Blockchain<T> {
     pub const DB_FILE: &'static str = "blockchain.kv";
     pub const BLOCKS_BUCKET: &'static str = "blocks";
-    const GENESIS_COINBASE: &'static str =
-        "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+    /// Valid blocks off the active chain, kept in case their branch overtakes it
+    pub const SIDE_BUCKET: &'static str = "side_blocks";
+    /// Cumulative proof of work from genesis up to and including a block, keyed by its hash
+    pub const WORK_BUCKET: &'static str = "chain_work";
+    /// Maps id of every transaction in the active chain to the hash of its block
+    pub const TX_INDEX_BUCKET: &'static str = "tx_index";
+    /// Key of the genesis chain id cached in the default tree
+    const CHAIN_ID_KEY: &'static [u8] = b"chain_id";
+    pub const RETARGET_WINDOW: u64 = 2016;
+    pub const TARGET_BLOCK_TIME: Duration = Duration::from_secs(10 * 60);
+    pub const MAX_BLOCK_TXS: usize = 100;
+    pub const MAX_BLOCK_BYTES: usize = 1_000_000;
+    pub const HALVING_INTERVAL: u64 = 210_000;
+    pub const MAX_FUTURE_DRIFT: Duration = Duration::from_secs(2 * 60 * 60);
+    pub const MIN_FEE_RATE: u64 = 1;
+    pub const COINBASE_MATURITY: u64 = 0;
+    /// Number of blocks below the tip `estimate_fee` samples fee rates from
+    pub const FEE_SAMPLE_BLOCKS: usize = 10;
+    /// Difficulty of chains made by `new_test`, a couple of hashes per block
+    #[cfg(test)]
+    pub const TEST_BITS: u64 = 1;
+    /// Length of a fixed size P-256 ECDSA signature, what `ProofOfAuthority` seals with
+    const SIGNATURE_LEN: usize = 64;
+    /// Most bits difficulty can move by in one retarget (a factor of 4, like Bitcoin)
+    const MAX_RETARGET_BITS: i64 = 2;
 
-    pub fn new(address: &String) -> Self
+    pub fn new(address: &str) -> Result<Self, ChainError>
     where
         T: Blockchainable,
     {
-        let db = sled::open(Self::DB_FILE).expect("Could not open db file!");
-        let blocks = db
-            .open_tree(Self::BLOCKS_BUCKET)
-            .expect("Could not open blocks bucket!");
-        let last_hash = blocks.get(b"l").expect("Get value error!");
+        Self::open(Self::DB_FILE, address)
+    }
+
+    pub fn new_with_difficulty(address: &str, difficulty_bits: u64) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        Self::open_with_difficulty(Self::DB_FILE, address, difficulty_bits)
+    }
+
+    /// Chain in the default db whose genesis block, if it has to be mined, is built from `config`
+    pub fn new_with_genesis(config: &GenesisConfig) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        Self::open_with_genesis(Self::DB_FILE, config, ProofOfWork::<T>::TARGET_BITS)
+    }
+
+    /// Opens chain stored in sled directory at `path`, mining genesis to `address` if there is none
+    pub fn open(path: impl AsRef<Path>, address: &str) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        Self::open_with_difficulty(path, address, ProofOfWork::<T>::TARGET_BITS)
+    }
+
+    pub fn open_with_difficulty(
+        path: impl AsRef<Path>,
+        address: &str,
+        difficulty_bits: u64,
+    ) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        Self::open_with_genesis(path, &GenesisConfig::new(address), difficulty_bits)
+    }
+
+    /// Opens chain stored in sled directory at `path`, mining genesis from `config` if there is none
+    pub fn open_with_genesis(
+        path: impl AsRef<Path>,
+        config: &GenesisConfig,
+        difficulty_bits: u64,
+    ) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        Self::from_db(sled::open(path)?, config, difficulty_bits)
+    }
+
+    /// Chain in a temporary in-memory sled db, gone once dropped. Mines genesis to `address`
+    pub fn new_in_memory(address: &str) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        Self::new_in_memory_with_difficulty(address, ProofOfWork::<T>::TARGET_BITS)
+    }
+
+    /// Like `new_in_memory`, mining genesis at `difficulty_bits`
+    pub fn new_in_memory_with_difficulty(
+        address: &str,
+        difficulty_bits: u64,
+    ) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let db = sled::Config::new().temporary(true).open()?;
+        Self::from_db(db, &GenesisConfig::new(address), difficulty_bits)
+    }
+
+    /// Chain for tests mined at `TEST_BITS` in a temporary sled directory holding its wallets
+    /// too, all removed once dropped. Mines genesis to `address`
+    #[cfg(test)]
+    pub fn new_test(address: &str) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "rust-chain-test-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        let db = sled::Config::new().path(&dir).temporary(true).open()?;
+        let mut blockchain = Self::from_db(db, &GenesisConfig::new(address), Self::TEST_BITS)?;
+        blockchain.wallets_file = dir.join(Wallets::WALLETS_FILE);
+        Ok(blockchain)
+    }
+
+    /// Chain stored in an already opened `db`, whatever its sled config, mining genesis from
+    /// `config` if there is none
+    pub fn from_db(db: Db, config: &GenesisConfig, difficulty_bits: u64) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        Self::from_db_with_config(db, config, &ChainConfig::default(), difficulty_bits)
+    }
+
+    /// `from_db` checking blocks against the custom checkpoints of `chain` too
+    pub fn from_db_with_config(
+        db: Db,
+        config: &GenesisConfig,
+        chain: &ChainConfig,
+        difficulty_bits: u64,
+    ) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let checkpoints = CheckpointSet::with_custom(&chain.checkpoints)?;
+        let blocks = db.open_tree(Self::BLOCKS_BUCKET)?;
+        let last_hash = blocks.get(b"l")?;
         let tip = if let Some(lh) = last_hash {
             ByteBuf::from(lh.to_vec())
         } else {
-            let genesis_block = Block::<T>::new(
-                vec![Transaction::new_coinbase_tx(
-                    &address,
-                    &Self::GENESIS_COINBASE.to_string(),
-                )],
-                None,
-            );
-            if let Some(hash) = &genesis_block.hash {
-                blocks
-                    .insert(
-                        hash,
-                        serialize(&genesis_block).expect("Serialization error!"),
-                    )
-                    .expect("Insertion error!");
-                blocks
-                    .insert(b"l", hash.to_vec())
-                    .expect("Insertion error!");
-                hash.clone()
-            } else {
-                ByteBuf::new()
-            }
+            Self::store_block(&db, &config.block::<T>(difficulty_bits)?)?
+        };
+        let mut blockchain = Self::with_stored_db(db, tip, difficulty_bits, false)?;
+        blockchain.checkpoints = checkpoints;
+        Ok(blockchain)
+    }
+
+    /// Opens chain stored in sled directory at `path` without ever mining genesis, `None`
+    /// when nothing is stored there yet
+    pub fn open_existing(path: impl AsRef<Path>) -> Result<Option<Self>, ChainError>
+    where
+        T: Blockchainable,
+    {
+        Self::open_stored(path.as_ref(), false)
+    }
+
+    /// Like `open_existing`, but nothing is written on open, on drop or by `wallets`, so the
+    /// chain can be inspected without changing it. Indexes the chain lacks are not built
+    pub fn open_existing_read_only(path: impl AsRef<Path>) -> Result<Option<Self>, ChainError>
+    where
+        T: Blockchainable,
+    {
+        Self::open_stored(path.as_ref(), true)
+    }
+
+    fn open_stored(path: &Path, read_only: bool) -> Result<Option<Self>, ChainError>
+    where
+        T: Blockchainable,
+    {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let db = sled::open(path)?;
+        let Some(tip) = db.open_tree(Self::BLOCKS_BUCKET)?.get(b"l")? else {
+            return Ok(None);
         };
+        let tip = ByteBuf::from(tip.to_vec());
+        Self::with_stored_db(db, tip, ProofOfWork::<T>::TARGET_BITS, read_only).map(Some)
+    }
+
+    /// `with_db` for a chain already holding blocks, building indexes it may lack unless
+    /// `read_only`
+    fn with_stored_db(
+        db: Db,
+        tip: ByteBuf,
+        difficulty_bits: u64,
+        read_only: bool,
+    ) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let mut blockchain = Self::with_db(db, tip, difficulty_bits);
+        blockchain.read_only = read_only;
+        blockchain.chain_id = blockchain.load_chain_id()?;
+        if read_only {
+            return Ok(blockchain);
+        }
+        // Reads need it too, a later read only open must find it instead of creating it
+        blockchain.mempool()?;
+
+        // Chains created before the UTXO set or work index existed get them built on first open
+        let utxo_set = blockchain.utxo_set()?;
+        if utxo_set.is_empty() && !blockchain.tip.is_empty() {
+            utxo_set.reindex(&blockchain)?;
+        }
+        if blockchain.db.open_tree(Self::WORK_BUCKET)?.is_empty() {
+            blockchain.reindex_work()?;
+        }
+        if blockchain.db.open_tree(Self::TX_INDEX_BUCKET)?.is_empty() {
+            blockchain.reindex_transactions()?;
+        }
+
+        Ok(blockchain)
+    }
 
+    fn with_db(db: Db, tip: ByteBuf, difficulty_bits: u64) -> Self
+    where
+        T: Blockchainable,
+    {
         Blockchain {
             tip,
             db,
+            checkpoints: CheckpointSet::default(),
+            difficulty_bits,
+            retarget_window: Self::RETARGET_WINDOW,
+            target_block_time: Self::TARGET_BLOCK_TIME,
+            wallets_file: PathBuf::from(Wallets::WALLETS_FILE),
+            miner_address: None,
+            max_block_txs: Self::MAX_BLOCK_TXS,
+            max_block_bytes: Self::MAX_BLOCK_BYTES,
+            mempool_max_txs: Mempool::MAX_COUNT,
+            mempool_max_bytes: Mempool::MAX_BYTES,
+            halving_interval: Self::HALVING_INTERVAL,
+            max_future_drift: Self::MAX_FUTURE_DRIFT,
+            min_fee_rate: Self::MIN_FEE_RATE,
+            coinbase_maturity: Self::COINBASE_MATURITY,
+            chain_id: GenesisConfig::DEFAULT_CHAIN_ID,
+            consensus: Arc::new(PowConsensus),
+            read_only: false,
             phantom: PhantomData,
         }
     }
 
-    pub fn add_block(&mut self, data: Vec<Transaction>)
+    /// Writes every block, genesis first, as a JSON array streamed one block at a time
+    pub fn export_json(&self, writer: impl Write) -> Result<(), ChainError>
     where
         T: Blockchainable,
     {
-        let blocks = self
-            .db
-            .open_tree(Self::BLOCKS_BUCKET)
-            .expect("Could not open blocks bucket!");
-        let last_hash = blocks
-            .get(b"l")
-            .expect("Get value error!")
-            .map(|v| ByteBuf::from(v.to_vec()));
+        let mut json = serde_json::Serializer::new(writer);
+        serde::Serializer::collect_seq(&mut json, self.iter_forward())?;
+        Ok(())
+    }
 
-        let new_block = Block::<T>::new(data, last_hash);
-        if let Some(hash) = &new_block.hash {
-            blocks
-                .insert(hash, serialize(&new_block).expect("Serialization error"))
-                .expect("Insertion error");
-            blocks.insert(b"l", hash.to_vec()).expect("Insertion error");
-            self.tip = hash.clone();
+    /// Rebuilds chain at `path` from a dump made by `export_json`, replacing whatever
+    /// chain, side branches and mempool are stored there. Every block is checked the way
+    /// `accept_block` checks it on a scratch copy and the result has its supply audited, so
+    /// nothing is written unless every block in the dump is valid
+    pub fn import_json(reader: impl Read, path: impl AsRef<Path>) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let blocks: Vec<Block<T>> = serde_json::from_reader(reader)?;
+        let difficulty_bits = blocks
+            .first()
+            .map_or(ProofOfWork::<T>::TARGET_BITS, |genesis| {
+                genesis.difficulty_bits
+            });
+        let mut scratch = Self::with_db(
+            sled::Config::new().temporary(true).open()?,
+            ByteBuf::new(),
+            difficulty_bits,
+        );
+        if let Some(genesis) = blocks.first() {
+            scratch.chain_id = GenesisConfig::chain_id_of(genesis);
         }
-    }
+        for block in blocks {
+            scratch
+                .accept_block(block)
+                .inspect_err(|e| warn!("Rejecting chain dump: {e}"))?;
+        }
+        scratch
+            .audit_supply()
+            .inspect_err(|e| warn!("Rejecting chain dump: {e}"))?;
 
-    pub fn remove_blocks(&self) {
-        let blocks = self
-            .db
-            .open_tree(Self::BLOCKS_BUCKET)
-            .expect("Could not open blocks bucket!");
+        let db = sled::open(path)?;
+        db.open_tree(Self::SIDE_BUCKET)?.clear()?;
+        db.open_tree(Self::WORK_BUCKET)?.clear()?;
+        Mempool::open(&db)?.clear()?;
+        db.remove(Self::CHAIN_ID_KEY)?;
+        let mut blockchain = Self::with_db(db, ByteBuf::new(), difficulty_bits);
+        blockchain.adopt(&scratch)?;
+        blockchain.chain_id = blockchain.load_chain_id()?;
+        Ok(blockchain)
+    }
 
-        blocks.iter().for_each(|p| {
-            if let Ok((key, _)) = p {
-                blocks.remove(key).expect("Could not remove key!");
+    /// Chain id of genesis, cached in the default tree after the first lookup
+    fn load_chain_id(&self) -> Result<u32, ChainError>
+    where
+        T: Blockchainable,
+    {
+        if let Some(bytes) = self.db.get(Self::CHAIN_ID_KEY)? {
+            if let Ok(bytes) = bytes.as_ref().try_into() {
+                return Ok(u32::from_be_bytes(bytes));
             }
-        })
+        }
+        let chain_id = self
+            .get_block_by_height(0)
+            .map_or(GenesisConfig::DEFAULT_CHAIN_ID, |genesis| {
+                GenesisConfig::chain_id_of(&genesis)
+            });
+        if !self.read_only {
+            self.db
+                .insert(Self::CHAIN_ID_KEY, &chain_id.to_be_bytes())?;
+        }
+        Ok(chain_id)
+    }
+
+    /// Checks block is not more than `max_future_drift` ahead of local clock
+    fn check_timestamp(block: &Block<T>, max_future_drift: Duration) -> Result<(), ChainError> {
+        if block.timestamp > SystemTime::now() + max_future_drift {
+            return Err(ChainError::InvalidTimestamp {
+                height: block.height,
+            });
+        }
+        Ok(())
     }
 
-    pub fn find_unspent_txs(&mut self, address: &ByteBuf) -> Vec<Transaction>
+    /// Switches to `blocks`, a whole chain ordered genesis first, if it has more work than
+    /// this one. Blocks past the prefix shared with this chain are fully validated on a
+    /// scratch copy and the result has its supply audited before anything is written, so an
+    /// invalid chain leaves this one untouched. Returns whether the chain changed
+    pub fn replace_chain(&mut self, blocks: Vec<Block<T>>) -> Result<bool, ChainError>
     where
         T: Blockchainable,
     {
-        let mut spent_txos: HashMap<ByteBuf, Vec<usize>> = HashMap::new();
-        let mut unspent_txs: Vec<Transaction> = Vec::new();
+        let keep = blocks
+            .iter()
+            .enumerate()
+            .take_while(|(height, block)| {
+                block.height == *height as u64
+                    && block
+                        .hash
+                        .as_ref()
+                        .is_some_and(|hash| self.contains_block(hash))
+            })
+            .count();
+        let mut fork = self.fork(keep as u64)?;
+        for block in blocks.into_iter().skip(keep) {
+            fork.accept_block(block)?;
+        }
+        fork.audit_supply()
+            .inspect_err(|e| warn!("Rejecting candidate chain: {e}"))?;
+        if fork.total_work() <= self.total_work() {
+            return Ok(false);
+        }
 
-        for block in self {
-            for tx in block.transactions {
-                'outs: for (this_idx, vout) in tx.vout.iter().enumerate() {
-                    if let Some(indicies) = spent_txos.get(&tx.id) {
-                        for idx in indicies {
-                            if *idx == this_idx {
-                                continue 'outs;
-                            }
-                        }
-                    }
+        self.adopt(&fork)?;
+        info!(height = fork.height(), "Switched to heavier chain");
+        Ok(true)
+    }
 
-                    if vout.is_locked_with(address) {
-                        unspent_txs.push(tx.clone());
-                    }
-                }
+    /// Makes the active chain of `fork` this one. Blocks, UTXO set and transaction index are
+    /// swapped for the fork's and its chain work recorded in one sled transaction, so a crash
+    /// leaves either chain whole. Transactions of the new chain leave the mempool
+    fn adopt(&mut self, fork: &Self) -> Result<(), ChainError>
+    where
+        T: Blockchainable,
+    {
+        let blocks = self.db.open_tree(Self::BLOCKS_BUCKET)?;
+        let utxos = self.db.open_tree(UTXOSet::UTXO_BUCKET)?;
+        let tx_index = self.db.open_tree(Self::TX_INDEX_BUCKET)?;
+        let works = self.db.open_tree(Self::WORK_BUCKET)?;
+        // Side branches keep their chain work, only the replaced trees lose every old entry
+        let stale = [&blocks, &utxos, &tx_index]
+            .iter()
+            .map(|tree| tree.iter().keys().collect::<Result<Vec<_>, _>>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let fresh = [
+            Self::BLOCKS_BUCKET,
+            UTXOSet::UTXO_BUCKET,
+            Self::TX_INDEX_BUCKET,
+            Self::WORK_BUCKET,
+        ]
+        .iter()
+        .map(|name| {
+            Ok(fork
+                .db
+                .open_tree(name)?
+                .iter()
+                .collect::<Result<Vec<_>, _>>()?)
+        })
+        .collect::<Result<Vec<_>, ChainError>>()?;
 
-                if !tx.is_coinbase() {
-                    for vin in &tx.vin {
-                        if vin.uses_key(address) {
-                            if let Some(indicies) = spent_txos.get_mut(&vin.txid) {
-                                indicies.push(vin.vout.expect("How tf!"));
-                            } else {
-                                spent_txos
-                                    .insert(vin.txid.clone(), vec![vin.vout.expect("How tf!")]);
-                            }
-                        }
-                    }
+        (&blocks, &utxos, &tx_index, &works).transaction(|(blocks, utxos, tx_index, works)| {
+            for (tree, keys) in [blocks, utxos, tx_index].into_iter().zip(&stale) {
+                for key in keys {
+                    tree.remove(key)?;
                 }
-
-                if let Some(ref ph) = block.previous_block_hash {
-                    if ph.len() == 0 {
-                        break;
-                    }
+            }
+            for (tree, entries) in [blocks, utxos, tx_index, works].into_iter().zip(&fresh) {
+                for (key, value) in entries {
+                    tree.insert(key, value)?;
                 }
             }
+            Ok::<_, ConflictableTransactionError<ChainError>>(())
+        })?;
+        self.tip = fork.tip.clone();
+
+        let mempool = self.mempool()?;
+        for block in fork.iter() {
+            for tx in &block.transactions {
+                mempool.remove(&tx.id)?;
+            }
         }
+        Ok(())
+    }
 
-        unspent_txs
+    /// Copy of the first `keep` blocks in a temporary database, with the same consensus settings
+    fn fork(&self, keep: u64) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let db = sled::Config::new().temporary(true).open()?;
+        let blocks = db.open_tree(Self::BLOCKS_BUCKET)?;
+        let mut tip = ByteBuf::new();
+        for block in self.iter().filter(|block| block.height < keep) {
+            let hash = block.hash.clone().unwrap_or_default();
+            if tip.is_empty() {
+                tip = hash.clone();
+            }
+            blocks.insert(&hash, serialize(&block)?)?;
+        }
+        if !tip.is_empty() {
+            blocks.insert(b"l", tip.to_vec())?;
+        }
+
+        let mut fork = Self::with_db(db, tip, self.difficulty_bits);
+        fork.chain_id = self.chain_id;
+        fork.checkpoints = self.checkpoints.clone();
+        fork.retarget_window = self.retarget_window;
+        fork.target_block_time = self.target_block_time;
+        fork.halving_interval = self.halving_interval;
+        fork.max_future_drift = self.max_future_drift;
+        fork.coinbase_maturity = self.coinbase_maturity;
+        fork.max_block_bytes = self.max_block_bytes;
+        fork.consensus = Arc::clone(&self.consensus);
+        fork.utxo_set()?.reindex(&fork)?;
+        fork.reindex_work()?;
+        fork.reindex_transactions()?;
+        Ok(fork)
+    }
+
+    pub fn utxo_set(&self) -> Result<UTXOSet, ChainError> {
+        UTXOSet::open(&self.db)
+    }
+
+    pub fn mempool(&self) -> Result<Mempool, ChainError> {
+        Ok(Mempool::open(&self.db)?.with_limits(self.mempool_max_txs, self.mempool_max_bytes))
     }
 
-    pub fn find_utxo(&mut self, address: &ByteBuf) -> Vec<TXOutput>
+    /// Validates transactions, exactly one of them a coinbase, and mines them into a new block
+    /// carrying `T::default_data`
+    pub fn add_block(&mut self, txs: Vec<Transaction>) -> Result<(), ChainError>
     where
         T: Blockchainable,
     {
-        self.find_unspent_txs(address)
-            .iter()
-            .flat_map(|utx| utx.vout.clone())
-            .filter(|txo| txo.is_locked_with(address))
-            .collect()
+        self.add_block_with_data(txs, T::default_data())
     }
 
-    pub fn balance_at(&mut self, address: &ByteBuf) -> u64
+    /// Like `add_block`, but the new block carries `data`
+    pub fn add_block_with_data(&mut self, txs: Vec<Transaction>, data: T) -> Result<(), ChainError>
     where
         T: Blockchainable,
     {
-        let pub_key_hash = bs58::decode(address).with_alphabet(bs58::Alphabet::BITCOIN).into_vec().expect("Address could not be decoded to base58!");
-        let pub_key_hash = ByteBuf::from(&pub_key_hash[1..pub_key_hash.len() - Wallet::CHECKSUM_LEN]);
-        let f = self.find_utxo(&pub_key_hash);
-        f.iter().fold(0, |acc, utxo| utxo.value + acc)
+        // Caught before mining, `validate_block` would only reject the block after all the work
+        let count = txs.iter().filter(|tx| tx.is_coinbase()).count();
+        if count != 1 {
+            return Err(BlockError::CoinbaseCount {
+                height: self.next_height()?,
+                count,
+            }
+            .into());
+        }
+        let size = Self::block_bytes(&txs, &data);
+        if size > self.max_block_bytes {
+            return Err(ChainError::BlockTooLarge {
+                height: self.next_height()?,
+                size,
+                max: self.max_block_bytes,
+            });
+        }
+        let (txs, _) = self.prepare_transactions(txs)?;
+        self.mine_transactions(txs, data)
     }
 
-    pub fn send(&mut self, from: &String, to: &String, value: u64)
+    /// Mines already verified transactions into a new block on top of the tip
+    fn mine_transactions(&mut self, txs: Vec<Transaction>, data: T) -> Result<(), ChainError>
     where
         T: Blockchainable,
     {
-        let tx = Transaction::new_tx(to, from, value, self);
-        self.add_block(vec![tx]);
+        let consensus = Arc::clone(&self.consensus);
+        self.seal_transactions(txs, data, |block| consensus.seal(block))
     }
 
-    pub fn find_spendable_outputs(
+    /// Block of `txs` and `data` on top of the tip sealed by `seal`, inserted and flushed
+    fn seal_transactions(
         &mut self,
-        address: &ByteBuf,
-        value: u64,
-    ) -> (u64, HashMap<ByteBuf, Vec<usize>>)
+        txs: Vec<Transaction>,
+        data: T,
+        seal: impl FnOnce(&Block<T>) -> Option<SealResult>,
+    ) -> Result<(), ChainError>
     where
         T: Blockchainable,
     {
-        let mut unspent_outputs: HashMap<ByteBuf, Vec<usize>> = HashMap::new();
-        let unspent_tx = self.find_unspent_txs(address);
-        let mut all = 0;
+        let last_hash = self.last_hash()?;
+        let last_hash = (!last_hash.is_empty()).then_some(last_hash);
 
-        'outer: for tx in unspent_tx {
-            for (idx, vout) in tx.vout.iter().enumerate() {
-                if vout.is_locked_with(address) && all < value {
-                    all += vout.value;
-                    if let Some(indicies) = unspent_outputs.get_mut(&tx.id) {
-                        indicies.push(idx);
-                    } else {
-                        unspent_outputs.insert(tx.id.clone(), vec![idx]);
-                    }
+        let height = self.next_height()?;
+        let difficulty_bits = self.next_difficulty()?;
+        let mut new_block = Block::template(txs, data, last_hash, height, difficulty_bits);
+        if let Some(seal) = seal(&new_block) {
+            new_block.apply_seal(seal);
+        }
+        self.insert_block(&new_block)?;
+        self.flush()
+    }
 
-                    if all >= value {
-                        break 'outer;
-                    }
+    /// Mines pending mempool transactions into a new block rewarding `miner_address`,
+    /// returns hash of the new block once it is flushed to disk
+    pub fn mine_block(&mut self) -> Result<ByteBuf, ChainError>
+    where
+        T: Blockchainable,
+    {
+        self.mine_block_with_data(T::default_data())
+    }
+
+    /// Like `mine_block`, but the new block carries `data`
+    pub fn mine_block_with_data(&mut self, data: T) -> Result<ByteBuf, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let miner_address = self
+            .miner_address
+            .clone()
+            .ok_or(ChainError::NoMinerAddress)?;
+        let txs = self.block_transactions(&miner_address, &data)?;
+        self.mine_transactions(txs, data)?;
+        Ok(self.tip.clone())
+    }
+
+    /// Subsidy for block at `height`, halved every `halving_interval` blocks until it reaches zero
+    pub fn block_reward(&self, height: u64) -> u64 {
+        let halvings = height.checked_div(self.halving_interval).unwrap_or(0);
+        Transaction::SUBSIDY
+            .checked_shr(halvings as u32)
+            .unwrap_or(0)
+    }
+
+    /// Suggested fee per 1000 bytes for a transaction to be mined within `target_blocks`,
+    /// picked among rates paid in the last `FEE_SAMPLE_BLOCKS` blocks: the highest for the
+    /// next block, nearing the median for distant targets. Never below `min_fee_rate`, which
+    /// is also suggested when no recent transaction paid a fee
+    pub fn estimate_fee(&self, target_blocks: usize) -> u64
+    where
+        T: Blockchainable,
+    {
+        let mut rates: Vec<u64> = self
+            .iter()
+            .take(Self::FEE_SAMPLE_BLOCKS)
+            .flat_map(|block| block.transactions)
+            .filter(|tx| !tx.is_coinbase())
+            .filter_map(|tx| {
+                let fee = tx.fee(&self.collect_prev_txs(&tx)).ok()?;
+                Some(fee.saturating_mul(1000) / tx.size_bytes().max(1) as u64)
+            })
+            .collect();
+        if rates.is_empty() {
+            return self.min_fee_rate;
+        }
+        rates.sort_unstable();
+        let quantile = 0.5 + 0.5 / target_blocks.max(1) as f64;
+        let idx = ((rates.len() - 1) as f64 * quantile).round() as usize;
+        rates[idx].max(self.min_fee_rate)
+    }
+
+    /// Fee rates per 1000 bytes paid by mempool transactions, with how many blocks a
+    /// transaction paying each of them waits for when blocks fill up best paying first.
+    /// Rates are all `min_fee_rate` while the mempool is empty or can not be read
+    pub fn fee_market_stats(&self) -> FeeMarketStats {
+        let rates = self
+            .mempool()
+            .and_then(|mempool| mempool.fee_rates())
+            .unwrap_or_default();
+        let mut stats = FeeMarketStats {
+            min_fee_rate: self.min_fee_rate,
+            max_fee_rate: self.min_fee_rate,
+            median_fee_rate: self.min_fee_rate,
+            mempool_tx_count: rates.len(),
+            mempool_total_bytes: rates.iter().map(|(_, size)| size).sum(),
+            estimated_blocks_to_confirm: HashMap::new(),
+        };
+        let (Some((max, _)), Some((min, _))) = (rates.first(), rates.last()) else {
+            return stats;
+        };
+        stats.max_fee_rate = *max;
+        stats.min_fee_rate = *min;
+        stats.median_fee_rate = rates[rates.len() / 2].0;
+
+        // Transactions paying the same rate wait for the last of them, so later ones overwrite
+        let max_txs = self.max_block_txs.max(1) as u64;
+        let max_bytes = self.max_block_bytes.max(1) as u64;
+        let mut bytes = 0;
+        for (count, (rate, size)) in (1u64..).zip(&rates) {
+            bytes += *size as u64;
+            let blocks = count.div_ceil(max_txs).max(bytes.div_ceil(max_bytes));
+            stats.estimated_blocks_to_confirm.insert(*rate, blocks);
+        }
+        stats
+    }
+
+    /// Verified coinbase paying `miner_address` the block reward plus fees, followed by
+    /// up to `max_block_txs` mempool transactions, best paying first, that fit in
+    /// `max_block_bytes`. Transactions that do not fit or spend an immature coinbase stay in
+    /// the mempool, invalid ones are evicted from it
+    fn block_transactions(
+        &self,
+        miner_address: &str,
+        data: &T,
+    ) -> Result<Vec<Transaction>, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let height = self.next_height()?;
+        let reward = self.block_reward(height);
+        let message = format!("Reward to {miner_address}");
+        // Coinbase size does not depend on its value, so the final one takes as much room
+        let coinbase = Transaction::new_coinbase_tx(miner_address, &message, reward, height)?;
+        let mut size = Self::block_bytes(std::slice::from_ref(&coinbase), data);
+
+        let mempool = self.mempool()?;
+        let utxo_set = self.utxo_set()?;
+        let mut spent = HashSet::new();
+        let mut rated = Vec::new();
+        let mut fees = 0;
+        for tx in mempool.collect(usize::MAX)? {
+            if rated.len() >= self.max_block_txs {
+                break;
+            }
+            let tx_size = tx.size_bytes();
+            if size + tx_size > self.max_block_bytes {
+                continue;
+            }
+            let fee = match self.check_pending(&tx, height, &utxo_set, &spent) {
+                Ok(fee) => fee,
+                Err(ChainError::ImmatureCoinbase { .. }) => continue,
+                Err(e) => {
+                    warn!(txid = hex::encode(&tx.id), "Evicting from mempool: {e}");
+                    mempool.remove(&tx.id)?;
+                    continue;
                 }
+            };
+            spent.extend(
+                tx.vin
+                    .iter()
+                    .filter_map(|vin| Some((vin.txid.clone(), vin.vout?))),
+            );
+            fees = checked_sum([fees, fee])?;
+            size += tx_size;
+            rated.push((fee as f64 / tx_size.max(1) as f64, tx));
+        }
+        rated.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+        let coinbase = Transaction::new_coinbase_tx(
+            miner_address,
+            &message,
+            checked_sum([reward, fees])?,
+            height,
+        )?;
+        let mut txs = vec![coinbase];
+        txs.extend(rated.into_iter().map(|(_, tx)| tx));
+        Ok(txs)
+    }
+
+    /// Checks mempool transaction `tx` for a block at `height` already spending `spent`,
+    /// returns the fee it pays
+    fn check_pending(
+        &self,
+        tx: &Transaction,
+        height: u64,
+        utxo_set: &UTXOSet,
+        spent: &HashSet<(ByteBuf, usize)>,
+    ) -> Result<u64, ChainError>
+    where
+        T: Blockchainable,
+    {
+        for vin in &tx.vin {
+            let Some(vout) = vin.vout else { continue };
+            if spent.contains(&(vin.txid.clone(), vout)) || !utxo_set.contains(&vin.txid, vout)? {
+                return Err(ChainError::DoubleSpend {
+                    txid: vin.txid.clone(),
+                    vout,
+                });
             }
         }
+        self.check_transaction(tx, height)
+    }
 
-        (all, unspent_outputs)
+    /// Serialized size of a sealed, non genesis block holding `txs` and `data`. Counts both a
+    /// nonce and an authority signature, so it holds whatever the consensus
+    fn block_bytes(txs: &[Transaction], data: &T) -> usize
+    where
+        T: Blockchainable,
+    {
+        let hash = ByteBuf::from(vec![0; Sha256Hasher.digest_bits() as usize / 8]);
+        // Unit data takes no bytes, `data` is added on top
+        let mut block = Block::<()>::template(Vec::new(), (), Some(hash.clone()), 0, 0);
+        block.merkle_root = hash.clone();
+        block.hash = Some(hash);
+        block.nonce = Some(0);
+        block.signature = Some(ByteBuf::from(vec![0; Self::SIGNATURE_LEN]));
+        let empty = serialize(&block).map_or(0, |block| block.len())
+            + serialize(data).map_or(0, |data| data.len());
+        txs.iter().fold(empty, |size, tx| size + tx.size_bytes())
     }
-}
 
-impl<T> Iterator for Blockchain<T>
-where
-    T: Blockchainable,
-{
-    type Item = Block<T>;
+    /// Verifies transactions, returns them coinbase first and the rest ordered
+    /// by fee per byte, highest first, along with the sum of their fees
+    fn prepare_transactions(
+        &self,
+        data: Vec<Transaction>,
+    ) -> Result<(Vec<Transaction>, u64), ChainError>
+    where
+        T: Blockchainable,
+    {
+        self.check_double_spends(&data)?;
+        let (mut coinbase, txs): (Vec<_>, Vec<_>) =
+            data.into_iter().partition(|tx| tx.is_coinbase());
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let blocks = self
-            .db
-            .open_tree(Self::BLOCKS_BUCKET)
-            .expect("Could not open blocks bucket!");
-        if let Some(block) = blocks.get(&self.tip).expect("Get value error!") {
-            let block = deserialize::<Block<T>>(&block).expect("Deserialization error!");
-            if let Some(ph) = &block.previous_block_hash {
-                self.tip = ph.clone();
-            } else {
-                self.tip = ByteBuf::new();
+        let height = self.next_height()?;
+        let mut rated = Vec::with_capacity(txs.len());
+        let mut fees = 0;
+        for tx in txs {
+            let fee = self.check_transaction(&tx, height)?;
+            fees = checked_sum([fees, fee])?;
+            let fee_rate = fee as f64 / tx.size_bytes().max(1) as f64;
+            rated.push((fee_rate, tx));
+        }
+        rated.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        coinbase.extend(rated.into_iter().map(|(_, tx)| tx));
+        Ok((coinbase, fees))
+    }
+
+    /// Fails with `DoubleSpend` when two inputs of `txs` spend the same output or an input
+    /// spends an output that is not unspent on the current chain
+    fn check_double_spends(&self, txs: &[Transaction]) -> Result<(), ChainError> {
+        let utxo_set = self.utxo_set()?;
+        let mut spent = HashSet::new();
+        for tx in txs.iter().filter(|tx| !tx.is_coinbase()) {
+            for vin in &tx.vin {
+                let Some(vout) = vin.vout else { continue };
+                if !spent.insert((vin.txid.clone(), vout)) || !utxo_set.contains(&vin.txid, vout)? {
+                    warn!(
+                        txid = hex::encode(&tx.id),
+                        "Rejecting transaction spending an already spent output"
+                    );
+                    return Err(ChainError::DoubleSpend {
+                        txid: vin.txid.clone(),
+                        vout,
+                    });
+                }
             }
-            return Some(block);
         }
-        None
+        Ok(())
+    }
+
+    /// Verifies signatures of a non coinbase transaction to be mined at `height` and that
+    /// it spends no coinbase younger than `coinbase_maturity`, returns the fee it pays
+    fn check_transaction(&self, tx: &Transaction, height: u64) -> Result<u64, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let prev_txs = self.collect_prev_txs(tx);
+        if !tx.verify(&prev_txs, self.chain_id) {
+            warn!(
+                txid = hex::encode(&tx.id),
+                "Rejecting transaction with invalid signature"
+            );
+            return Err(ChainError::InvalidSignature {
+                txid: tx.id.clone(),
+            });
+        }
+        for prev in prev_txs.values() {
+            let mature_at = prev
+                .coinbase_height()
+                .map(|mined| mined.saturating_add(self.coinbase_maturity));
+            if mature_at.is_some_and(|mature_at| height < mature_at) {
+                return Err(ChainError::ImmatureCoinbase {
+                    txid: prev.id.clone(),
+                });
+            }
+        }
+        tx.fee(&prev_txs)
+    }
+
+    /// Outputs of coinbases too young to be spent in the next block
+    fn immature_outputs(&self) -> Result<HashSet<(ByteBuf, usize)>, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let next_height = self.next_height()?;
+        Ok(self
+            .iter()
+            .take_while(|block| block.height.saturating_add(self.coinbase_maturity) > next_height)
+            .filter_map(|block| block.transactions.into_iter().next())
+            .flat_map(|coinbase| {
+                (0..coinbase.vout.len()).map(move |idx| (coinbase.id.clone(), idx))
+            })
+            .collect())
+    }
+
+    /// Appends a block mined elsewhere once it passes `validate_block`
+    pub fn accept_block(&mut self, block: Block<T>) -> Result<(), ChainError>
+    where
+        T: Blockchainable,
+    {
+        self.insert_block(&block)
+    }
+
+    /// Takes a block that may not extend the tip. Blocks on top of the tip are accepted right
+    /// away, others are kept as a side branch until the branch has more work than the active
+    /// chain above their common ancestor, which switches the active chain to it. Blocks left
+    /// behind by the switch become a side branch and their transactions return to the
+    /// mempool. Returns whether the tip changed
+    pub fn consider_block(&mut self, block: Block<T>) -> Result<bool, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let height = block.height;
+        let hash = block
+            .hash
+            .clone()
+            .ok_or(ChainError::UnsealedBlock { height })?;
+        let side = self.db.open_tree(Self::SIDE_BUCKET)?;
+        if self.contains_block(&hash) || side.contains_key(&hash)? {
+            return Ok(false);
+        }
+        let tip = (!self.tip.is_empty()).then(|| self.tip.clone());
+        if block.previous_block_hash == tip {
+            self.accept_block(block)?;
+            return Ok(true);
+        }
+
+        let prev_hash = block
+            .previous_block_hash
+            .clone()
+            .ok_or(ChainError::InvalidGenesis { height })?;
+        let parent = match self.read_block(&prev_hash)? {
+            Some(parent) => parent,
+            None => Self::read_side_block(&side, &prev_hash)?
+                .ok_or(ChainError::BrokenLink { height })?,
+        };
+        block.validate_with(Some(&parent), &*self.consensus)?;
+        let parent_work = self
+            .chain_work(&prev_hash)
+            .ok_or(ChainError::MissingChainWork { height })?;
+        let work = parent_work + ProofOfWork::new(&block).work();
+        side.insert(&hash, serialize(&block)?)?;
+        self.db
+            .open_tree(Self::WORK_BUCKET)?
+            .insert(&hash, work.to_bytes_be())?;
+
+        // Side blocks from just above the active chain up to the new one
+        let mut branch = vec![block];
+        while let Some(prev) = branch[branch.len() - 1].previous_block_hash.clone() {
+            match Self::read_side_block(&side, &prev)? {
+                Some(prev) => branch.push(prev),
+                None if self.contains_block(&prev) => break,
+                None => return Err(ChainError::BrokenLink { height }),
+            }
+        }
+        branch.reverse();
+        let fork_height = branch[0].height - 1;
+
+        if work <= self.total_work() {
+            debug!(height, fork_height, "Kept block on a side branch");
+            return Ok(false);
+        }
+        let stale: Vec<Block<T>> = self
+            .iter()
+            .take_while(|block| block.height > fork_height)
+            .collect();
+        self.reorg(branch, stale)?;
+        Ok(true)
+    }
+
+    /// Replaces `stale` blocks at the top of the chain, tip first, with `branch` built on
+    /// their parent. `branch` is fully validated on a scratch copy first, an invalid block
+    /// is dropped from the side branches along with its descendants
+    fn reorg(&mut self, branch: Vec<Block<T>>, stale: Vec<Block<T>>) -> Result<(), ChainError>
+    where
+        T: Blockchainable,
+    {
+        let side = self.db.open_tree(Self::SIDE_BUCKET)?;
+        let mut fork = self.fork(branch[0].height)?;
+        for (idx, block) in branch.iter().enumerate() {
+            if let Err(e) = fork.insert_block(block) {
+                for invalid in &branch[idx..] {
+                    side.remove(invalid.hash.clone().unwrap_or_default())?;
+                }
+                warn!(height = block.height, "Rejecting side branch: {e}");
+                return Err(e);
+            }
+        }
+
+        // Rewinding drops the work of stale blocks, which they need back as a side branch so
+        // blocks built on them can still outweigh the active chain
+        let stale_work: Vec<(ByteBuf, Vec<u8>)> = stale
+            .iter()
+            .map(|block| {
+                let hash = block.hash.clone().unwrap_or_default();
+                let work = self.chain_work(&hash).ok_or(ChainError::MissingChainWork {
+                    height: block.height,
+                })?;
+                Ok((hash, work.to_bytes_be()))
+            })
+            .collect::<Result<_, ChainError>>()?;
+        self.rewind(stale.len())?;
+        for block in branch {
+            let hash = block.hash.clone().unwrap_or_default();
+            self.accept_block(block)?;
+            side.remove(hash)?;
+        }
+        let works = self.db.open_tree(Self::WORK_BUCKET)?;
+        for (block, (hash, work)) in stale.iter().zip(stale_work) {
+            side.insert(&hash, serialize(block)?)?;
+            works.insert(&hash, work)?;
+        }
+
+        // Transactions depending on other stale ones are not spendable yet and get dropped
+        let mempool = self.mempool()?;
+        for tx in stale
+            .iter()
+            .rev()
+            .flat_map(|block| &block.transactions)
+            .filter(|tx| !tx.is_coinbase())
+        {
+            let requeued = self
+                .check_double_spends(std::slice::from_ref(tx))
+                .and_then(|_| self.check_transaction(tx, self.next_height()?))
+                .and_then(|fee| mempool.add(tx.clone(), fee));
+            if let Err(e) = requeued {
+                debug!(txid = hex::encode(&tx.id), "Dropped stale transaction: {e}");
+            }
+        }
+        info!(
+            height = self.height(),
+            stale = stale.len(),
+            "Switched to heavier branch"
+        );
+        Ok(())
+    }
+
+    fn read_side_block(side: &sled::Tree, hash: &[u8]) -> Result<Option<Block<T>>, ChainError>
+    where
+        T: Blockchainable,
+    {
+        match side.get(hash)? {
+            Some(bytes) => Ok(Some(deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks block extends the tip: everything `Block::validate` checks, size, checkpoints, clock
+    /// drift, difficulty, signatures, double spends and a coinbase paying reward plus fees
+    fn validate_block(&self, block: &Block<T>) -> Result<(), ChainError>
+    where
+        T: Blockchainable,
+    {
+        let height = block.height;
+        let parent = if self.tip.is_empty() {
+            None
+        } else {
+            let parent = self.read_block(&self.tip)?;
+            Some(parent.ok_or(ChainError::BrokenLink { height })?)
+        };
+        block.validate_with(parent.as_ref(), &*self.consensus)?;
+        let size = block.size_bytes();
+        if size > self.max_block_bytes {
+            return Err(ChainError::BlockTooLarge {
+                height,
+                size,
+                max: self.max_block_bytes,
+            });
+        }
+        let hash = block
+            .hash
+            .clone()
+            .ok_or(ChainError::UnsealedBlock { height })?;
+        self.checkpoints.check(height, &hash)?;
+        Self::check_timestamp(block, self.max_future_drift)?;
+        if block.difficulty_bits != self.next_difficulty()? {
+            return Err(ChainError::InvalidProofOfWork { height });
+        }
+
+        let mut fees = 0;
+        for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            fees = checked_sum([fees, self.check_transaction(tx, height)?])?;
+        }
+        self.check_double_spends(&block.transactions)?;
+        let expected = checked_sum([self.block_reward(height), fees])?;
+        let actual = checked_sum(block.transactions[0].vout.iter().map(|out| out.value))?;
+        if actual != expected {
+            return Err(ChainError::InvalidReward {
+                height,
+                expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// Stores block as the new tip once it passes `validate_block`, applying it to UTXO set
+    /// and mempool, which drops the block's transactions and any spending the same outputs
+    fn insert_block(&mut self, block: &Block<T>) -> Result<(), ChainError>
+    where
+        T: Blockchainable,
+    {
+        if let Err(e) = self.validate_block(block) {
+            warn!(height = block.height, "Rejecting block: {e}");
+            return Err(e);
+        }
+
+        let hash = Self::store_block(&self.db, block)?;
+        let mempool = self.mempool()?;
+        let spent: HashSet<_> = block
+            .transactions
+            .iter()
+            .flat_map(|tx| &tx.vin)
+            .filter_map(|vin| Some((vin.txid.clone(), vin.vout?)))
+            .collect();
+        for tx in mempool.collect(usize::MAX)? {
+            let conflicts = tx.vin.iter().any(|vin| {
+                vin.vout
+                    .is_some_and(|vout| spent.contains(&(vin.txid.clone(), vout)))
+            });
+            if conflicts {
+                mempool.remove(&tx.id)?;
+            }
+        }
+        for tx in &block.transactions {
+            mempool.remove(&tx.id)?;
+        }
+        info!(
+            height = block.height,
+            hash = hex::encode(&hash),
+            "Block added"
+        );
+        self.tip = hash;
+        Ok(())
+    }
+
+    /// Writes `block`, points the tip at it, records its chain work and applies it to the UTXO
+    /// set in one sled transaction, so a crash never leaves the tip ahead of the stored block
+    /// or UTXO set. Returns the block hash
+    fn store_block(db: &Db, block: &Block<T>) -> Result<ByteBuf, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let hash = block.hash.clone().ok_or(ChainError::UnsealedBlock {
+            height: block.height,
+        })?;
+        let bytes = serialize(block)?;
+        let blocks = db.open_tree(Self::BLOCKS_BUCKET)?;
+        let utxos = db.open_tree(UTXOSet::UTXO_BUCKET)?;
+        let works = db.open_tree(Self::WORK_BUCKET)?;
+        let tx_index = db.open_tree(Self::TX_INDEX_BUCKET)?;
+        let work = ProofOfWork::new(block).work();
+        (&blocks, &utxos, &works, &tx_index).transaction(|(blocks, utxos, works, tx_index)| {
+            let parent_work = match &block.previous_block_hash {
+                Some(prev) => works.get(prev.as_ref())?,
+                None => None,
+            };
+            let work =
+                parent_work.map_or_else(BigUint::default, |w| BigUint::from_bytes_be(&w)) + &work;
+            blocks.insert(hash.as_ref(), bytes.as_slice())?;
+            blocks.insert(b"l", hash.as_ref())?;
+            works.insert(hash.as_ref(), work.to_bytes_be())?;
+            for tx in &block.transactions {
+                tx_index.insert(tx.id.as_ref(), hash.as_ref())?;
+            }
+            UTXOSet::apply(utxos, block)
+        })?;
+        Ok(hash)
+    }
+
+    /// Rebuilds `TX_INDEX_BUCKET` from scratch out of the active chain
+    pub fn reindex_transactions(&self) -> Result<(), ChainError>
+    where
+        T: Blockchainable,
+    {
+        let tx_index = self.db.open_tree(Self::TX_INDEX_BUCKET)?;
+        tx_index.clear()?;
+        let mut batch = sled::Batch::default();
+        for block in self.iter() {
+            let hash = block.hash.unwrap_or_default();
+            for tx in &block.transactions {
+                batch.insert(tx.id.as_ref(), hash.as_ref());
+            }
+        }
+        Ok(tx_index.apply_batch(batch)?)
+    }
+
+    /// Recomputes chain work of every block in the active chain from genesis up
+    fn reindex_work(&self) -> Result<(), ChainError>
+    where
+        T: Blockchainable,
+    {
+        let mut batch = sled::Batch::default();
+        let mut work = BigUint::default();
+        for block in self.iter_forward() {
+            work += ProofOfWork::new(&block).work();
+            batch.insert(
+                block.hash.clone().unwrap_or_default().as_ref(),
+                work.to_bytes_be(),
+            );
+        }
+        Ok(self.db.open_tree(Self::WORK_BUCKET)?.apply_batch(batch)?)
+    }
+
+    /// Cumulative proof of work from genesis up to the block with `hash`, active or side
+    pub fn chain_work(&self, hash: &ByteBuf) -> Option<BigUint> {
+        let work = self
+            .db
+            .open_tree(Self::WORK_BUCKET)
+            .ok()?
+            .get(hash)
+            .ok()??;
+        Some(BigUint::from_bytes_be(&work))
+    }
+
+    /// Cumulative proof of work of the active chain, what competing branches are compared by
+    pub fn total_work(&self) -> BigUint {
+        self.chain_work(&self.tip).unwrap_or_default()
+    }
+
+    /// Difficulty for the next block, adjusted every `retarget_window` blocks so that blocks
+    /// come `target_block_time` apart
+    pub fn next_difficulty(&self) -> Result<u64, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let window = self.retarget_window.max(1);
+        let next_height = self.next_height()?;
+        let Some(last) = self.read_block(&self.last_hash()?)? else {
+            return Ok(self.difficulty_bits);
+        };
+        // Before the first retarget every block keeps the difficulty genesis was mined at,
+        // so a chain reopened with other `difficulty_bits` still mines at its own difficulty
+        if next_height < window {
+            return Ok(last.difficulty_bits);
+        }
+        if !next_height.is_multiple_of(window) {
+            return Ok(last.difficulty_bits);
+        }
+
+        let mut first = None;
+        let mut hash = last.previous_block_hash.clone();
+        for _ in 1..window {
+            let Some(h) = hash else { break };
+            let Some(block) = self.read_block(&h)? else {
+                break;
+            };
+            hash = block.previous_block_hash.clone();
+            first = Some(block);
+        }
+        let Some(first) = first else {
+            return Ok(last.difficulty_bits);
+        };
+
+        let actual_span = last
+            .timestamp
+            .duration_since(first.timestamp)
+            .unwrap_or_default()
+            .as_secs_f64()
+            .max(f64::EPSILON);
+        let target_span = (self.target_block_time * (window - 1) as u32).as_secs_f64();
+        // Blocks came faster than targeted -> more leading zero bits, slower -> fewer
+        let delta = (target_span / actual_span).log2().round().clamp(
+            -Self::MAX_RETARGET_BITS as f64,
+            Self::MAX_RETARGET_BITS as f64,
+        ) as i64;
+
+        Ok((last.difficulty_bits as i64 + delta).clamp(1, 255) as u64)
+    }
+
+    /// Walks from the tip to genesis running `Block::validate` on every block, then runs
+    /// `audit_supply`
+    pub fn verify_chain(&self) -> Result<(), ChainError>
+    where
+        T: Blockchainable,
+    {
+        self.walk_chain()
+            .and_then(|_| self.audit_supply().map(|_| ()))
+            .inspect_err(|e| warn!("Chain verification failed: {e}"))
+    }
+
+    /// Circulating supply, checked to be exactly the reward of every block minus value burned
+    /// in unspendable outputs. Fees only move coins, so anything else fails with
+    /// `SupplyMismatch`, a sign of coins minted or lost outside the reward schedule
+    pub fn audit_supply(&self) -> Result<u64, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let mut minted = 0;
+        let mut burned = 0;
+        for block in self.iter() {
+            minted = checked_sum([minted, self.block_reward(block.height)])?;
+            let outputs = block.transactions.iter().flat_map(|tx| &tx.vout);
+            for out in outputs.filter(|out| !out.is_spendable()) {
+                burned = checked_sum([burned, out.value])?;
+            }
+        }
+
+        let actual = self.utxo_set()?.total_value()?;
+        let expected = minted.checked_sub(burned);
+        if expected != Some(actual) {
+            return Err(ChainError::SupplyMismatch {
+                expected: expected.unwrap_or_default(),
+                actual,
+            });
+        }
+        Ok(actual)
+    }
+
+    fn walk_chain(&self) -> Result<(), ChainError>
+    where
+        T: Blockchainable,
+    {
+        let blocks = self.db.open_tree(Self::BLOCKS_BUCKET)?;
+        let stored = Self::stored_blocks(&blocks);
+        if stored == 0 {
+            return Ok(());
+        }
+
+        // Stored blocks not reachable from the tip sit below a missing one,
+        // so the missing block's height is the number of blocks not walked yet
+        let mut walked = 0;
+        let mut hash = self.last_hash()?;
+        let mut child: Option<Block<T>> = None;
+        loop {
+            // More blocks walked than stored means the links form a cycle
+            let height = stored
+                .checked_sub(walked)
+                .ok_or(ChainError::BrokenLink { height: 0 })?;
+            let block = self
+                .read_block(&hash)?
+                .ok_or(ChainError::BrokenLink { height })?;
+            if block.hash.as_ref() != Some(&hash) {
+                return Err(ChainError::BrokenLink { height });
+            }
+            walked += 1;
+
+            let height = stored - walked;
+            if block.height != height {
+                return Err(BlockError::HeightMismatch {
+                    expected: height,
+                    actual: block.height,
+                }
+                .into());
+            }
+            Self::check_timestamp(&block, self.max_future_drift)?;
+            if let Some(child) = &child {
+                child.validate_with(Some(&block), &*self.consensus)?;
+            }
+            match block.previous_block_hash.clone() {
+                Some(prev) => hash = prev,
+                None if height == 0 => return Ok(block.validate(None)?),
+                None => return Err(ChainError::InvalidGenesis { height }),
+            }
+            child = Some(block);
+        }
+    }
+
+    /// Hash of the last block in chain, empty for a chain without blocks
+    pub(crate) fn last_hash(&self) -> Result<ByteBuf, ChainError> {
+        let blocks = self.db.open_tree(Self::BLOCKS_BUCKET)?;
+        Ok(blocks
+            .get(b"l")?
+            .map(|v| ByteBuf::from(v.to_vec()))
+            .unwrap_or_default())
+    }
+
+    pub(crate) fn read_block(&self, hash: &[u8]) -> Result<Option<Block<T>>, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let blocks = self.db.open_tree(Self::BLOCKS_BUCKET)?;
+        match blocks.get(hash)? {
+            Some(block) => Ok(Some(deserialize(&block)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Blocks from the tip down to genesis
+    pub fn iter(&self) -> BlockchainIterator<'_, T> {
+        BlockchainIterator {
+            db: &self.db,
+            current: self.tip.clone(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Blocks from genesis up to the tip. Walking back from the tip only collects hashes,
+    /// blocks are read one at a time on the way forward
+    pub fn iter_forward(&self) -> impl Iterator<Item = Block<T>> + '_
+    where
+        T: Blockchainable,
+    {
+        let mut hashes: Vec<ByteBuf> = self.iter().filter_map(|block| block.hash).collect();
+        hashes.reverse();
+        hashes
+            .into_iter()
+            .map_while(|hash| self.read_block(&hash).ok().flatten())
+    }
+
+    /// Blocks becoming the tip from now on, in the order they do, pushed by sled as the tip
+    /// pointer is written rather than polled. Only sees writes of this process, sled keeps
+    /// other processes out of an open db
+    pub fn watch_blocks(&self) -> Result<BlockWatcher<T>, ChainError> {
+        let blocks = self.db.open_tree(Self::BLOCKS_BUCKET)?;
+        Ok(BlockWatcher {
+            subscriber: blocks.watch_prefix(b"l"),
+            blocks,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Up to `limit` blocks of the chain ending at the tip, from height `from` upwards. Empty
+    /// when `from` is past the tip
+    pub fn blocks_range(&self, from: u64, limit: usize) -> Vec<Block<T>>
+    where
+        T: Blockchainable,
+    {
+        let end = from.saturating_add(limit as u64);
+        let mut blocks: Vec<Block<T>> = self
+            .iter()
+            .skip_while(|block| block.height >= end)
+            .take_while(|block| block.height >= from)
+            .collect();
+        blocks.reverse();
+        blocks
+    }
+
+    /// Block stored under `hash`, `None` when it is missing or can not be read
+    pub fn get_block(&self, hash: &ByteBuf) -> Option<Block<T>>
+    where
+        T: Blockchainable,
+    {
+        self.read_block(hash).ok().flatten()
+    }
+
+    /// Whether a block with `hash` is stored, checked without reading the block itself
+    pub fn contains_block(&self, hash: &ByteBuf) -> bool {
+        // `l` is the tip pointer, not a block
+        if hash.as_slice() == b"l" {
+            return false;
+        }
+        self.db
+            .open_tree(Self::BLOCKS_BUCKET)
+            .and_then(|blocks| blocks.contains_key(hash))
+            .unwrap_or(false)
+    }
+
+    /// Block `tip` points at, `None` for a chain without blocks
+    pub fn tip_block(&self) -> Option<Block<T>>
+    where
+        T: Blockchainable,
+    {
+        if self.tip.is_empty() {
+            return None;
+        }
+        self.get_block(&self.tip)
+    }
+
+    /// Block at `height` on the chain ending at the tip
+    pub fn get_block_by_height(&self, height: u64) -> Option<Block<T>>
+    where
+        T: Blockchainable,
+    {
+        let mut block = self.tip_block()?;
+        while block.height > height {
+            block = self.get_block(block.previous_block_hash.as_ref()?)?;
+        }
+        (block.height == height).then_some(block)
+    }
+
+    /// Height of the tip block, 0 for a chain without blocks
+    pub fn height(&self) -> u64
+    where
+        T: Blockchainable,
+    {
+        self.tip_block().map_or(0, |block| block.height)
+    }
+
+    /// Height the next block appended to the chain will have (genesis = 0)
+    fn next_height(&self) -> Result<u64, ChainError>
+    where
+        T: Blockchainable,
+    {
+        Ok(self
+            .read_block(&self.last_hash()?)?
+            .map_or(0, |block| block.height + 1))
+    }
+
+    fn stored_blocks(blocks: &sled::Tree) -> u64 {
+        // Every key except the `l` tip pointer is a block
+        blocks.len().saturating_sub(1) as u64
+    }
+
+    /// Like `mine_block`, but rewards `miner_address` and seals through `consensus` on
+    /// `threads` threads, which only speeds up sealing by a search such as proof of work
+    pub fn mine_block_parallel(
+        &mut self,
+        miner_address: &str,
+        threads: usize,
+    ) -> Result<ByteBuf, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let data = T::default_data();
+        let txs = self.block_transactions(miner_address, &data)?;
+        let threads = threads.max(1);
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build()?;
+        let consensus = Arc::clone(&self.consensus);
+        self.seal_transactions(txs, data, |block| {
+            pool.install(|| consensus.seal_parallel(block, threads))
+        })?;
+        Ok(self.tip.clone())
+    }
+
+    /// Writes everything buffered by sled to disk, instead of waiting for its background flush
+    pub fn flush(&self) -> Result<(), ChainError> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn remove_blocks(&self) -> Result<(), ChainError> {
+        let blocks = self.db.open_tree(Self::BLOCKS_BUCKET)?;
+
+        for p in blocks.iter() {
+            let (key, _) = p?;
+            blocks.remove(key)?;
+        }
+        self.db.open_tree(Self::SIDE_BUCKET)?.clear()?;
+        self.db.open_tree(Self::WORK_BUCKET)?.clear()?;
+        self.db.open_tree(Self::TX_INDEX_BUCKET)?.clear()?;
+        self.db.remove(Self::CHAIN_ID_KEY)?;
+        self.utxo_set()?.clear()
+    }
+
+    /// Drops the top `n` blocks, making the block below them the new tip, along with their
+    /// index entries and pending transactions spending their outputs. Genesis always stays
+    pub fn rewind(&mut self, n: usize) -> Result<(), ChainError>
+    where
+        T: Blockchainable,
+    {
+        if n == 0 {
+            return Ok(());
+        }
+        let height = self.height();
+        if self.tip.is_empty() || n as u64 > height {
+            return Err(ChainError::RewindPastGenesis { height });
+        }
+
+        let mut dropped = Vec::with_capacity(n);
+        let mut hash = self.last_hash()?;
+        for _ in 0..n {
+            let block = self
+                .read_block(&hash)?
+                .ok_or(ChainError::BrokenLink { height })?;
+            let prev = block
+                .previous_block_hash
+                .clone()
+                .ok_or(ChainError::InvalidGenesis {
+                    height: block.height,
+                })?;
+            dropped.push(block);
+            hash = prev;
+        }
+
+        // Dropped blocks, their work and transaction index entries and the moved tip pointer
+        // are written together
+        let blocks = self.db.open_tree(Self::BLOCKS_BUCKET)?;
+        let works = self.db.open_tree(Self::WORK_BUCKET)?;
+        let tx_index = self.db.open_tree(Self::TX_INDEX_BUCKET)?;
+        (&blocks, &works, &tx_index).transaction(|(blocks, works, tx_index)| {
+            for block in &dropped {
+                let dropped_hash = block.hash.clone().unwrap_or_default();
+                blocks.remove(dropped_hash.as_ref())?;
+                works.remove(dropped_hash.as_ref())?;
+                for tx in &block.transactions {
+                    tx_index.remove(tx.id.as_ref())?;
+                }
+            }
+            blocks.insert(b"l", hash.as_ref())?;
+            Ok::<_, ConflictableTransactionError<ChainError>>(())
+        })?;
+        self.tip = hash;
+        let utxo_set = self.utxo_set()?;
+        utxo_set.reindex(self)?;
+
+        // Pending transactions spending outputs of dropped blocks can never be mined now
+        let mempool = self.mempool()?;
+        for tx in mempool.collect(usize::MAX)? {
+            let mut spends_dropped = false;
+            for vin in &tx.vin {
+                let Some(vout) = vin.vout else { continue };
+                spends_dropped |= !utxo_set.contains(&vin.txid, vout)?;
+            }
+            if spends_dropped {
+                mempool.remove(&tx.id)?;
+            }
+        }
+        info!(height = height - n as u64, "Rewound chain");
+        Ok(())
+    }
+
+    /// Block and transaction counts, block sizes, spendable supply and tip of the chain. Blocks
+    /// and supply come from the blocks tree length and the UTXO set, only transactions and
+    /// sizes need a walk
+    pub fn stats(&self) -> Result<ChainStats, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let blocks = Self::stored_blocks(&self.db.open_tree(Self::BLOCKS_BUCKET)?);
+        let tip = self.tip_block();
+        let mut transactions = 0;
+        let mut total_bytes = 0;
+        let mut max_block_bytes = 0;
+        for block in self.iter() {
+            transactions += block.transactions.len() as u64;
+            let size = block.size_bytes();
+            total_bytes += size;
+            max_block_bytes = max_block_bytes.max(size);
+        }
+        Ok(ChainStats {
+            blocks,
+            transactions,
+            avg_block_bytes: total_bytes.checked_div(blocks as usize).unwrap_or(0),
+            max_block_bytes,
+            total_supply: self.utxo_set()?.total_value()?,
+            difficulty_bits: tip
+                .as_ref()
+                .map_or(self.difficulty_bits, |block| block.difficulty_bits),
+            tip_hash: self.tip.clone(),
+            tip_height: tip.map_or(0, |block| block.height),
+        })
+    }
+
+    /// Transactions with outputs locked to `pub_key_hash` not spent anywhere in chain
+    pub fn find_unspent_txs(&self, pub_key_hash: &ByteBuf) -> Vec<Transaction>
+    where
+        T: Blockchainable,
+    {
+        let mut spent_txos: HashMap<ByteBuf, Vec<usize>> = HashMap::new();
+        let mut unspent_txs: Vec<Transaction> = Vec::new();
+
+        for block in self.iter() {
+            for tx in block.transactions {
+                'outs: for (this_idx, vout) in tx.vout.iter().enumerate() {
+                    if let Some(indicies) = spent_txos.get(&tx.id) {
+                        for idx in indicies {
+                            if *idx == this_idx {
+                                continue 'outs;
+                            }
+                        }
+                    }
+
+                    if vout.can_be_unlocked_with(pub_key_hash) {
+                        unspent_txs.push(tx.clone());
+                    }
+                }
+
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        if let (true, Some(vout)) =
+                            (vin.can_unlock_output_with(pub_key_hash), vin.vout)
+                        {
+                            spent_txos.entry(vin.txid.clone()).or_default().push(vout);
+                        }
+                    }
+                }
+
+                if let Some(ref ph) = block.previous_block_hash {
+                    if ph.is_empty() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        unspent_txs
+    }
+
+    pub fn find_utxo(&self, pub_key_hash: &ByteBuf) -> Vec<TXOutput>
+    where
+        T: Blockchainable,
+    {
+        self.find_unspent_txs(pub_key_hash)
+            .iter()
+            .flat_map(|utx| utx.vout.clone())
+            .filter(|txo| txo.can_be_unlocked_with(pub_key_hash))
+            .collect()
+    }
+
+    /// Sum of unspent outputs locked to `address`
+    pub fn balance_at(&self, address: &Address) -> Result<u64, ChainError>
+    where
+        T: Blockchainable,
+    {
+        Ok(self.balance_detail(address)?.0)
+    }
+
+    /// Balance of `address` and number of unspent outputs making it up
+    pub fn balance_detail(&self, address: &Address) -> Result<(u64, usize), ChainError>
+    where
+        T: Blockchainable,
+    {
+        let utxos = self.utxo_set()?.find_utxo(&address.pub_key_hash())?;
+        let balance = checked_sum(utxos.iter().map(|utxo| utxo.value))?;
+        Ok((balance, utxos.len()))
+    }
+
+    /// Balance of `address` split into `(mature, immature)`, immature being coinbase outputs
+    /// that can't be spent in the next block yet
+    pub fn mature_balance(&self, address: &Address) -> Result<(u64, u64), ChainError>
+    where
+        T: Blockchainable,
+    {
+        let pub_key_hash = address.pub_key_hash();
+        let immature_outputs = self.immature_outputs()?;
+        let (immature, mature): (Vec<_>, Vec<_>) = self
+            .utxo_set()?
+            .find_utxo_refs(&pub_key_hash)?
+            .into_iter()
+            .partition(|(txid, idx, _)| immature_outputs.contains(&(txid.clone(), *idx)));
+        Ok((
+            checked_sum(mature.iter().map(|(_, _, out)| out.value))?,
+            checked_sum(immature.iter().map(|(_, _, out)| out.value))?,
+        ))
+    }
+
+    /// Unspent outputs locked to `address` as `(txid, output index, value)`
+    pub fn list_utxos(&self, address: &Address) -> Result<Vec<(ByteBuf, usize, u64)>, ChainError> {
+        let pub_key_hash = address.pub_key_hash();
+        Ok(self
+            .utxo_set()?
+            .find_utxo_refs(&pub_key_hash)?
+            .into_iter()
+            .map(|(txid, idx, out)| (txid, idx, out.value))
+            .collect())
+    }
+
+    /// Replays the chain from genesis, returning `(height, utxo_count, total_value)`
+    /// every `sample_every` blocks
+    pub fn utxo_growth_history(&self, sample_every: u64) -> Vec<(u64, usize, u64)>
+    where
+        T: Blockchainable,
+    {
+        let sample_every = sample_every.max(1);
+        let mut utxos: HashMap<(ByteBuf, usize), u64> = HashMap::new();
+        let mut history = Vec::new();
+
+        for (height, block) in self.iter_forward().enumerate() {
+            for tx in &block.transactions {
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        if let Some(vout) = vin.vout {
+                            utxos.remove(&(vin.txid.clone(), vout));
+                        }
+                    }
+                }
+                for (idx, vout) in tx.vout.iter().enumerate() {
+                    if vout.is_spendable() {
+                        utxos.insert((tx.id.clone(), idx), vout.value);
+                    }
+                }
+            }
+
+            let height = height as u64;
+            if height.is_multiple_of(sample_every) {
+                let value = utxos
+                    .values()
+                    .fold(0u64, |acc, value| acc.saturating_add(*value));
+                history.push((height, utxos.len(), value));
+            }
+        }
+
+        history
+    }
+
+    /// Signs a transaction paying `value` from `from` to `to` and queues it in the mempool,
+    /// returns its id once the mempool is flushed to disk
+    pub fn send(
+        &mut self,
+        from: &Address,
+        to: &Address,
+        value: u64,
+        fee: u64,
+    ) -> Result<ByteBuf, ChainError>
+    where
+        T: Blockchainable,
+    {
+        self.send_many(from, &[(to.to_string(), value)], fee)
+    }
+
+    /// Like `send`, but pays every `(address, value)` of `outputs` in one transaction
+    pub fn send_many(
+        &mut self,
+        from: &Address,
+        outputs: &[(String, u64)],
+        fee: u64,
+    ) -> Result<ByteBuf, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let tx = Transaction::new_multi(from.as_str(), outputs, fee, self)?;
+        self.sign_and_queue(from.as_str(), tx)
+    }
+
+    /// Queues a transaction anchoring `data` in chain in an unspendable output paid by `from`,
+    /// returns its id
+    pub fn anchor(&mut self, from: &str, data: &[u8], fee: u64) -> Result<ByteBuf, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let tx = Transaction::new_anchor(from, data, fee, self)?;
+        self.sign_and_queue(from, tx)
+    }
+
+    /// Wallets of `wallets_file`, read without importing a legacy file into the store or
+    /// creating one when the chain was opened read only
+    pub fn wallets(&self) -> Result<Wallets, ChainError> {
+        if self.read_only {
+            Wallets::open_read_only(&self.wallets_file)
+        } else {
+            Wallets::open(&self.wallets_file)
+        }
+    }
+
+    /// Hardcoded checkpoints and custom ones from `ChainConfig` blocks are checked against
+    pub fn checkpoints(&self) -> &CheckpointSet {
+        &self.checkpoints
+    }
+
+    /// Whether the chain was opened by `open_existing_read_only`
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Signs `tx` with `from`'s wallet and adds it to the mempool once it verifies
+    fn sign_and_queue(&mut self, from: &str, mut tx: Transaction) -> Result<ByteBuf, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let wallets = self.wallets()?;
+        let wallet = wallets
+            .get(&ByteBuf::from(from))
+            .ok_or(ChainError::WalletNotFound)?;
+        let private_key = wallet.signing_key()?;
+        let prev_txs = self.collect_prev_txs(&tx);
+        tx.sign(&private_key, &prev_txs, self.chain_id)?;
+        if !tx.verify(&prev_txs, self.chain_id) {
+            return Err(ChainError::InvalidSignature { txid: tx.id });
+        }
+
+        let fee = tx.fee(&prev_txs)?;
+        let txid = tx.id.clone();
+        self.mempool()?.add(tx, fee)?;
+        self.flush()?;
+        Ok(txid)
+    }
+
+    /// Block containing transaction `txid` together with its output `vout`
+    pub fn output_origin(&self, txid: &ByteBuf, vout: usize) -> Option<(Block<T>, TXOutput)>
+    where
+        T: Blockchainable,
+    {
+        self.iter().find_map(|block| {
+            let output = block
+                .transactions
+                .iter()
+                .find(|tx| tx.id == *txid)?
+                .vout
+                .get(vout)?
+                .clone();
+            Some((block, output))
+        })
+    }
+
+    /// Id of the transaction spending output `vout` of `txid`, `None` while it is unspent
+    pub fn spent_by(&self, txid: &ByteBuf, vout: usize) -> Option<ByteBuf>
+    where
+        T: Blockchainable,
+    {
+        // Anything still in the UTXO set is unspent, no need to walk the chain
+        if self.utxo_set().ok()?.contains(txid, vout).ok()? {
+            return None;
+        }
+        self.iter()
+            .flat_map(|block| block.transactions)
+            .filter(|tx| !tx.is_coinbase())
+            .find(|tx| {
+                tx.vin
+                    .iter()
+                    .any(|vin| vin.txid == *txid && vin.vout == Some(vout))
+            })
+            .map(|tx| tx.id)
+    }
+
+    /// Transaction with id `id` in the active chain, one `TX_INDEX_BUCKET` lookup and one block
+    /// read. Rewinding removes the entries of the blocks it drops, so side branches are misses
+    pub fn find_transaction(&self, id: &ByteBuf) -> Option<Transaction>
+    where
+        T: Blockchainable,
+    {
+        let tx_index = self.db.open_tree(Self::TX_INDEX_BUCKET).ok()?;
+        let hash = tx_index.get(id).ok()??;
+        self.read_block(&hash)
+            .ok()??
+            .transactions
+            .into_iter()
+            .find(|tx| tx.id == *id)
+    }
+
+    /// Multi-line listing like `Transaction::describe`, with inputs resolved against the chain
+    /// to the value and address they spend and totals of inputs, outputs and fee. Inputs
+    /// spending transactions that are not in the chain show as `<unknown>`
+    pub fn describe_transaction(&self, tx: &Transaction) -> String
+    where
+        T: Blockchainable,
+    {
+        let address = |pub_key_hash: &ByteBuf| {
+            String::from_utf8_lossy(&Wallet::address_from_pub_key_hash(pub_key_hash)).into_owned()
+        };
+        let total_out = checked_sum(tx.vout.iter().map(|out| out.value));
+
+        let mut out = format!("ID: {}", hex::encode(&tx.id));
+        let mut total_in = Some(0u64);
+        for (idx, vin) in tx.vin.iter().enumerate() {
+            if tx.is_coinbase() {
+                let reward = total_out
+                    .as_ref()
+                    .map_or("<unknown>".to_string(), |value| value.to_string());
+                let height = tx
+                    .coinbase_height()
+                    .map_or("NONE".to_string(), |height| height.to_string());
+                out.push_str(&format!(
+                    "\n\tVIN {idx}: COINBASE, HEIGHT: {height}, REWARD: {reward}"
+                ));
+                continue;
+            }
+            let spent = self
+                .find_transaction(&vin.txid)
+                .and_then(|prev| prev.vout.get(vin.vout?).cloned());
+            let (value, from) = match &spent {
+                Some(prev_out) => (
+                    prev_out.value.to_string(),
+                    prev_out
+                        .pub_key_hash()
+                        .map_or("<unknown>".to_string(), address),
+                ),
+                None => ("<unknown>".to_string(), "<unknown>".to_string()),
+            };
+            total_in = total_in
+                .zip(spent)
+                .and_then(|(sum, prev_out)| sum.checked_add(prev_out.value));
+            out.push_str(&format!(
+                "\n\tVIN {idx}: TXID: {}, VOUT: {}, VALUE: {value}, FROM: {from}",
+                hex::encode(&vin.txid),
+                vin.vout.map_or("NONE".to_string(), |vout| vout.to_string()),
+            ));
+        }
+        for (idx, vout) in tx.vout.iter().enumerate() {
+            let script = match &vout.script {
+                ScriptType::P2PKH(pub_key_hash) => format!("TO: {}", address(pub_key_hash)),
+                ScriptType::OpReturn(data) => format!("OP_RETURN: {}", hex::encode(data)),
+            };
+            out.push_str(&format!("\n\tVOUT {idx}: VALUE: {}, {script}", vout.value));
+        }
+
+        if !tx.is_coinbase() {
+            let show =
+                |value: Option<u64>| value.map_or("<unknown>".to_string(), |v| v.to_string());
+            let total_out = total_out.ok();
+            let fee = total_in
+                .zip(total_out)
+                .and_then(|(inputs, outputs)| inputs.checked_sub(outputs));
+            out.push_str(&format!(
+                "\n\tTOTAL IN: {}, TOTAL OUT: {}, FEE: {}",
+                show(total_in),
+                show(total_out),
+                show(fee)
+            ));
+        }
+        out
+    }
+
+    /// Transactions referenced by inputs of given tx, keyed by their id, empty for a coinbase
+    pub fn collect_prev_txs(&self, tx: &Transaction) -> HashMap<ByteBuf, Transaction>
+    where
+        T: Blockchainable,
+    {
+        if tx.is_coinbase() {
+            return HashMap::new();
+        }
+        tx.vin
+            .iter()
+            .filter_map(|vin| self.find_transaction(&vin.txid))
+            .map(|prev| (prev.id.clone(), prev))
+            .collect()
+    }
+
+    pub fn find_spendable_outputs(
+        &self,
+        pub_key_hash: &ByteBuf,
+        value: u64,
+    ) -> Result<(u64, HashMap<ByteBuf, Vec<usize>>), ChainError>
+    where
+        T: Blockchainable,
+    {
+        // Outputs already spent by pending transactions are not spendable again, young
+        // coinbases not yet
+        let mut excluded = self.mempool()?.claimed_outputs()?;
+        excluded.extend(self.immature_outputs()?);
+        self.utxo_set()?
+            .find_spendable_outputs(pub_key_hash, value, &excluded)
+    }
+}
+
+/// Best effort flush for handles dropped without an explicit `flush`
+impl<T> Drop for Blockchain<T> {
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+        if let Err(e) = self.db.flush() {
+            warn!("Failed to flush chain on drop: {e}");
+        }
+    }
+}
+
+/// Summary of a chain returned by `Blockchain::stats`
+#[derive(Debug, Clone)]
+pub struct ChainStats {
+    pub blocks: u64,
+    pub transactions: u64,
+    /// Mean serialized size of a block, 0 for a chain without blocks
+    pub avg_block_bytes: usize,
+    /// Serialized size of the largest block
+    pub max_block_bytes: usize,
+    /// Coins that can still be spent, everything minted minus burned outputs
+    pub total_supply: u64,
+    /// Difficulty the tip block was mined at
+    pub difficulty_bits: u64,
+    /// Empty for a chain without blocks
+    pub tip_hash: ByteBuf,
+    pub tip_height: u64,
+}
+
+impl Display for ChainStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Chain stats")?;
+        writeln!(f, "\tBLOCKS: {}", self.blocks)?;
+        writeln!(f, "\tTRANSACTIONS: {}", self.transactions)?;
+        writeln!(f, "\tAVG BLOCK BYTES: {}", self.avg_block_bytes)?;
+        writeln!(f, "\tMAX BLOCK BYTES: {}", self.max_block_bytes)?;
+        writeln!(f, "\tTOTAL SUPPLY: {}", self.total_supply)?;
+        writeln!(f, "\tDIFFICULTY BITS: {}", self.difficulty_bits)?;
+        writeln!(f, "\tTIP HASH: {}", hex::encode(&self.tip_hash))?;
+        write!(f, "\tTIP HEIGHT: {}", self.tip_height)
+    }
+}
+
+/// Fee rates, per 1000 bytes, paid in the mempool as returned by `Blockchain::fee_market_stats`
+#[derive(Debug, Clone)]
+pub struct FeeMarketStats {
+    pub min_fee_rate: u64,
+    pub max_fee_rate: u64,
+    pub median_fee_rate: u64,
+    pub mempool_tx_count: usize,
+    /// Serialized size of every mempool transaction together
+    pub mempool_total_bytes: usize,
+    /// Blocks until a transaction paying a fee rate seen in the mempool is mined
+    pub estimated_blocks_to_confirm: HashMap<u64, u64>,
+}
+
+impl Display for FeeMarketStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Fee market")?;
+        writeln!(f, "\tMEMPOOL TXS: {}", self.mempool_tx_count)?;
+        writeln!(f, "\tMEMPOOL BYTES: {}", self.mempool_total_bytes)?;
+        writeln!(f, "\tMIN FEE RATE: {}", self.min_fee_rate)?;
+        writeln!(f, "\tMEDIAN FEE RATE: {}", self.median_fee_rate)?;
+        write!(f, "\tMAX FEE RATE: {}", self.max_fee_rate)?;
+        let mut estimates: Vec<_> = self.estimated_blocks_to_confirm.iter().collect();
+        estimates.sort_unstable_by(|a, b| b.0.cmp(a.0));
+        for (rate, blocks) in estimates {
+            write!(f, "\n\tFEE RATE {rate}: {blocks} blocks")?;
+        }
+        Ok(())
+    }
+}
+
+/// New tips of a chain as made by `Blockchain::watch_blocks`, iterating blocks until the db
+/// is closed
+pub struct BlockWatcher<T> {
+    subscriber: Subscriber,
+    blocks: Tree,
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> BlockWatcher<T>
+where
+    T: Blockchainable,
+{
+    /// Next new tip, `None` when none arrives within `timeout` or the db is closed
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<Block<T>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.checked_duration_since(Instant::now())?;
+            let event = self.subscriber.next_timeout(left).ok()?;
+            if let Some(block) = self.read_tip(event) {
+                return Some(block);
+            }
+        }
+    }
+
+    /// Block the tip pointer was set to, `None` for removals and unreadable blocks
+    fn read_tip(&self, event: Event) -> Option<Block<T>> {
+        let Event::Insert { value: hash, .. } = event else {
+            return None;
+        };
+        match self.blocks.get(&hash) {
+            Ok(Some(block)) => deserialize(&block)
+                .inspect_err(|e| error!("Could not deserialize block: {e}"))
+                .ok(),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Could not read block: {e}");
+                None
+            }
+        }
+    }
+}
+
+impl<T> Iterator for BlockWatcher<T>
+where
+    T: Blockchainable,
+{
+    type Item = Block<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let event = self.subscriber.next()?;
+            if let Some(block) = self.read_tip(event) {
+                return Some(block);
+            }
+        }
+    }
+}
+
+/// Walks blocks from a starting hash back to genesis without touching the chain's tip
+pub struct BlockchainIterator<'a, T> {
+    db: &'a Db,
+    current: ByteBuf,
+    phantom: PhantomData<T>,
+}
+
+impl<T> Iterator for BlockchainIterator<'_, T>
+where
+    T: Blockchainable,
+{
+    type Item = Block<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let blocks = match self.db.open_tree(Blockchain::<T>::BLOCKS_BUCKET) {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                error!("Could not open blocks bucket: {e}");
+                return None;
+            }
+        };
+        let block = match blocks.get(&self.current) {
+            Ok(block) => block,
+            Err(e) => {
+                error!("Could not read block: {e}");
+                return None;
+            }
+        };
+        if let Some(block) = block {
+            let block = match deserialize::<Block<T>>(&block) {
+                Ok(block) => block,
+                Err(e) => {
+                    error!("Could not deserialize block: {e}");
+                    return None;
+                }
+            };
+            if let Some(ph) = &block.previous_block_hash {
+                self.current = ph.clone();
+            } else {
+                self.current = ByteBuf::new();
+            }
+            return Some(block);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Chain whose genesis pays a new wallet stored in its wallets file, with that address
+    fn funded_chain() -> Result<(Blockchain<String>, Address), ChainError> {
+        let wallet = Wallet::new();
+        let address = Address::from_pub_key_hash(&Wallet::hash_pub_key(&wallet.public_key));
+        let blockchain = Blockchain::new_test(address.as_str())?;
+        Wallets::insert(&blockchain.wallets_file, &wallet)?;
+        Ok((blockchain, address))
+    }
+
+    /// Stores a new wallet in `blockchain`'s wallets file, returns its address
+    fn new_wallet(blockchain: &Blockchain<String>) -> Result<Address, ChainError> {
+        let wallet = Wallet::new();
+        Wallets::insert(&blockchain.wallets_file, &wallet)?;
+        Ok(Address::from_pub_key_hash(&Wallet::hash_pub_key(
+            &wallet.public_key,
+        )))
+    }
+
+    #[test]
+    fn test_chain_mines_at_test_difficulty() -> Result<(), ChainError> {
+        let (mut blockchain, address) = funded_chain()?;
+        blockchain.miner_address = Some(address.to_string());
+        blockchain.mine_block()?;
+        assert_eq!(blockchain.height(), 1);
+        assert!(blockchain
+            .iter()
+            .all(|block| block.difficulty_bits == Blockchain::<String>::TEST_BITS));
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_mining_seals_through_consensus() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        blockchain.send(&alice, &bob, 3, 1)?;
+
+        let hash = blockchain.mine_block_parallel(alice.as_str(), 4)?;
+        assert_eq!(blockchain.tip, hash);
+        assert_eq!(blockchain.height(), 1);
+        let tip = blockchain.tip_block().unwrap();
+        assert!(tip.nonce.is_some() && PowConsensus.verify(&tip));
+        blockchain.verify_chain()?;
+        assert_eq!(blockchain.balance_at(&bob)?, 3);
+        assert!(blockchain.mempool()?.is_empty());
+
+        // An authority chain gets a signed block instead of a mined one
+        let authority = Wallet::new();
+        blockchain.consensus = Arc::new(
+            crate::consensus::ProofOfAuthority::new(vec![authority.public_key.clone()])
+                .with_signer(authority),
+        );
+        blockchain.mine_block_parallel(alice.as_str(), 4)?;
+        let tip = blockchain.tip_block().unwrap();
+        assert!(tip.nonce.is_none() && tip.signature.is_some());
+        assert_eq!(blockchain.height(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn chain_verifies_after_difficulty_changes() -> Result<(), ChainError> {
+        let (mut blockchain, address) = funded_chain()?;
+        blockchain.miner_address = Some(address.to_string());
+        blockchain.mine_block()?;
+
+        blockchain.difficulty_bits = 16;
+
+        blockchain.verify_chain()?;
+        Ok(())
+    }
+
+    #[test]
+    fn chains_at_different_paths_are_independent() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let (_, address) = funded_chain()?;
+        let open = |name: &str| {
+            Blockchain::<String>::open_with_difficulty(
+                dir.path().join(name),
+                address.as_str(),
+                Blockchain::<String>::TEST_BITS,
+            )
+        };
+        let mut main = open("main")?;
+        let test = open("test")?;
+        main.miner_address = Some(address.to_string());
+
+        main.mine_block()?;
+
+        assert_eq!((main.height(), test.height()), (1, 0));
+        assert_eq!(main.balance_at(&address)?, 2 * Transaction::SUBSIDY);
+        assert_eq!(test.balance_at(&address)?, Transaction::SUBSIDY);
+        assert!(dir.path().join("main").exists() && dir.path().join("test").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn mined_block_survives_dropping_and_reopening_chain() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let (_, address) = funded_chain()?;
+        let path = dir.path().join("chain");
+        let open = || {
+            Blockchain::<String>::open_with_difficulty(
+                &path,
+                address.as_str(),
+                Blockchain::<String>::TEST_BITS,
+            )
+        };
+        let mut blockchain = open()?;
+        blockchain.miner_address = Some(address.to_string());
+        blockchain.mine_block()?;
+        let tip = blockchain.iter().next().unwrap().hash;
+        drop(blockchain);
+
+        let reopened = open()?;
+        assert_eq!(reopened.height(), 1);
+        assert_eq!(reopened.iter().next().unwrap().hash, tip);
+        assert_eq!(reopened.balance_at(&address)?, 2 * Transaction::SUBSIDY);
+        Ok(())
+    }
+
+    #[test]
+    fn export_import_round_trip_keeps_chain() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        blockchain.miner_address = Some(alice.to_string());
+        blockchain.send(&alice, &bob, 3, 1)?;
+        blockchain.mine_block()?;
+        blockchain.mine_block()?;
+        let mut dump = Vec::new();
+        blockchain.export_json(&mut dump)?;
+        // Chain the dump replaces, its blocks and their work must not linger
+        let path = dir.path().join("chain");
+        let mut old = Blockchain::<String>::open_with_difficulty(
+            &path,
+            bob.as_str(),
+            Blockchain::<String>::TEST_BITS,
+        )?;
+        old.miner_address = Some(bob.to_string());
+        old.mine_block()?;
+        drop(old);
+
+        let imported = Blockchain::<String>::import_json(&dump[..], &path)?;
+
+        assert_eq!(imported.tip, blockchain.tip);
+        assert_eq!(imported.height(), 2);
+        assert_eq!(imported.chain_id, blockchain.chain_id);
+        assert_eq!(imported.balance_at(&bob)?, 3);
+        assert_eq!(imported.balance_at(&alice)?, blockchain.balance_at(&alice)?);
+        assert_eq!(imported.total_work(), blockchain.total_work());
+        let tree = |name| imported.db.open_tree(name).map(|tree| tree.len());
+        assert_eq!(tree(Blockchain::<String>::WORK_BUCKET)?, 3);
+        assert_eq!(tree(Blockchain::<String>::SIDE_BUCKET)?, 0);
+        imported.verify_chain()?;
+        Ok(())
+    }
+
+    #[test]
+    fn dump_with_tampered_transaction_is_rejected() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("chain");
+        let (blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        let existing = Blockchain::<String>::open_with_difficulty(
+            &path,
+            alice.as_str(),
+            Blockchain::<String>::TEST_BITS,
+        )?;
+        let existing_tip = existing.tip.clone();
+        drop(existing);
+
+        // Links, seal and merkle root are all fine, only the signature is not
+        let mut spend = signed(
+            &blockchain,
+            &alice,
+            Transaction::new_tx(&alice, &bob, 2, 1, &blockchain)?,
+        )?;
+        let last = spend.vin[0].signature.len() - 1;
+        spend.vin[0].signature[last] ^= 1;
+        let height = blockchain.next_height()?;
+        let coinbase = Transaction::new_coinbase_tx(
+            alice.as_str(),
+            "",
+            blockchain.block_reward(height) + 1,
+            height,
+        )?;
+        let mut block = Block::template(
+            vec![coinbase, spend],
+            String::new(),
+            Some(blockchain.tip.clone()),
+            height,
+            blockchain.next_difficulty()?,
+        );
+        let (nonce, hash) = ProofOfWork::new(&block).run_deterministic().unwrap();
+        block.nonce = Some(nonce);
+        block.hash = Some(hash);
+        let genesis = blockchain.tip_block().unwrap();
+        assert!(block.validate(Some(&genesis)).is_ok());
+        let dump = serde_json::to_vec(&[genesis, block])?;
+
+        assert!(matches!(
+            Blockchain::<String>::import_json(&dump[..], &path),
+            Err(ChainError::InvalidSignature { .. })
+        ));
+        let existing = Blockchain::<String>::open_existing(&path)?.unwrap();
+        assert_eq!(existing.tip, existing_tip);
+        Ok(())
+    }
+
+    #[test]
+    fn block_reward_halves_until_zero() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let interval = Blockchain::<String>::HALVING_INTERVAL;
+        assert_eq!(blockchain.block_reward(0), Transaction::SUBSIDY);
+        assert_eq!(blockchain.block_reward(interval - 1), Transaction::SUBSIDY);
+        assert_eq!(blockchain.block_reward(interval), Transaction::SUBSIDY / 2);
+        assert_eq!(blockchain.block_reward(64 * interval), 0);
+        assert_eq!(blockchain.block_reward(u64::MAX), 0);
+
+        // Mined coinbases pay the reward of their height plus fees
+        let bob = new_wallet(&blockchain)?;
+        blockchain.halving_interval = 1;
+        blockchain.miner_address = Some(bob.to_string());
+        blockchain.send(&alice, &bob, 1, 3)?;
+        blockchain.mine_block()?;
+        let coinbase = &blockchain.tip_block().unwrap().transactions[0];
+        assert_eq!(coinbase.vout[0].value, Transaction::SUBSIDY / 2 + 3);
+        Ok(())
+    }
+
+    #[test]
+    fn send_moves_coins_from_sender_to_recipient() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        let miner = new_wallet(&blockchain)?;
+        blockchain.miner_address = Some(miner.to_string());
+        assert_eq!(blockchain.balance_at(&bob)?, 0);
+
+        blockchain.send(&alice, &bob, 4, 1)?;
+        blockchain.mine_block()?;
+
+        assert_eq!(blockchain.balance_at(&alice)?, Transaction::SUBSIDY - 4 - 1);
+        assert_eq!(blockchain.balance_at(&bob)?, 4);
+        assert_eq!(
+            blockchain.balance_at(&miner)?,
+            blockchain.block_reward(1) + 1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn audited_supply_follows_reward_schedule_through_sends() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        let miner = new_wallet(&blockchain)?;
+        blockchain.miner_address = Some(miner.to_string());
+        assert_eq!(blockchain.audit_supply()?, Transaction::SUBSIDY);
+
+        blockchain.mine_block()?;
+        blockchain.send(&alice, &bob, 4, 1)?;
+        blockchain.mine_block()?;
+        blockchain.send(&alice, &bob, 2, 2)?;
+        blockchain.send(&bob, &alice, 1, 1)?;
+        blockchain.mine_block()?;
+
+        let expected: u64 = (0..=blockchain.height())
+            .map(|height| blockchain.block_reward(height))
+            .sum();
+        assert_eq!(expected, 4 * Transaction::SUBSIDY);
+        assert_eq!(blockchain.audit_supply()?, expected);
+        blockchain.verify_chain()?;
+        Ok(())
+    }
+
+    #[test]
+    fn tx_index_matches_full_scan_and_rebuild() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        blockchain.miner_address = Some(alice.to_string());
+        blockchain.send(&alice, &bob, 3, 1)?;
+        blockchain.mine_block()?;
+        blockchain.send(&bob, &alice, 1, 1)?;
+        blockchain.mine_block()?;
+
+        let scanned: Vec<Transaction> = blockchain
+            .iter()
+            .flat_map(|block| block.transactions)
+            .collect();
+        assert_eq!(scanned.len(), 5);
+        for tx in &scanned {
+            let found = blockchain.find_transaction(&tx.id).unwrap();
+            assert_eq!(bincode::serialize(&found)?, bincode::serialize(tx)?);
+        }
+
+        let entries = || -> Result<Vec<_>, ChainError> {
+            let tree = blockchain
+                .db
+                .open_tree(Blockchain::<String>::TX_INDEX_BUCKET)?;
+            Ok(tree.iter().collect::<Result<Vec<_>, _>>()?)
+        };
+        let incremental = entries()?;
+        blockchain.reindex_transactions()?;
+        assert_eq!(entries()?, incremental);
+        assert!(blockchain
+            .find_transaction(&ByteBuf::from(vec![0; 32]))
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn stats_report_average_and_largest_block_size() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        blockchain.miner_address = Some(alice.to_string());
+        let genesis_bytes = blockchain.iter().next().unwrap().size_bytes();
+        blockchain.send(&alice, &bob, 3, 1)?;
+        blockchain.mine_block()?;
+        let tip_bytes = blockchain.iter().next().unwrap().size_bytes();
+
+        let stats = blockchain.stats()?;
+        assert!(tip_bytes > genesis_bytes);
+        assert_eq!(stats.max_block_bytes, tip_bytes);
+        assert_eq!(stats.avg_block_bytes, (genesis_bytes + tip_bytes) / 2);
+        Ok(())
+    }
+
+    /// Block paying `address` on top of the tip of `blockchain`, mined after its timestamp
+    /// is set to `timestamp`
+    fn block_at(
+        blockchain: &Blockchain<String>,
+        address: &Address,
+        timestamp: SystemTime,
+    ) -> Result<Block<String>, ChainError> {
+        let height = blockchain.next_height()?;
+        let coinbase = Transaction::new_coinbase_tx(
+            address.as_str(),
+            "",
+            blockchain.block_reward(height),
+            height,
+        )?;
+        let mut block = Block::template(
+            vec![coinbase],
+            String::new(),
+            Some(blockchain.tip.clone()),
+            height,
+            blockchain.next_difficulty()?,
+        );
+        block.timestamp = timestamp;
+        let (nonce, hash) = ProofOfWork::new(&block).run_deterministic().unwrap();
+        block.nonce = Some(nonce);
+        block.hash = Some(hash);
+        Ok(block)
+    }
+
+    #[test]
+    fn unsealed_block_is_refused() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let mut block = block_at(&blockchain, &alice, SystemTime::now())?;
+        block.hash = None;
+        assert!(matches!(
+            blockchain.accept_block(block),
+            Err(ChainError::UnsealedBlock { height: 1 })
+        ));
+        assert_eq!(blockchain.height(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_store_leaves_tip_block_and_utxos_untouched() -> Result<(), ChainError> {
+        let (blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        let genesis_txid = blockchain.tip_block().unwrap().transactions[0].id.clone();
+        let spend = signed(
+            &blockchain,
+            &alice,
+            Transaction::new_tx(&alice, &bob, 2, 1, &blockchain)?,
+        )?;
+        let mut block = block_at(&blockchain, &alice, SystemTime::now())?;
+        block.transactions.push(spend);
+        // Corrupt UTXO entry of the spent output, so applying the block fails after the
+        // block and tip were written inside the transaction
+        let utxos = blockchain.db.open_tree(UTXOSet::UTXO_BUCKET)?;
+        utxos.insert(genesis_txid.as_ref(), b"garbage".as_slice())?;
+
+        assert!(Blockchain::<String>::store_block(&blockchain.db, &block).is_err());
+
+        let blocks = blockchain
+            .db
+            .open_tree(Blockchain::<String>::BLOCKS_BUCKET)?;
+        let hash = block.hash.unwrap();
+        assert_eq!(blocks.get(b"l")?.unwrap().as_ref(), blockchain.tip.as_ref());
+        assert!(blocks.get(&hash)?.is_none());
+        let works = blockchain.db.open_tree(Blockchain::<String>::WORK_BUCKET)?;
+        assert!(works.get(&hash)?.is_none());
+        assert_eq!(utxos.get(&genesis_txid)?.unwrap().as_ref(), b"garbage");
+        Ok(())
+    }
+
+    #[test]
+    fn heavier_branch_with_fewer_blocks_wins_and_returns_orphans() -> Result<(), ChainError> {
+        const BLOCK_TIME: Duration = Duration::from_millis(50);
+        let wallet = Wallet::new();
+        let alice = Address::from_pub_key_hash(&Wallet::hash_pub_key(&wallet.public_key));
+        let genesis = GenesisConfig::new(alice.as_str());
+        // Both retarget every 2 blocks, the slow one waits before its first block so its
+        // difficulty drops where the fast one's rises
+        let retargeting = |slow: bool| -> Result<Blockchain<String>, ChainError> {
+            let db = sled::Config::new().temporary(true).open()?;
+            let mut blockchain = Blockchain::from_db(db, &genesis, 8)?;
+            blockchain.retarget_window = 2;
+            blockchain.target_block_time = BLOCK_TIME;
+            blockchain.miner_address = Some(alice.to_string());
+            if slow {
+                std::thread::sleep(BLOCK_TIME * 4);
+            }
+            Ok(blockchain)
+        };
+        let mut heavy = retargeting(false)?;
+        heavy.mine_block()?;
+        heavy.mine_block()?;
+        let dir = tempfile::tempdir()?;
+        let mut light = retargeting(true)?;
+        light.wallets_file = dir.path().join(Wallets::WALLETS_FILE);
+        Wallets::insert(&light.wallets_file, &wallet)?;
+        let bob = new_wallet(&light)?;
+        let txid = light.send(&alice, &bob, 3, 1)?;
+        for _ in 0..3 {
+            light.mine_block()?;
+        }
+        assert!(light.height() > heavy.height());
+        assert!(light.total_work() < heavy.total_work());
+
+        let branch: Vec<_> = heavy.iter_forward().skip(1).collect();
+        let switched: Vec<bool> = branch
+            .into_iter()
+            .map(|block| light.consider_block(block))
+            .collect::<Result<_, _>>()?;
+
+        assert_eq!(switched, [false, true]);
+        assert_eq!(light.tip, heavy.tip);
+        assert_eq!(light.total_work(), heavy.total_work());
+        assert!(light.mempool()?.contains(&txid)?);
+        assert_eq!(light.balance_at(&bob)?, 0);
+        light.verify_chain()?;
+        Ok(())
+    }
+
+    #[test]
+    fn abandoned_branch_wins_back_once_it_outweighs() -> Result<(), ChainError> {
+        let (_, alice) = funded_chain()?;
+        let (_, bob) = funded_chain()?;
+        let genesis = GenesisConfig::new(alice.as_str());
+        let chain = |miner: &Address| -> Result<Blockchain<String>, ChainError> {
+            let db = sled::Config::new().temporary(true).open()?;
+            let mut blockchain =
+                Blockchain::from_db(db, &genesis, Blockchain::<String>::TEST_BITS)?;
+            blockchain.miner_address = Some(miner.to_string());
+            Ok(blockchain)
+        };
+        let mut a = chain(&alice)?;
+        let mut b = chain(&bob)?;
+        for _ in 0..2 {
+            a.mine_block()?;
+        }
+        for _ in 0..3 {
+            b.mine_block()?;
+        }
+        let mut local = chain(&alice)?;
+        let mut consider = |source: &Blockchain<String>, from: usize| {
+            source
+                .iter_forward()
+                .skip(from)
+                .map(|block| local.consider_block(block))
+                .collect::<Result<Vec<bool>, _>>()
+        };
+
+        assert_eq!(consider(&a, 1)?, [true, true]);
+        assert_eq!(consider(&b, 1)?, [false, false, true]);
+        for _ in 0..2 {
+            a.mine_block()?;
+        }
+        assert_eq!(consider(&a, 3)?, [false, true]);
+
+        assert_eq!(local.tip, a.tip);
+        assert_eq!(local.total_work(), a.total_work());
+        let b_tip = b.tip.clone();
+        assert_eq!(local.chain_work(&b_tip), b.chain_work(&b_tip));
+        local.verify_chain()?;
+        Ok(())
+    }
+
+    #[test]
+    fn heavier_candidate_with_invalid_block_leaves_chain_untouched() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        blockchain.miner_address = Some(alice.to_string());
+        blockchain.send(&alice, &bob, 3, 1)?;
+        blockchain.mine_block()?;
+        let snapshot = |blockchain: &Blockchain<String>| -> Result<_, ChainError> {
+            let entries = |name| -> Result<Vec<_>, ChainError> {
+                Ok(blockchain
+                    .db
+                    .open_tree(name)?
+                    .iter()
+                    .collect::<Result<_, _>>()?)
+            };
+            Ok((
+                blockchain.tip.clone(),
+                entries(UTXOSet::UTXO_BUCKET)?,
+                entries(Blockchain::<String>::TX_INDEX_BUCKET)?,
+            ))
+        };
+        let before = snapshot(&blockchain)?;
+
+        // Valid first block, then one whose coinbase pays too much and a valid one on top
+        let mut candidate = blockchain.fork(1)?;
+        candidate.miner_address = Some(bob.to_string());
+        candidate.mine_block()?;
+        let mut blocks: Vec<_> = candidate.iter_forward().collect();
+        for overpay in [5, 0] {
+            let height = blocks.len() as u64;
+            let coinbase = Transaction::new_coinbase_tx(
+                bob.as_str(),
+                "",
+                candidate.block_reward(height) + overpay,
+                height,
+            )?;
+            let prev = blocks[blocks.len() - 1].hash.clone();
+            let mut block = Block::template(
+                vec![coinbase],
+                String::new(),
+                prev,
+                height,
+                Blockchain::<String>::TEST_BITS,
+            );
+            let (nonce, hash) = ProofOfWork::new(&block).run_deterministic().unwrap();
+            block.nonce = Some(nonce);
+            block.hash = Some(hash);
+            blocks.push(block);
+        }
+        // Same genesis, and more blocks at the same difficulty, so more work
+        assert!(blockchain.contains_block(blocks[0].hash.as_ref().unwrap()));
+        assert!(blocks.len() as u64 > blockchain.height() + 1);
+
+        assert!(matches!(
+            blockchain.replace_chain(blocks),
+            Err(ChainError::InvalidReward { height: 2, .. })
+        ));
+        assert_eq!(snapshot(&blockchain)?, before);
+        assert_eq!(blockchain.balance_at(&bob)?, 3);
+        blockchain.verify_chain()?;
+        Ok(())
+    }
+
+    #[test]
+    fn blocks_before_parent_or_far_in_future_are_rejected() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let parent = blockchain.tip_block().unwrap().timestamp;
+        blockchain.max_future_drift = Duration::from_secs(60);
+
+        let past = block_at(&blockchain, &alice, parent - Duration::from_secs(1))?;
+        assert!(matches!(
+            blockchain.consider_block(past),
+            Err(ChainError::Block(BlockError::TimestampBeforeParent {
+                height: 1
+            }))
+        ));
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        let future = block_at(&blockchain, &alice, future)?;
+        assert!(matches!(
+            blockchain.consider_block(future),
+            Err(ChainError::InvalidTimestamp { height: 1 })
+        ));
+
+        let now = block_at(&blockchain, &alice, SystemTime::now())?;
+        assert!(blockchain.consider_block(now)?);
+        assert_eq!(blockchain.height(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn send_many_pays_every_recipient_in_one_transaction() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let recipients = [
+            new_wallet(&blockchain)?,
+            new_wallet(&blockchain)?,
+            new_wallet(&blockchain)?,
+        ];
+        blockchain.miner_address = Some(new_wallet(&blockchain)?.to_string());
+        let outputs: Vec<_> = recipients
+            .iter()
+            .zip(1..)
+            .map(|(to, value)| (to.to_string(), value))
+            .collect();
+
+        let txid = blockchain.send_many(&alice, &outputs, 1)?;
+        blockchain.mine_block()?;
+
+        let tip = blockchain.tip_block().unwrap();
+        assert_eq!(tip.transactions.len(), 2);
+        assert_eq!(tip.transactions[1].id, txid);
+        assert_eq!(blockchain.balance_at(&alice)?, Transaction::SUBSIDY - 6 - 1);
+        for (to, value) in recipients.iter().zip(1..) {
+            assert_eq!(blockchain.balance_at(to)?, value);
+        }
+        assert!(matches!(
+            blockchain.send_many(&alice, &[("1nvalid".to_string(), 1)], 1),
+            Err(ChainError::InvalidAddress)
+        ));
+        Ok(())
+    }
+
+    /// `tx` signed by `from`'s wallet in the wallets file of `blockchain`
+    fn signed(
+        blockchain: &Blockchain<String>,
+        from: &Address,
+        mut tx: Transaction,
+    ) -> Result<Transaction, ChainError> {
+        let wallets = Wallets::open(&blockchain.wallets_file)?;
+        let wallet = wallets.get(&ByteBuf::from(from.as_str())).unwrap();
+        tx.sign(
+            &wallet.signing_key()?,
+            &blockchain.collect_prev_txs(&tx),
+            blockchain.chain_id,
+        )?;
+        Ok(tx)
+    }
+
+    #[test]
+    fn block_spending_an_output_twice_is_rejected() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        let genesis_txid = blockchain.tip_block().unwrap().transactions[0].id.clone();
+        // Both spend the genesis coinbase
+        let first = signed(
+            &blockchain,
+            &alice,
+            Transaction::new_tx(&alice, &bob, 2, 1, &blockchain)?,
+        )?;
+        let second = signed(
+            &blockchain,
+            &alice,
+            Transaction::new_tx(&alice, &bob, 3, 1, &blockchain)?,
+        )?;
+        let coinbase =
+            Transaction::new_coinbase_tx(alice.as_str(), "", blockchain.block_reward(1) + 2, 1)?;
+
+        let result = blockchain.add_block(vec![coinbase, first.clone(), second.clone()]);
+        assert!(matches!(
+            result,
+            Err(ChainError::DoubleSpend { txid, vout: 0 }) if txid == genesis_txid
+        ));
+        assert_eq!(blockchain.height(), 0);
+
+        // Once `first` is mined, `second` spends an output spent earlier in chain
+        let coinbase =
+            Transaction::new_coinbase_tx(alice.as_str(), "", blockchain.block_reward(1) + 1, 1)?;
+        blockchain.add_block(vec![coinbase, first])?;
+        let coinbase =
+            Transaction::new_coinbase_tx(alice.as_str(), "", blockchain.block_reward(2) + 1, 2)?;
+        assert!(matches!(
+            blockchain.add_block(vec![coinbase, second]),
+            Err(ChainError::DoubleSpend { .. })
+        ));
+        assert_eq!(blockchain.height(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn coinbase_spend_waits_for_maturity() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        let genesis_txid = blockchain.tip_block().unwrap().transactions[0].id.clone();
+        // Built before maturity is set, `new_tx` skips immature outputs
+        let spend = signed(
+            &blockchain,
+            &alice,
+            Transaction::new_tx(&alice, &bob, 2, 1, &blockchain)?,
+        )?;
+        blockchain.coinbase_maturity = 3;
+        let coinbase =
+            Transaction::new_coinbase_tx(alice.as_str(), "", blockchain.block_reward(1) + 1, 1)?;
+        assert!(matches!(
+            blockchain.add_block(vec![coinbase, spend.clone()]),
+            Err(ChainError::ImmatureCoinbase { txid }) if txid == genesis_txid
+        ));
+        assert_eq!(blockchain.height(), 0);
+
+        blockchain.miner_address = Some(bob.to_string());
+        blockchain.mine_block()?;
+        blockchain.mine_block()?;
+        let coinbase =
+            Transaction::new_coinbase_tx(alice.as_str(), "", blockchain.block_reward(3) + 1, 3)?;
+        blockchain.add_block(vec![coinbase, spend])?;
+        assert_eq!(blockchain.height(), 3);
+        assert_eq!(blockchain.balance_at(&bob)?, 2 * Transaction::SUBSIDY + 2);
+        Ok(())
+    }
+
+    #[test]
+    fn block_with_outputs_summing_past_u64_max_is_rejected() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let mut coinbase = Transaction::new_coinbase_tx(alice.as_str(), "", u64::MAX, 1)?;
+        coinbase.vout.push(TXOutput::new(u64::MAX, alice.as_str())?);
+
+        assert!(matches!(
+            blockchain.add_block(vec![coinbase]),
+            Err(ChainError::AmountOverflow)
+        ));
+        assert_eq!(blockchain.height(), 0);
+        assert_eq!(blockchain.balance_at(&alice)?, Transaction::SUBSIDY);
+        Ok(())
+    }
+
+    #[test]
+    fn coinbases_to_same_address_get_distinct_ids() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        blockchain.miner_address = Some(bob.to_string());
+        blockchain.mine_block()?;
+        blockchain.mine_block()?;
+        let first = blockchain.get_block_by_height(1).unwrap().transactions[0].clone();
+        let second = blockchain.get_block_by_height(2).unwrap().transactions[0].clone();
+        assert_ne!(first.id, second.id);
+
+        // Needs both coinbase outputs
+        blockchain.miner_address = Some(alice.to_string());
+        blockchain.send(&bob, &alice, 15, 1)?;
+        blockchain.mine_block()?;
+
+        let spent = &blockchain.tip_block().unwrap().transactions[1];
+        let mut inputs: Vec<_> = spent.vin.iter().map(|vin| vin.txid.clone()).collect();
+        inputs.sort();
+        let mut coinbases = vec![first.id, second.id];
+        coinbases.sort();
+        assert_eq!(inputs, coinbases);
+        assert_eq!(
+            blockchain.balance_at(&bob)?,
+            2 * Transaction::SUBSIDY - 15 - 1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fewer_harder_blocks_carry_more_work() -> Result<(), ChainError> {
+        let (_, address) = funded_chain()?;
+        let mined = |difficulty_bits: u64, blocks: usize| {
+            let mut blockchain = Blockchain::<String>::new_in_memory_with_difficulty(
+                address.as_str(),
+                difficulty_bits,
+            )?;
+            blockchain.miner_address = Some(address.to_string());
+            for _ in 0..blocks {
+                blockchain.mine_block()?;
+            }
+            Ok::<_, ChainError>(blockchain)
+        };
+        let hard = mined(10, 1)?;
+        let easy = mined(1, 5)?;
+
+        assert!(hard.height() < easy.height());
+        assert!(hard.total_work() > easy.total_work());
+        assert_eq!(hard.total_work(), BigUint::from(2u32 * 1024));
+        assert_eq!(easy.total_work(), BigUint::from(6u32 * 2));
+        Ok(())
+    }
+
+    #[test]
+    fn fee_estimate_falls_within_recent_fee_rates() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        blockchain.miner_address = Some(alice.to_string());
+        blockchain.min_fee_rate = 1;
+        assert_eq!(blockchain.estimate_fee(1), 1);
+
+        for _ in 0..3 {
+            blockchain.mine_block()?;
+        }
+        let mut rates = Vec::new();
+        for fee in [1, 3, 6] {
+            let txid = blockchain.send(&alice, &bob, 1, fee)?;
+            blockchain.mine_block()?;
+            let tx = blockchain.find_transaction(&txid).unwrap();
+            rates.push(fee * 1000 / tx.size_bytes() as u64);
+        }
+
+        let (low, high) = (rates[0], rates[2]);
+        for target_blocks in [1, 2, 10] {
+            let estimate = blockchain.estimate_fee(target_blocks);
+            assert!(
+                (low..=high).contains(&estimate),
+                "{estimate} not in {low}..={high}"
+            );
+        }
+        assert_eq!(blockchain.estimate_fee(1), high);
+        blockchain.min_fee_rate = high + 1;
+        assert_eq!(blockchain.estimate_fee(1), high + 1);
+        Ok(())
+    }
+
+    /// In-memory chain at `TEST_BITS` keeping its wallets in `dir`, with a funded address
+    fn in_memory_chain(dir: &Path) -> Result<(Blockchain<String>, Address), ChainError> {
+        let wallet = Wallet::new();
+        let address = Address::from_pub_key_hash(&Wallet::hash_pub_key(&wallet.public_key));
+        let mut blockchain = Blockchain::new_in_memory_with_difficulty(
+            address.as_str(),
+            Blockchain::<String>::TEST_BITS,
+        )?;
+        blockchain.wallets_file = dir.join(Wallets::WALLETS_FILE);
+        Wallets::insert(&blockchain.wallets_file, &wallet)?;
+        Ok((blockchain, address))
+    }
+
+    #[test]
+    fn in_memory_chain_adds_blocks() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let (mut blockchain, alice) = in_memory_chain(dir.path())?;
+        let reward = blockchain.block_reward(1);
+        let coinbase = Transaction::new_coinbase_tx(alice.as_str(), "", reward, 1)?;
+
+        blockchain.add_block(vec![coinbase])?;
+
+        assert_eq!(blockchain.height(), 1);
+        assert_eq!(
+            blockchain.balance_at(&alice)?,
+            Transaction::SUBSIDY + reward
+        );
+        assert!(matches!(
+            blockchain.add_block(Vec::new()),
+            Err(ChainError::Block(BlockError::CoinbaseCount {
+                height: 2,
+                count: 0
+            }))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn in_memory_chains_send_and_keep_balances_apart() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let (mut blockchain, alice) = in_memory_chain(dir.path())?;
+        let (other, _) = in_memory_chain(dir.path())?;
+        let bob = new_wallet(&blockchain)?;
+        blockchain.miner_address = Some(alice.to_string());
+
+        blockchain.send(&alice, &bob, 4, 1)?;
+        blockchain.mine_block()?;
+
+        assert_eq!(blockchain.balance_at(&bob)?, 4);
+        assert_eq!(blockchain.balance_at(&alice)?, 2 * Transaction::SUBSIDY - 4);
+        assert_eq!(other.balance_at(&bob)?, 0);
+        assert_eq!(other.balance_at(&alice)?, 0);
+        Ok(())
+    }
+
+    /// Create, send twice mining each send, verify: the CLI scenario of `--difficulty`
+    #[test]
+    fn sends_mined_into_blocks_move_coins() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        blockchain.miner_address = Some(alice.to_string());
+
+        blockchain.send(&alice, &bob, 6, 1)?;
+        blockchain.mine_block()?;
+        blockchain.send(&bob, &alice, 2, 1)?;
+        blockchain.mine_block()?;
+
+        blockchain.verify_chain()?;
+        assert_eq!(blockchain.height(), 2);
+        assert_eq!(blockchain.balance_at(&bob)?, 3);
+        assert_eq!(blockchain.balance_at(&alice)?, 3 * Transaction::SUBSIDY - 3);
+        Ok(())
+    }
+
+    #[test]
+    fn fee_market_stats_rank_mempool_fee_rates() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        blockchain.miner_address = Some(alice.to_string());
+        blockchain.min_fee_rate = 2;
+        let empty = blockchain.fee_market_stats();
+        assert_eq!((empty.min_fee_rate, empty.max_fee_rate), (2, 2));
+        assert!(empty.estimated_blocks_to_confirm.is_empty());
+
+        blockchain.mine_block()?;
+        blockchain.send(&alice, &bob, 1, 1)?;
+        blockchain.send(&alice, &bob, 1, 5)?;
+        blockchain.max_block_txs = 1;
+
+        let stats = blockchain.fee_market_stats();
+        let mempool = blockchain.mempool()?;
+        assert_eq!(stats.mempool_tx_count, 2);
+        assert_eq!(stats.mempool_total_bytes, mempool.size_bytes()?);
+        assert!(stats.min_fee_rate < stats.max_fee_rate);
+        assert_eq!(stats.median_fee_rate, stats.min_fee_rate);
+        assert_eq!(stats.estimated_blocks_to_confirm[&stats.max_fee_rate], 1);
+        assert_eq!(stats.estimated_blocks_to_confirm[&stats.min_fee_rate], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn mined_block_fits_max_block_bytes_and_leaves_the_rest_queued() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        blockchain.miner_address = Some(alice.to_string());
+        let senders = (0..4)
+            .map(|_| new_wallet(&blockchain))
+            .collect::<Result<Vec<_>, _>>()?;
+        let outputs: Vec<_> = senders.iter().map(|to| (to.to_string(), 2)).collect();
+        blockchain.send_many(&alice, &outputs, 1)?;
+        blockchain.mine_block()?;
+        for sender in &senders {
+            blockchain.send(sender, &alice, 1, 1)?;
+        }
+
+        let mut pending = blockchain.mempool()?.collect(usize::MAX)?;
+        assert_eq!(pending.len(), 4);
+        pending.truncate(2);
+        let message = format!("Reward to {alice}");
+        let coinbase = Transaction::new_coinbase_tx(alice.as_str(), &message, 0, 2)?;
+        let mut fitting = vec![coinbase];
+        fitting.extend(pending);
+        blockchain.max_block_bytes = Blockchain::block_bytes(&fitting, &String::new());
+        blockchain.mine_block()?;
+
+        let tip = blockchain.tip_block().unwrap();
+        assert!(tip.size_bytes() <= blockchain.max_block_bytes);
+        assert_eq!(tip.transactions.len(), 3);
+        let mempool = blockchain.mempool()?;
+        assert_eq!(mempool.len(), 2);
+        assert!(tip.transactions[1..]
+            .iter()
+            .all(|tx| !mempool.contains(&tx.id).unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn mine_block_evicts_invalid_mempool_transactions() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        blockchain.miner_address = Some(alice.to_string());
+        // Never signed, so it fails verification once a block is assembled
+        let unsigned = Transaction::new_tx(&alice, &bob, 2, 1, &blockchain)?;
+        blockchain.mempool()?.add(unsigned, 1)?;
+
+        blockchain.mine_block()?;
+
+        assert_eq!(blockchain.height(), 1);
+        assert!(blockchain.mempool()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn stored_block_drops_conflicting_mempool_transactions() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        // Both spend the genesis coinbase
+        let mut mined = Transaction::new_tx(&alice, &bob, 2, 1, &blockchain)?;
+        let queued = Transaction::new_tx(&alice, &bob, 3, 1, &blockchain)?;
+        blockchain.sign_and_queue(alice.as_str(), queued)?;
+
+        let wallets = Wallets::open(&blockchain.wallets_file)?;
+        let wallet = wallets.get(&ByteBuf::from(alice.as_str())).unwrap();
+        mined.sign(
+            &wallet.signing_key()?,
+            &blockchain.collect_prev_txs(&mined),
+            blockchain.chain_id,
+        )?;
+        let coinbase =
+            Transaction::new_coinbase_tx(alice.as_str(), "", blockchain.block_reward(1) + 1, 1)?;
+        blockchain.add_block(vec![coinbase, mined])?;
+
+        assert_eq!(blockchain.balance_at(&bob)?, 2);
+        assert!(blockchain.mempool()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn rewind_restores_state_after_earlier_block() -> Result<(), ChainError> {
+        let (mut blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        blockchain.miner_address = Some(alice.to_string());
+        blockchain.mine_block()?;
+        blockchain.mine_block()?;
+        blockchain.mine_block()?;
+        let tip = blockchain.tip.clone();
+        let balance = blockchain.balance_at(&alice)?;
+
+        blockchain.send(&alice, &bob, 2, 1)?;
+        let dropped = blockchain.mine_block()?;
+        blockchain.mine_block()?;
+        // Spends the only output of bob, mined in a dropped block
+        blockchain.send(&bob, &alice, 1, 1)?;
+        blockchain.rewind(2)?;
+
+        assert_eq!(blockchain.height(), 3);
+        assert_eq!(blockchain.tip, tip);
+        assert_eq!(blockchain.balance_at(&alice)?, balance);
+        assert_eq!(blockchain.balance_at(&bob)?, 0);
+        assert!(blockchain.chain_work(&dropped).is_none());
+        assert!(blockchain
+            .db
+            .open_tree(Blockchain::<String>::TX_INDEX_BUCKET)?
+            .iter()
+            .values()
+            .all(|block| block
+                .is_ok_and(|block| blockchain.contains_block(&ByteBuf::from(block.to_vec())))));
+        assert!(blockchain.mempool()?.is_empty());
+        blockchain.verify_chain()
+    }
+
+    #[test]
+    fn rewind_refuses_to_drop_genesis() -> Result<(), ChainError> {
+        let (mut blockchain, _) = funded_chain()?;
+        assert!(matches!(
+            blockchain.rewind(1),
+            Err(ChainError::RewindPastGenesis { height: 0 })
+        ));
+        assert!(blockchain.tip_block().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn custom_checkpoints_reject_other_blocks() -> Result<(), ChainError> {
+        let (_, address) = funded_chain()?;
+        let dir = tempfile::tempdir()?;
+        let db = sled::Config::new()
+            .path(dir.path())
+            .temporary(true)
+            .open()?;
+        let config = ChainConfig {
+            checkpoints: vec![(1, "00".repeat(32))],
+        };
+        let mut blockchain = Blockchain::<String>::from_db_with_config(
+            db,
+            &GenesisConfig::new(address.as_str()),
+            &config,
+            Blockchain::<String>::TEST_BITS,
+        )?;
+        blockchain.miner_address = Some(address.to_string());
+
+        assert_eq!(
+            blockchain.checkpoints().get(1),
+            Some(&ByteBuf::from(vec![0; 32]))
+        );
+        assert!(matches!(
+            blockchain.mine_block(),
+            Err(ChainError::CheckpointMismatch { height: 1 })
+        ));
+        assert_eq!(blockchain.next_height()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_chain_builds_transactions_without_writing() -> Result<(), ChainError> {
+        let (blockchain, alice) = funded_chain()?;
+        let bob = new_wallet(&blockchain)?;
+        let db = blockchain.db.clone();
+        // Like a chain made before chain ids were cached, opening it normally stores the id
+        db.remove(Blockchain::<String>::CHAIN_ID_KEY)?;
+        let checksum = db.checksum()?;
+
+        let mut read_only = Blockchain::<String>::with_stored_db(
+            db.clone(),
+            blockchain.tip.clone(),
+            Blockchain::<String>::TEST_BITS,
+            true,
+        )?;
+        read_only.wallets_file = blockchain.wallets_file.clone();
+        let tx = Transaction::new_tx(&alice, &bob, 2, 1, &read_only)?;
+        assert!(read_only.is_read_only());
+        assert_eq!(tx.vout[0].value, 2);
+        drop(read_only);
+
+        assert_eq!(db.checksum()?, checksum);
+        assert!(!db.contains_key(Blockchain::<String>::CHAIN_ID_KEY)?);
+        assert_eq!(blockchain.height(), 0);
+        assert!(blockchain.mempool()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn iter_forward_starts_at_genesis_and_climbs() -> Result<(), ChainError> {
+        let (mut blockchain, address) = funded_chain()?;
+        blockchain.miner_address = Some(address.to_string());
+        for _ in 0..3 {
+            blockchain.mine_block()?;
+        }
+
+        let blocks: Vec<Block<String>> = blockchain.iter_forward().collect();
+        assert_eq!(blocks.len(), 4);
+        assert!(blocks[0].previous_block_hash.is_none());
+        assert!(blocks
+            .iter()
+            .enumerate()
+            .all(|(height, block)| block.height == height as u64));
+        assert_eq!(blocks[3].hash, blockchain.iter().next().unwrap().hash);
+        Ok(())
+    }
+
+    #[test]
+    fn watcher_sees_mined_block() -> Result<(), ChainError> {
+        let (mut blockchain, address) = funded_chain()?;
+        blockchain.miner_address = Some(address.to_string());
+        let mut watcher = blockchain.watch_blocks()?;
+        let timeout = Duration::from_secs(5);
+
+        blockchain.mine_block()?;
+
+        let block = watcher.next_timeout(timeout).unwrap();
+        assert_eq!(block.height, 1);
+        assert_eq!(block.hash, blockchain.iter().next().unwrap().hash);
+        assert!(watcher.next_timeout(Duration::from_millis(50)).is_none());
+        Ok(())
     }
 }
@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+
+use serde_bytes::ByteBuf;
+
+use crate::{blockchain::Blockchain, error::ChainError, Blockchainable};
+
+/// Hardcoded `(height, hex block hash)` pairs every chain must contain
+const CHECKPOINTS: &[(u64, &str)] = &[];
+
+#[derive(Clone)]
+pub struct CheckpointSet(BTreeMap<u64, ByteBuf>);
+
+impl CheckpointSet {
+    /// Builds set from `(height, hex block hash)` pairs
+    pub fn from_pairs<S: AsRef<str>>(pairs: &[(u64, S)]) -> Result<Self, ChainError> {
+        let mut checkpoints = BTreeMap::new();
+        for (height, hash) in pairs {
+            checkpoints.insert(*height, ByteBuf::from(hex::decode(hash.as_ref())?));
+        }
+        Ok(CheckpointSet(checkpoints))
+    }
+
+    /// Hardcoded checkpoints plus custom `(height, hex block hash)` pairs, custom ones win
+    /// at heights both have
+    pub fn with_custom<S: AsRef<str>>(pairs: &[(u64, S)]) -> Result<Self, ChainError> {
+        let mut checkpoints = Self::default();
+        checkpoints.0.extend(Self::from_pairs(pairs)?.0);
+        Ok(checkpoints)
+    }
+
+    pub fn get(&self, height: u64) -> Option<&ByteBuf> {
+        self.0.get(&height)
+    }
+
+    /// Fails with `CheckpointMismatch` when block at checkpointed height has other hash
+    pub fn check(&self, height: u64, hash: &ByteBuf) -> Result<(), ChainError> {
+        match self.get(height) {
+            Some(expected) if expected != hash => Err(ChainError::CheckpointMismatch { height }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that every checkpoint up to the tip height has the expected hash in chain
+    pub fn verify<T>(&self, blockchain: &Blockchain<T>) -> Result<bool, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let mut hashes: Vec<ByteBuf> = blockchain
+            .iter()
+            .map(|block| block.hash.unwrap_or_default())
+            .collect();
+        hashes.reverse();
+
+        for (height, expected) in &self.0 {
+            if let Some(hash) = hashes.get(*height as usize) {
+                if hash != expected {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Default for CheckpointSet {
+    fn default() -> Self {
+        Self::from_pairs(CHECKPOINTS).expect("Hardcoded checkpoints are not valid hex!")
+    }
+}
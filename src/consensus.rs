@@ -0,0 +1,167 @@
+use serde_bytes::ByteBuf;
+
+use crate::{
+    block::Block,
+    hasher::{Hasher, Sha256Hasher},
+    pow::ProofOfWork,
+    wallet::Wallet,
+    Blockchainable,
+};
+
+/// How blocks past genesis are sealed and how their seal is checked. Genesis always comes
+/// from `GenesisConfig` and is checked by proof of work, whatever a chain uses after it
+pub trait Consensus<T>: Send + Sync {
+    /// Seal for a block not sealed yet, `None` when this node can not seal it
+    fn seal(&self, block: &Block<T>) -> Option<SealResult>;
+
+    /// Like `seal`, searching on `threads` threads of the calling rayon pool where sealing is
+    /// a search
+    fn seal_parallel(&self, block: &Block<T>, _threads: usize) -> Option<SealResult> {
+        self.seal(block)
+    }
+
+    /// Whether `block` carries a valid seal
+    fn verify(&self, block: &Block<T>) -> bool;
+}
+
+/// What sealing sets on a block, see `Block::apply_seal`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SealResult {
+    pub hash: ByteBuf,
+    pub nonce: Option<u64>,
+    pub signature: Option<ByteBuf>,
+}
+
+/// Sealing by `ProofOfWork` at the block's `difficulty_bits`, what every chain uses by default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PowConsensus;
+
+impl<T> Consensus<T> for PowConsensus
+where
+    T: Blockchainable,
+{
+    fn seal(&self, block: &Block<T>) -> Option<SealResult> {
+        let (nonce, hash) = ProofOfWork::new(block).run()?;
+        Some(SealResult {
+            hash,
+            nonce: Some(nonce),
+            signature: None,
+        })
+    }
+
+    fn seal_parallel(&self, block: &Block<T>, threads: usize) -> Option<SealResult> {
+        let (nonce, hash) = ProofOfWork::new(block).run_parallel(threads as u64)?;
+        Some(SealResult {
+            hash,
+            nonce: Some(nonce),
+            signature: None,
+        })
+    }
+
+    fn verify(&self, block: &Block<T>) -> bool {
+        block.signature.is_none() && ProofOfWork::new(block).validate()
+    }
+}
+
+/// Sealing by a signature of one of a fixed set of authorities instead of a nonce, for
+/// permissioned chains where mining would only burn power. The block hash is the SHA-256 of
+/// its header with nonce 0 and the signature is made over those same header bytes
+#[derive(Clone)]
+pub struct ProofOfAuthority {
+    /// Sec1 encoded public keys allowed to seal blocks
+    pub authorities: Vec<ByteBuf>,
+    /// Wallet sealing blocks made by this node, `None` on nodes only verifying
+    pub signer: Option<Wallet>,
+}
+
+impl ProofOfAuthority {
+    pub fn new(authorities: Vec<ByteBuf>) -> Self {
+        ProofOfAuthority {
+            authorities,
+            signer: None,
+        }
+    }
+
+    /// Seals blocks with `signer`, which only succeeds while its key is an authority
+    pub fn with_signer(mut self, signer: Wallet) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+}
+
+impl<T> Consensus<T> for ProofOfAuthority
+where
+    T: Blockchainable,
+{
+    fn seal(&self, block: &Block<T>) -> Option<SealResult> {
+        let signer = self.signer.as_ref()?;
+        if !self.authorities.contains(&signer.public_key) {
+            return None;
+        }
+        let header = block.header().bytes();
+        Some(SealResult {
+            hash: ByteBuf::from(Sha256Hasher.hash(&header)),
+            nonce: None,
+            signature: Some(signer.sign_data(&header)),
+        })
+    }
+
+    fn verify(&self, block: &Block<T>) -> bool {
+        let (None, Some(signature)) = (block.nonce, &block.signature) else {
+            return false;
+        };
+        let header = block.header().bytes();
+        let hash = Sha256Hasher.hash(&header);
+        block
+            .hash
+            .as_ref()
+            .is_some_and(|stored| stored.as_slice() == hash.as_slice())
+            && self
+                .authorities
+                .iter()
+                .any(|key| Wallet::verify_data(key, &header, signature))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transaction;
+
+    const ADDRESS: &str = "1MBnzAR1oDnakY5fKLWvDeFD87PhPH9q1f";
+
+    /// Block at height 1 sealed by `consensus`
+    fn sealed(consensus: &dyn Consensus<String>) -> Block<String> {
+        let coinbase = Transaction::new_coinbase_tx(ADDRESS, "", Transaction::SUBSIDY, 1).unwrap();
+        Block::new_with(consensus, vec![coinbase], String::new(), None, 1, 1)
+    }
+
+    #[test]
+    fn authority_signed_block_validates_and_outsider_signed_is_rejected() {
+        let authority = Wallet::new();
+        let outsider = Wallet::new();
+        let poa = ProofOfAuthority::new(vec![authority.public_key.clone()]);
+
+        let block = sealed(&poa.clone().with_signer(authority));
+        assert!(block.nonce.is_none() && block.signature.is_some());
+        assert!(poa.verify(&block));
+        assert!(!PowConsensus.verify(&block));
+
+        // Sealing with a key that is not an authority is refused outright
+        assert!(
+            Consensus::<String>::seal(&poa.clone().with_signer(outsider.clone()), &block).is_none()
+        );
+        // A block an outsider sealed for itself is not accepted by the real authorities
+        let own = ProofOfAuthority::new(vec![outsider.public_key.clone()]).with_signer(outsider);
+        let block = sealed(&own);
+        assert!(own.verify(&block));
+        assert!(!poa.verify(&block));
+    }
+
+    #[test]
+    fn mined_block_fails_authority_check() {
+        let block = sealed(&PowConsensus);
+        assert!(PowConsensus.verify(&block));
+        assert!(!ProofOfAuthority::new(vec![Wallet::new().public_key]).verify(&block));
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use crate::error::ChainError;
+
+/// Environment variable naming the directory chain and wallets are kept in
+pub const DATA_DIR_ENV: &str = "RUSTCHAIN_DATA_DIR";
+/// Subdirectory of the platform data directory used when nothing else is set
+const APP_DIR: &str = "rust-chain";
+
+/// Directory chain and wallets are kept in: `RUSTCHAIN_DATA_DIR` if set, otherwise
+/// `rust-chain` under the platform data directory (`$XDG_DATA_HOME`, `~/.local/share`,
+/// `~/Library/Application Support` or `%APPDATA%`), otherwise the working directory
+pub fn data_dir() -> PathBuf {
+    non_empty_var(DATA_DIR_ENV)
+        .or_else(|| platform_data_dir().map(|dir| dir.join(APP_DIR)))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// `dir_override` if given, `data_dir` otherwise, created when missing
+pub fn resolve(dir_override: Option<&Path>) -> Result<PathBuf, ChainError> {
+    let dir = dir_override.map_or_else(data_dir, Path::to_path_buf);
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn platform_data_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        return non_empty_var("APPDATA");
+    }
+    if let Some(dir) = non_empty_var("XDG_DATA_HOME") {
+        return Some(dir);
+    }
+    let home = non_empty_var("HOME")?;
+    if cfg!(target_os = "macos") {
+        Some(home.join("Library").join("Application Support"))
+    } else {
+        Some(home.join(".local").join("share"))
+    }
+}
+
+fn non_empty_var(key: &str) -> Option<PathBuf> {
+    env::var_os(key)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The only test touching `RUSTCHAIN_DATA_DIR`, so no other test sees it change
+    #[test]
+    fn flag_wins_over_env_and_env_wins_over_default() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let from_env = dir.path().join("env");
+        let from_flag = dir.path().join("flag");
+
+        env::remove_var(DATA_DIR_ENV);
+        let default = data_dir();
+        assert_ne!(default, from_env);
+
+        env::set_var(DATA_DIR_ENV, &from_env);
+        assert_eq!(data_dir(), from_env);
+        assert_eq!(resolve(None)?, from_env);
+        assert_eq!(resolve(Some(&from_flag))?, from_flag);
+        env::remove_var(DATA_DIR_ENV);
+
+        assert!(from_env.is_dir() && from_flag.is_dir());
+        assert_eq!(data_dir(), default);
+        Ok(())
+    }
+}
@@ -0,0 +1,374 @@
+use std::fmt::Display;
+
+use serde_bytes::ByteBuf;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+
+use crate::transaction::Transaction;
+
+#[derive(Debug)]
+pub enum ChainError {
+    /// Underlying sled database failure
+    Db(sled::Error),
+    /// Bincode (de)serialization failure
+    Serialize(bincode::Error),
+    /// Mining worker pool could not be created
+    ThreadPool(rayon::ThreadPoolBuildError),
+    /// Whole nonce space searched without finding a valid hash
+    NonceExhausted,
+    /// Block at checkpointed height does not have the expected hash
+    CheckpointMismatch { height: u64 },
+    /// Malformed hex string
+    Hex(hex::FromHexError),
+    /// Transaction referenced by an input could not be found
+    MissingPrevTx { txid: ByteBuf },
+    /// Malformed private or public key
+    Key(p256::ecdsa::Error),
+    /// Transaction signature does not verify
+    InvalidSignature { txid: ByteBuf },
+    /// Spendable outputs do not cover requested value
+    NotEnoughFunds { have: u64, need: u64 },
+    /// No wallet stored for given address
+    WalletNotFound,
+    /// Address is not valid base58check
+    InvalidAddress,
+    /// Block at given height is missing or its parent link does not match
+    BrokenLink { height: u64 },
+    /// Block at given height does not satisfy its proof of work
+    InvalidProofOfWork { height: u64 },
+    /// Block is older than its parent or too far in the future
+    InvalidTimestamp { height: u64 },
+    /// Block without parent found above height 0
+    InvalidGenesis { height: u64 },
+    /// File system failure
+    Io(std::io::Error),
+    /// Malformed JSON chain dump
+    Json(serde_json::Error),
+    /// Transaction creates more value than it spends
+    NegativeFee { txid: ByteBuf },
+    /// Output is already spent
+    DoubleSpend { txid: ByteBuf, vout: usize },
+    /// Mining requested without an address to reward
+    NoMinerAddress,
+    /// Peer answered with a message that does not fit the request
+    UnexpectedMessage,
+    /// Wallets could not be encrypted
+    Encryption,
+    /// Wrong passphrase or corrupted encrypted wallets file
+    Decryption,
+    /// Rewind would remove the genesis block of a chain at given height
+    RewindPastGenesis { height: u64 },
+    /// Phrase is not a valid BIP39 mnemonic
+    InvalidMnemonic,
+    /// Private key string is not a valid WIF made by `Wallet::export_wif`
+    InvalidWif { reason: &'static str },
+    /// Mempool limits reached and transaction pays too little to evict pending ones
+    MempoolFull,
+    /// Coinbase of block at given height does not pay exactly the block reward plus fees
+    InvalidReward {
+        height: u64,
+        expected: u64,
+        actual: u64,
+    },
+    /// Coinbase transaction offered anywhere but the start of a block it is mined in
+    UnexpectedCoinbase { txid: ByteBuf },
+    /// Sum of amounts does not fit in `u64`
+    AmountOverflow,
+    /// Coinbase input data of given length exceeds `Transaction::MAX_COINBASE_DATA`
+    CoinbaseDataTooLong { len: usize },
+    /// Raw private key bytes are not a valid P-256 scalar
+    InvalidPrivateKey { reason: &'static str },
+    /// Transaction spends outputs of given coinbase before `coinbase_maturity` blocks passed
+    ImmatureCoinbase { txid: ByteBuf },
+    /// Serialized block is larger than `max_block_bytes`
+    BlockTooLarge {
+        height: u64,
+        size: usize,
+        max: usize,
+    },
+    /// Unspent outputs do not add up to the block rewards minus burned value
+    SupplyMismatch { expected: u64, actual: u64 },
+    /// Peer announced a message frame longer than the node accepts
+    MessageTooLarge { len: usize, max: usize },
+    /// Peer sent an `Inv` with more block hashes than `MAX_INV_HASHES`
+    InvTooLong { len: usize, max: usize },
+    /// Input spends an output index its previous transaction does not have
+    MissingPrevOutput { txid: ByteBuf, vout: Option<usize> },
+    /// RPC request head or body is longer than the `max` bytes the server reads
+    RequestTooLarge { max: usize },
+    /// Block fails a check of `Block::validate`
+    Block(BlockError),
+    /// Peer did not sign the nonce of its handshake, so its blocks are refused
+    UnauthenticatedPeer,
+    /// Parent of the block at given height has no recorded chain work
+    MissingChainWork { height: u64 },
+    /// Block at given height has no hash, it was never sealed
+    UnsealedBlock { height: u64 },
+}
+
+impl Display for ChainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainError::Db(e) => write!(f, "Database error: {e}"),
+            ChainError::Serialize(e) => write!(f, "Serialization error: {e}"),
+            ChainError::ThreadPool(e) => write!(f, "Could not build mining thread pool: {e}"),
+            ChainError::NonceExhausted => write!(f, "No valid nonce found for block"),
+            ChainError::CheckpointMismatch { height } => {
+                write!(f, "Block at height {height} does not match checkpoint")
+            }
+            ChainError::Hex(e) => write!(f, "Invalid hex: {e}"),
+            ChainError::MissingPrevTx { txid } => {
+                write!(f, "Previous transaction {} not found", hex::encode(txid))
+            }
+            ChainError::Key(e) => write!(f, "Invalid key: {e}"),
+            ChainError::InvalidSignature { txid } => {
+                write!(f, "Transaction {} has invalid signature", hex::encode(txid))
+            }
+            ChainError::NotEnoughFunds { have, need } => {
+                write!(f, "Not enough funds: have {have}, need {need}")
+            }
+            ChainError::WalletNotFound => write!(f, "Wallet with given address not found"),
+            ChainError::InvalidAddress => write!(f, "Invalid address"),
+            ChainError::BrokenLink { height } => write!(f, "Broken link at height {height}"),
+            ChainError::InvalidProofOfWork { height } => {
+                write!(f, "Invalid proof of work at height {height}")
+            }
+            ChainError::InvalidTimestamp { height } => {
+                write!(f, "Invalid timestamp at height {height}")
+            }
+            ChainError::InvalidGenesis { height } => {
+                write!(f, "Block at height {height} has no previous block")
+            }
+            ChainError::Io(e) => write!(f, "IO error: {e}"),
+            ChainError::Json(e) => write!(f, "JSON error: {e}"),
+            ChainError::NegativeFee { txid } => {
+                write!(
+                    f,
+                    "Transaction {} spends less than it creates",
+                    hex::encode(txid)
+                )
+            }
+            ChainError::DoubleSpend { txid, vout } => {
+                write!(f, "Output {}:{vout} is already spent", hex::encode(txid))
+            }
+            ChainError::NoMinerAddress => write!(f, "No miner address to reward configured"),
+            ChainError::UnexpectedMessage => write!(f, "Unexpected message from peer"),
+            ChainError::Encryption => write!(f, "Could not encrypt wallets"),
+            ChainError::Decryption => {
+                write!(
+                    f,
+                    "Could not decrypt wallets, wrong passphrase or corrupted file"
+                )
+            }
+            ChainError::RewindPastGenesis { height } => {
+                write!(f, "Can not rewind past genesis from height {height}")
+            }
+            ChainError::InvalidMnemonic => write!(f, "Invalid mnemonic phrase"),
+            ChainError::InvalidWif { reason } => write!(f, "Invalid WIF private key: {reason}"),
+            ChainError::MempoolFull => write!(f, "Mempool is full, transaction fee is too low"),
+            ChainError::InvalidReward {
+                height,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Coinbase at height {height} pays {actual}, expected {expected}"
+            ),
+            ChainError::UnexpectedCoinbase { txid } => {
+                write!(
+                    f,
+                    "Coinbase transaction {} can only start a block",
+                    hex::encode(txid)
+                )
+            }
+            ChainError::AmountOverflow => write!(f, "Amount overflows u64"),
+            ChainError::CoinbaseDataTooLong { len } => write!(
+                f,
+                "Coinbase data is {len} bytes, at most {} allowed",
+                Transaction::MAX_COINBASE_DATA
+            ),
+            ChainError::InvalidPrivateKey { reason } => write!(f, "Invalid private key: {reason}"),
+            ChainError::ImmatureCoinbase { txid } => {
+                write!(f, "Coinbase {} is not mature yet", hex::encode(txid))
+            }
+            ChainError::BlockTooLarge { height, size, max } => write!(
+                f,
+                "Block at height {height} is {size} bytes, at most {max} allowed"
+            ),
+            ChainError::SupplyMismatch { expected, actual } => write!(
+                f,
+                "Unspent outputs hold {actual}, block rewards minus burned value are {expected}"
+            ),
+            ChainError::MessageTooLarge { len, max } => {
+                write!(f, "Peer message is {len} bytes, at most {max} allowed")
+            }
+            ChainError::InvTooLong { len, max } => {
+                write!(f, "Peer announced {len} blocks, at most {max} allowed")
+            }
+            ChainError::MissingPrevOutput { txid, vout } => match vout {
+                Some(vout) => write!(f, "Transaction {} has no output {vout}", hex::encode(txid)),
+                None => write!(f, "Input spending {} names no output", hex::encode(txid)),
+            },
+            ChainError::RequestTooLarge { max } => {
+                write!(f, "RPC request is longer than {max} bytes")
+            }
+            ChainError::Block(e) => write!(f, "Invalid block: {e}"),
+            ChainError::UnauthenticatedPeer => {
+                write!(f, "Peer is not authenticated, refusing its blocks")
+            }
+            ChainError::MissingChainWork { height } => {
+                write!(f, "No chain work recorded below block at height {height}")
+            }
+            ChainError::UnsealedBlock { height } => {
+                write!(
+                    f,
+                    "Block at height {height} was never sealed, it has no hash"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+/// Why `Block::validate` rejected a block, `height` is where the block belongs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    /// `previous_block_hash` is not the hash of the parent
+    PreviousHashMismatch { height: u64 },
+    /// Block past genesis has no `previous_block_hash`
+    MissingPreviousHash { height: u64 },
+    /// Genesis names a previous block
+    UnexpectedPreviousHash,
+    /// Stored height is not one above the parent's
+    HeightMismatch { expected: u64, actual: u64 },
+    /// Transactions do not hash to the merkle root
+    MerkleRootMismatch { height: u64 },
+    /// Seal is not valid under the chain's `Consensus`
+    InvalidSeal { height: u64 },
+    /// Timestamp is older than the parent's
+    TimestampBeforeParent { height: u64 },
+    /// Block holds `count` coinbases instead of exactly one
+    CoinbaseCount { height: u64, count: usize },
+    /// The only coinbase is not the first transaction
+    CoinbaseNotFirst { height: u64 },
+    /// Coinbase data is longer than `Transaction::MAX_COINBASE_DATA`
+    CoinbaseDataTooLong { height: u64, len: usize },
+    /// Coinbase commits to another height, `None` when to none
+    CoinbaseHeightMismatch { height: u64, actual: Option<u64> },
+}
+
+impl Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockError::PreviousHashMismatch { height } => write!(
+                f,
+                "Block at height {height} does not link to the hash of its parent"
+            ),
+            BlockError::MissingPreviousHash { height } => {
+                write!(f, "Block at height {height} has no previous block")
+            }
+            BlockError::UnexpectedPreviousHash => write!(f, "Genesis names a previous block"),
+            BlockError::HeightMismatch { expected, actual } => {
+                write!(f, "Block at height {expected} claims height {actual}")
+            }
+            BlockError::MerkleRootMismatch { height } => {
+                write!(f, "Invalid merkle root at height {height}")
+            }
+            BlockError::InvalidSeal { height } => {
+                write!(f, "Invalid block seal at height {height}")
+            }
+            BlockError::TimestampBeforeParent { height } => {
+                write!(f, "Block at height {height} is older than its parent")
+            }
+            BlockError::CoinbaseCount { height, count } => write!(
+                f,
+                "Block at height {height} has {count} coinbases, exactly one allowed"
+            ),
+            BlockError::CoinbaseNotFirst { height } => {
+                write!(
+                    f,
+                    "Coinbase of block at height {height} is not its first transaction"
+                )
+            }
+            BlockError::CoinbaseDataTooLong { height, len } => write!(
+                f,
+                "Coinbase data at height {height} is {len} bytes, at most {} allowed",
+                Transaction::MAX_COINBASE_DATA
+            ),
+            BlockError::CoinbaseHeightMismatch { height, actual } => match actual {
+                Some(actual) => write!(
+                    f,
+                    "Coinbase of block at height {height} commits to height {actual}"
+                ),
+                None => write!(
+                    f,
+                    "Coinbase of block at height {height} commits to no height"
+                ),
+            },
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+impl From<BlockError> for ChainError {
+    fn from(e: BlockError) -> Self {
+        ChainError::Block(e)
+    }
+}
+
+impl From<TransactionError<ChainError>> for ChainError {
+    fn from(e: TransactionError<ChainError>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => ChainError::Db(e),
+        }
+    }
+}
+
+/// Aborts a sled transaction with `e`, which the transaction then fails with
+pub(crate) fn abort(e: impl Into<ChainError>) -> ConflictableTransactionError<ChainError> {
+    ConflictableTransactionError::Abort(e.into())
+}
+
+impl From<sled::Error> for ChainError {
+    fn from(e: sled::Error) -> Self {
+        ChainError::Db(e)
+    }
+}
+
+impl From<bincode::Error> for ChainError {
+    fn from(e: bincode::Error) -> Self {
+        ChainError::Serialize(e)
+    }
+}
+
+impl From<rayon::ThreadPoolBuildError> for ChainError {
+    fn from(e: rayon::ThreadPoolBuildError) -> Self {
+        ChainError::ThreadPool(e)
+    }
+}
+
+impl From<hex::FromHexError> for ChainError {
+    fn from(e: hex::FromHexError) -> Self {
+        ChainError::Hex(e)
+    }
+}
+
+impl From<p256::ecdsa::Error> for ChainError {
+    fn from(e: p256::ecdsa::Error) -> Self {
+        ChainError::Key(e)
+    }
+}
+
+impl From<std::io::Error> for ChainError {
+    fn from(e: std::io::Error) -> Self {
+        ChainError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ChainError {
+    fn from(e: serde_json::Error) -> Self {
+        ChainError::Json(e)
+    }
+}
@@ -0,0 +1,79 @@
+use std::{sync::atomic::AtomicBool, time::SystemTime};
+
+use crate::{
+    block::Block,
+    error::ChainError,
+    hasher::Sha256Hasher,
+    pow::ProofOfWork,
+    transaction::{ScriptType, TXOutput, Transaction},
+    Blockchainable,
+};
+
+/// Everything the genesis block is made of, equal configs give byte identical genesis blocks
+#[derive(Clone, Debug)]
+pub struct GenesisConfig {
+    pub coinbase_message: String,
+    pub timestamp: SystemTime,
+    pub reward_address: String,
+    /// Mixed into every signature so transactions can't be replayed on another chain
+    pub chain_id: u32,
+}
+
+impl GenesisConfig {
+    pub const SATOSHI_MESSAGE: &'static str =
+        "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+    pub const DEFAULT_CHAIN_ID: u32 = 0;
+
+    /// Satoshi's coinbase message stamped with current time
+    pub fn new(reward_address: &str) -> Self {
+        GenesisConfig {
+            coinbase_message: Self::SATOSHI_MESSAGE.to_string(),
+            timestamp: SystemTime::now(),
+            reward_address: reward_address.to_string(),
+            chain_id: Self::DEFAULT_CHAIN_ID,
+        }
+    }
+
+    /// Chain id `genesis` was made with, carried by an unspendable output of its coinbase.
+    /// `DEFAULT_CHAIN_ID` for genesis blocks made before chain ids existed
+    pub fn chain_id_of<T>(genesis: &Block<T>) -> u32 {
+        genesis
+            .transactions
+            .first()
+            .into_iter()
+            .flat_map(|coinbase| &coinbase.vout)
+            .find_map(|out| match &out.script {
+                ScriptType::OpReturn(data) => Some(u32::from_be_bytes(data[..].try_into().ok()?)),
+                ScriptType::P2PKH(_) => None,
+            })
+            .unwrap_or(Self::DEFAULT_CHAIN_ID)
+    }
+
+    /// Mines the genesis block, always settling on the lowest valid nonce
+    pub fn block<T>(&self, difficulty_bits: u64) -> Result<Block<T>, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let mut coinbase = Transaction::new_coinbase_tx(
+            &self.reward_address,
+            &self.coinbase_message,
+            Transaction::SUBSIDY,
+            0,
+        )?;
+        coinbase
+            .vout
+            .push(TXOutput::op_return(&self.chain_id.to_be_bytes()));
+        coinbase.set_id_with(&Sha256Hasher);
+        let mut block =
+            Block::template(vec![coinbase], T::genesis_data(), None, 0, difficulty_bits);
+        block.timestamp = self.timestamp;
+
+        // Parallel search may return any valid nonce, other nodes must find this exact one
+        let (nonce, hash) = ProofOfWork::new(&block)
+            .run_range(0..u64::MAX, &AtomicBool::new(false))
+            .ok_or(ChainError::NonceExhausted)?;
+        block.nonce = Some(nonce);
+        block.hash = Some(hash);
+        Ok(block)
+    }
+}
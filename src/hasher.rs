@@ -0,0 +1,37 @@
+use sha2::{Digest, Sha256};
+
+/// Hash function used for proof of work and transaction ids
+pub trait Hasher: Send + Sync {
+    fn hash(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Length of produced digests in bits
+    fn digest_bits(&self) -> u64;
+}
+
+/// SHA-256, the default every chain is built with
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha256Hasher;
+
+impl Hasher for Sha256Hasher {
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        Sha256::digest(data).to_vec()
+    }
+
+    fn digest_bits(&self) -> u64 {
+        256
+    }
+}
+
+/// BLAKE3, a faster alternative with digests as long as SHA-256's
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Blake3Hasher;
+
+impl Hasher for Blake3Hasher {
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        blake3::hash(data).as_bytes().to_vec()
+    }
+
+    fn digest_bits(&self) -> u64 {
+        blake3::OUT_LEN as u64 * 8
+    }
+}
@@ -0,0 +1,91 @@
+//! `#[serde(with = "...")]` helpers writing byte fields as hex strings in human readable
+//! formats like JSON, while bincode keeps storing them as raw bytes
+
+use std::fmt;
+
+use serde::{
+    de::{Error, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_bytes::ByteBuf;
+
+pub fn serialize<S: Serializer>(bytes: &ByteBuf, serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&hex::encode(bytes))
+    } else {
+        bytes.serialize(serializer)
+    }
+}
+
+/// Accepts byte arrays too, which is how JSON dumps made before hex encoding store bytes
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ByteBuf, D::Error> {
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_any(HexVisitor)
+    } else {
+        ByteBuf::deserialize(deserializer)
+    }
+}
+
+struct HexVisitor;
+
+impl<'de> Visitor<'de> for HexVisitor {
+    type Value = ByteBuf;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a hex string or a byte array")
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<ByteBuf, E> {
+        hex::decode(v).map(ByteBuf::from).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<ByteBuf, E> {
+        Ok(ByteBuf::from(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ByteBuf, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(ByteBuf::from(bytes))
+    }
+}
+
+/// Same for optional byte fields, `None` stays `null`
+pub mod option {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde_bytes::ByteBuf;
+
+    struct Hex<'a>(&'a ByteBuf);
+
+    impl Serialize for Hex<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            super::serialize(self.0, serializer)
+        }
+    }
+
+    struct HexBuf(ByteBuf);
+
+    impl<'de> Deserialize<'de> for HexBuf {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            super::deserialize(deserializer).map(HexBuf)
+        }
+    }
+
+    pub fn serialize<S: Serializer>(
+        bytes: &Option<ByteBuf>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_some(&Hex(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<ByteBuf>, D::Error> {
+        Ok(Option::<HexBuf>::deserialize(deserializer)?.map(|hex| hex.0))
+    }
+}
@@ -4,10 +4,42 @@ use serde::{de::DeserializeOwned, Serialize};
 
 pub mod block;
 pub mod blockchain;
+pub mod checkpoint;
+pub mod consensus;
+pub mod data_dir;
+pub mod error;
+pub mod genesis;
+pub mod hasher;
+pub mod hex_bytes;
+pub mod mempool;
+pub mod merkle;
+pub mod net;
+pub mod peers;
 pub mod pow;
+pub mod rpc;
 pub mod transaction;
+pub mod utxo_set;
 pub mod wallet;
 
-pub trait Blockchainable: Serialize + DeserializeOwned + Display {
+/// Application data every block carries, `Send + Sync` so blocks can be mined in parallel
+pub trait Blockchainable: Serialize + DeserializeOwned + Display + Send + Sync {
+    /// Data of the genesis block
     fn genesis_data() -> Self;
+
+    /// Data of blocks mined without any given, `genesis_data` unless overridden
+    fn default_data() -> Self {
+        Self::genesis_data()
+    }
+}
+
+/// Block data of chains built by unit tests
+#[cfg(test)]
+impl Blockchainable for String {
+    fn genesis_data() -> Self {
+        "Genesis".to_string()
+    }
+
+    fn default_data() -> Self {
+        String::new()
+    }
 }
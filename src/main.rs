@@ -1,13 +1,31 @@
-use std::fmt::Display;
+use std::{
+    error::Error,
+    fmt::Display,
+    fs::File,
+    io::{BufReader, BufWriter},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
 
 use clap::Parser;
 use rust_chain::{
     blockchain::Blockchain,
-    wallet::{Wallet, Wallets},
+    data_dir,
+    genesis::GenesisConfig,
+    hasher::{Hasher, Sha256Hasher},
+    net::Node,
+    pow::ProofOfWork,
+    transaction::Transaction,
+    wallet::{Address, Wallet, Wallets},
     Blockchainable,
 };
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
+use tracing::Level;
+use tracing_subscriber::{filter::Targets, fmt, prelude::*};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Data {
@@ -20,6 +38,12 @@ impl Blockchainable for Data {
             field: "GENESIS DATA".to_string(),
         }
     }
+
+    fn default_data() -> Self {
+        Data {
+            field: String::new(),
+        }
+    }
 }
 
 impl Display for Data {
@@ -35,6 +59,70 @@ impl Display for Data {
 struct Args {
     #[clap(flatten)]
     group: ArgGroup,
+
+    /// Fee paid by a transaction made with --send, --send-many or --anchor
+    #[arg(long, default_value_t = 0)]
+    fee: u64,
+
+    /// Mine pending transactions right after --send, --send-many or --anchor, rewarding given
+    /// address
+    #[arg(long, value_name = "ADDRESS")]
+    miner: Option<String>,
+
+    /// Text carried by blocks made by --mine or --miner, empty by default
+    #[arg(long, value_name = "TEXT", default_value = "")]
+    block_data: String,
+
+    /// Store the public key of a wallet made by --create-wallet compressed, which gives it a
+    /// different address than the same key uncompressed
+    #[arg(long)]
+    compressed: bool,
+
+    /// Make --print list blocks from given height upwards instead of every block newest first
+    #[arg(long, value_name = "N")]
+    from_height: Option<u64>,
+
+    /// Make --print list at most n blocks, from --from-height or genesis upwards
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Address --watch serves peers on, blocks they send are what it prints
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:7878")]
+    listen: String,
+
+    /// Hex sec1 public key of a peer --watch accepts blocks from, may be repeated. Other peers
+    /// may only fetch blocks
+    #[arg(long, value_name = "KEY")]
+    trust_peer: Vec<String>,
+
+    /// Make --mine refuse to mine a block without pending transactions
+    #[arg(long)]
+    require_txs: bool,
+
+    /// Make --send only print the transaction it would make, nothing is signed, queued or mined
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Leading zero bits genesis made by --create-blockchain is mined at, later blocks follow it
+    #[arg(long, value_name = "BITS", default_value_t = ProofOfWork::<Data>::TARGET_BITS)]
+    difficulty: u64,
+
+    /// Chain id genesis made by --create-blockchain binds every signature to
+    #[arg(long, value_name = "ID", default_value_t = GenesisConfig::DEFAULT_CHAIN_ID)]
+    chain_id: u32,
+
+    /// Take every address argument as a hex pub key hash and use the address encoding it
+    #[arg(long)]
+    hex_hash: bool,
+
+    /// Directory chain and wallets are kept in, wins over RUSTCHAIN_DATA_DIR and the platform
+    /// data directory
+    #[arg(long, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Most verbose log level printed to stderr (error, warn, info, debug, trace)
+    #[arg(long, default_value = "warn")]
+    log_level: Level,
 }
 
 #[derive(Debug, clap::Args)]
@@ -48,6 +136,10 @@ struct ArgGroup {
     #[arg(short, long)]
     print: bool,
 
+    /// Print a single block with given hex hash
+    #[arg(long, value_name = "HASH")]
+    dump_block: Option<String>,
+
     /// Remove all blocks in blockchain
     #[arg(short, long)]
     remove_blocks: bool,
@@ -60,70 +152,484 @@ struct ArgGroup {
     #[arg(short, long, num_args = 3)]
     send: Option<Vec<String>>,
 
+    /// Send coins from an account to several others in one transaction (from, to:value...)
+    #[arg(long, num_args = 2.., value_names = ["FROM", "TO:VALUE"])]
+    send_many: Option<Vec<String>>,
+
+    /// Embed hex data in chain in an unspendable output paid by an account (from, hex)
+    #[arg(long, num_args = 2, value_names = ["FROM", "HEX"])]
+    anchor: Option<Vec<String>>,
+
+    /// Mine pending transactions into a new block rewarding given address
+    #[arg(long, value_name = "ADDRESS")]
+    mine: Option<String>,
+
+    /// Sync from known peers, then print every new block and balance changes of given address
+    /// until Ctrl-C, serving peers on --listen meanwhile
+    #[arg(long, value_name = "ADDRESS")]
+    watch: Option<String>,
+
     /// Get an valid bitcoin address
     #[arg(short, long)]
     address: bool,
 
     /// Create a wallet and save to file
-    #[arg(short, long)]
+    #[arg(long)]
     create_wallet: bool,
 
+    /// Forget the wallet of given address, its funds can no longer be spent
+    #[arg(long, value_name = "ADDRESS")]
+    delete_wallet: Option<String>,
+
     /// Prints all wallets fetched from file
-    #[arg(short, long)]
+    #[arg(long)]
     print_wallets: bool,
+
+    /// Print UTXO set size and value as CSV, sampled every n blocks
+    #[arg(long, value_name = "N")]
+    utxo_growth: Option<u64>,
+
+    /// Check hash links and proof of work of every block
+    #[arg(long)]
+    verify: bool,
+
+    /// Wipe the UTXO set and rebuild it from every block, to recover from a set that no longer
+    /// matches the blocks
+    #[arg(long)]
+    rebuild_utxo: bool,
+
+    /// Print block and transaction counts, supply, difficulty and tip of the chain
+    #[arg(long)]
+    stats: bool,
+
+    /// Suggest a fee per 1000 bytes for a transaction to be mined within n blocks
+    #[arg(long, value_name = "N")]
+    estimate_fee: Option<usize>,
+
+    /// Print fee rates paid in the mempool and blocks each of them waits to be mined
+    #[arg(long)]
+    fee_market: bool,
+
+    /// List unspent outputs locked to given address
+    #[arg(long, value_name = "ADDRESS")]
+    list_utxos: Option<String>,
+
+    /// Export all blocks to a JSON file
+    #[arg(long, value_name = "FILE")]
+    export: Option<PathBuf>,
+
+    /// Replace blockchain with blocks from a JSON file made by --export
+    #[arg(long, value_name = "FILE")]
+    import: Option<PathBuf>,
 }
 
 fn main() {
     let args = Args::parse();
+    // Only our own events, sled logs a lot at debug level
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .with(Targets::new().with_target("rust_chain", args.log_level))
+        .init();
+
+    if let Err(e) = run(args) {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn run(mut args: Args) -> Result<(), Box<dyn Error>> {
+    if args.hex_hash {
+        hex_hashes_to_addresses(&mut args)?;
+    }
+    let dir = data_dir::resolve(args.data_dir.as_deref())?;
+    let db_file = dir.join(Blockchain::<Data>::DB_FILE);
+    let wallets_file = dir.join(Wallets::WALLETS_FILE);
 
     if args.group.print {
-        // TODO: Make address optional
-        let blockchain = Blockchain::<Data>::new(&"".to_string());
-        for block in blockchain {
-            println!("{}", block);
+        let blockchain = existing_chain(&dir)?;
+        let blocks: Box<dyn Iterator<Item = _>> =
+            if args.from_height.is_some() || args.limit.is_some() {
+                let from = args.from_height.unwrap_or(0);
+                let limit = args.limit.unwrap_or(usize::MAX);
+                Box::new(blockchain.blocks_range(from, limit).into_iter())
+            } else {
+                Box::new(blockchain.iter())
+            };
+        for block in blocks {
+            println!(
+                "{}",
+                block.describe_with(|tx| blockchain.describe_transaction(tx))
+            );
+        }
+    }
+
+    if let Some(hash) = args.group.dump_block {
+        let bytes = hex::decode(&hash).map_err(|_| format!("Provided hash is not hex: {hash}"))?;
+        let expected = (Sha256Hasher.digest_bits() / 8) as usize;
+        if bytes.len() != expected {
+            return Err(format!(
+                "Provided hash is {} bytes, expected {expected}: {hash}",
+                bytes.len()
+            )
+            .into());
+        }
+        let blockchain = existing_chain(&dir)?;
+        match blockchain.get_block(&ByteBuf::from(bytes)) {
+            Some(block) => println!("{}", block),
+            None => return Err(format!("Block not found: {hash}").into()),
         }
     }
 
     if args.group.remove_blocks {
-        let blockchain = Blockchain::<Data>::new(&"".to_string());
-        blockchain.remove_blocks();
+        let blockchain = existing_chain(&dir)?;
+        blockchain.remove_blocks()?;
+        blockchain.flush()?;
     }
 
     if let Some(addr) = args.group.create_blockchain {
-        Blockchain::<Data>::new(&addr);
+        let mut config = GenesisConfig::new(&addr);
+        config.chain_id = args.chain_id;
+        Blockchain::<Data>::open_with_genesis(&db_file, &config, args.difficulty)?.flush()?;
     }
 
     if let Some(addr) = args.group.balance {
-        let mut blockchain = Blockchain::<Data>::new(&"".to_string());
-        let balance = blockchain.balance_at(&ByteBuf::from(addr.clone()));
-        println!("Balance at {}: {}", addr, balance);
+        let addr = parse_address(&addr)?;
+        let blockchain = existing_chain(&dir)?;
+        let (balance, utxos) = blockchain.balance_detail(&addr)?;
+        println!(
+            "Balance at {}: {} ({} unspent outputs)",
+            addr, balance, utxos
+        );
+        let (_, immature) = blockchain.mature_balance(&addr)?;
+        if immature > 0 {
+            println!("{immature} of it is immature coinbase reward");
+        }
     }
 
     if let Some(v) = args.group.send {
-        let mut blockchain = Blockchain::<Data>::new(&"".to_string());
-        blockchain.send(
-            &v[0],
-            &v[1],
-            v[2].parse::<u64>()
-                .expect("Provided value is not a number!"),
+        let from = parse_address(&v[0])?;
+        let to = parse_address(&v[1])?;
+        let value = v[2]
+            .parse::<u64>()
+            .map_err(|_| format!("Provided value is not a number: {}", v[2]))?;
+        if args.dry_run {
+            let blockchain = existing_chain_read_only(&dir)?;
+            let tx = Transaction::new_tx(&from, &to, value, args.fee, &blockchain)?;
+            // `new_tx` puts the payment first, anything after it is change
+            let change: u64 = tx.vout[1..].iter().map(|out| out.value).sum();
+            println!("Dry run, nothing was sent");
+            println!("{}", blockchain.describe_transaction(&tx));
+            println!("Change back to {from}: {change}");
+        } else {
+            let mut blockchain = existing_chain(&dir)?;
+            blockchain.send(&from, &to, value, args.fee)?;
+            println!("Transaction added to mempool");
+            mine_with(&mut blockchain, args.miner.clone(), &args.block_data)?;
+        }
+    }
+
+    if let Some(v) = args.group.send_many {
+        let from = parse_address(&v[0])?;
+        let mut blockchain = existing_chain(&dir)?;
+        let mut outputs = Vec::new();
+        for pair in &v[1..] {
+            let (to, value) = pair
+                .split_once(':')
+                .ok_or_else(|| format!("Expected address:value, got: {pair}"))?;
+            let value = value
+                .parse::<u64>()
+                .map_err(|_| format!("Provided value is not a number: {value}"))?;
+            outputs.push((to.to_string(), value));
+        }
+        blockchain.send_many(&from, &outputs, args.fee)?;
+        println!(
+            "Transaction to {} recipients added to mempool",
+            outputs.len()
         );
+        mine_with(&mut blockchain, args.miner.clone(), &args.block_data)?;
     }
 
-    if args.group.address {
-        let address = Wallet::new().address();
+    if let Some(v) = args.group.anchor {
+        let mut blockchain = existing_chain(&dir)?;
+        let data = hex::decode(&v[1]).map_err(|_| format!("Provided data is not hex: {}", v[1]))?;
+        let txid = blockchain.anchor(&v[0], &data, args.fee)?;
+        println!("Anchor transaction {} added to mempool", hex::encode(txid));
+        mine_with(&mut blockchain, args.miner.clone(), &args.block_data)?;
+    }
+
+    if let Some(miner) = args.group.mine {
+        let mut blockchain = existing_chain(&dir)?;
+        if args.require_txs && blockchain.mempool()?.is_empty() {
+            return Err("Mempool is empty, nothing to mine".into());
+        }
+        blockchain.miner_address = Some(miner);
+        blockchain.mine_block_with_data(Data {
+            field: args.block_data.clone(),
+        })?;
+        let block = blockchain
+            .tip_block()
+            .ok_or("Mined block is not in the chain")?;
+        let reward: u64 = block.transactions[0].vout.iter().map(|out| out.value).sum();
         println!(
-            "{}",
-            std::str::from_utf8(&address).expect("Could not create string address!")
+            "Mined block {} at height {} with {} transactions, {} in fees",
+            hex::encode(block.hash.clone().unwrap_or_default()),
+            block.height,
+            block.transactions.len() - 1,
+            reward - blockchain.block_reward(block.height)
         );
     }
 
+    if let Some(addr) = args.group.watch {
+        let addr = parse_address(&addr)?;
+        let blockchain = existing_chain(&dir)?;
+        let mut watcher = blockchain.watch_blocks()?;
+        let mut balance = blockchain.balance_at(&addr)?;
+        let trusted = args
+            .trust_peer
+            .iter()
+            .map(|key| hex::decode(key).map(ByteBuf::from))
+            .collect::<Result<Vec<_>, _>>()?;
+        let node = Node::new(blockchain).with_trusted_peers(trusted);
+        let downloaded = node.reconnect()?;
+        if downloaded > 0 {
+            println!("Downloaded {downloaded} blocks from known peers");
+        }
+        let listener = TcpListener::bind(&args.listen)?;
+        println!(
+            "Watching {addr} holding {balance}, serving peers on {} with key {}",
+            listener.local_addr()?,
+            hex::encode(node.identity().public_key())
+        );
+        let server = node.clone();
+        thread::spawn(move || server.serve(listener));
+
+        stop_on_ctrl_c();
+        while !INTERRUPTED.load(Ordering::Relaxed) {
+            let Some(block) = watcher.next_timeout(WATCH_TICK) else {
+                continue;
+            };
+            println!(
+                "Block {} at height {} with {} transactions",
+                hex::encode(block.hash.unwrap_or_default()),
+                block.height,
+                block.transactions.len()
+            );
+            let new_balance = node.blockchain().balance_at(&addr)?;
+            if new_balance != balance {
+                println!("Balance of {addr}: {balance} -> {new_balance}");
+                balance = new_balance;
+            }
+        }
+        node.blockchain().flush()?;
+    }
+
+    if args.group.address {
+        let address = Wallet::new().address();
+        println!("{}", std::str::from_utf8(&address)?);
+    }
+
     if args.group.create_wallet {
-        let wallet = Wallet::new();
-        Wallets::save_wallet(&wallet);
+        let wallet = if args.compressed {
+            Wallet::new_compressed()
+        } else {
+            Wallet::new()
+        };
+        Wallets::insert(&wallets_file, &wallet)?;
+    }
+
+    if let Some(addr) = args.group.delete_wallet {
+        let addr = parse_address(&addr)?;
+        let address = ByteBuf::from(addr.as_str());
+        if Wallets::open(&wallets_file)?.get(&address).is_none() {
+            return Err(format!("No wallet with address {addr}").into());
+        }
+        if let Some(blockchain) = Blockchain::<Data>::open_existing(&db_file)? {
+            let balance = blockchain.balance_at(&addr)?;
+            if balance > 0 {
+                eprintln!("Warning: {addr} holds {balance}, those funds can no longer be spent");
+            }
+        }
+        Wallets::remove(&wallets_file, &address)?;
+        println!("Removed wallet {addr}");
     }
 
     if args.group.print_wallets {
-        let wallets = Wallets::fetch_wallets();
+        let wallets = Wallets::open(&wallets_file)?;
         println!("{}", wallets)
     }
+
+    if let Some(sample_every) = args.group.utxo_growth {
+        let blockchain = existing_chain(&dir)?;
+        println!("height,utxo_count,total_value");
+        for (height, count, value) in blockchain.utxo_growth_history(sample_every) {
+            println!("{},{},{}", height, count, value);
+        }
+    }
+
+    if args.group.verify {
+        let blockchain = existing_chain(&dir)?;
+        blockchain.verify_chain()?;
+        println!("Blockchain is valid");
+    }
+
+    if args.group.rebuild_utxo {
+        // Opening the db locks it, no other process can write while the set is rebuilt
+        let blockchain = existing_chain(&dir)?;
+        let count = blockchain.utxo_set()?.reindex(&blockchain)?;
+        blockchain.flush()?;
+        println!("Rebuilt UTXO set with {count} unspent outputs");
+    }
+
+    if args.group.stats {
+        let blockchain = existing_chain(&dir)?;
+        println!("{}", blockchain.stats()?);
+    }
+
+    if let Some(target_blocks) = args.group.estimate_fee {
+        let blockchain = existing_chain(&dir)?;
+        println!(
+            "Estimated fee: {} per 1000 bytes",
+            blockchain.estimate_fee(target_blocks)
+        );
+    }
+
+    if args.group.fee_market {
+        let blockchain = existing_chain(&dir)?;
+        println!("{}", blockchain.fee_market_stats());
+    }
+
+    if let Some(addr) = args.group.list_utxos {
+        let addr = parse_address(&addr)?;
+        let blockchain = existing_chain(&dir)?;
+        let utxos = blockchain.list_utxos(&addr)?;
+        if utxos.is_empty() {
+            println!("{addr} has no unspent outputs");
+        } else {
+            println!("{:<64}  {:>5}  {:>10}", "TXID", "INDEX", "VALUE");
+            for (txid, idx, value) in utxos {
+                println!("{:<64}  {:>5}  {:>10}", hex::encode(txid), idx, value);
+            }
+        }
+    }
+
+    if let Some(path) = args.group.export {
+        let blockchain = existing_chain(&dir)?;
+        blockchain.export_json(BufWriter::new(File::create(path)?))?;
+    }
+
+    if let Some(path) = args.group.import {
+        let reader = BufReader::new(File::open(path)?);
+        let blockchain = Blockchain::<Data>::import_json(reader, &db_file)?;
+        blockchain.flush()?;
+        println!("Imported {} blocks", blockchain.iter().count());
+    }
+
+    Ok(())
+}
+
+/// Set once Ctrl-C is pressed while --watch runs
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// How often --watch checks for Ctrl-C while no block arrives
+const WATCH_TICK: Duration = Duration::from_millis(200);
+
+/// Makes Ctrl-C set `INTERRUPTED` instead of killing the process, so it can flush and exit
+#[cfg(unix)]
+fn stop_on_ctrl_c() {
+    extern "C" fn handle(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+    }
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            handle as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn stop_on_ctrl_c() {}
+
+/// Replaces every address argument, given as a hex pub key hash, with the address encoding it
+fn hex_hashes_to_addresses(args: &mut Args) -> Result<(), Box<dyn Error>> {
+    let group = &mut args.group;
+    let mut addresses: Vec<&mut String> = Vec::new();
+    addresses.extend(&mut args.miner);
+    addresses.extend(&mut group.create_blockchain);
+    addresses.extend(&mut group.balance);
+    addresses.extend(&mut group.mine);
+    addresses.extend(&mut group.delete_wallet);
+    addresses.extend(&mut group.list_utxos);
+    addresses.extend(&mut group.watch);
+    if let Some(v) = &mut group.send {
+        addresses.extend(&mut v[..2]);
+    }
+    if let Some(v) = &mut group.anchor {
+        addresses.push(&mut v[0]);
+    }
+
+    for address in addresses {
+        *address = address_of_hex_hash(address)?;
+    }
+    if let Some(v) = &mut group.send_many {
+        v[0] = address_of_hex_hash(&v[0])?;
+        for pair in &mut v[1..] {
+            if let Some((to, value)) = pair.split_once(':') {
+                *pair = format!("{}:{value}", address_of_hex_hash(to)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Base58check address encoding pub key hash `hex_hash`
+fn address_of_hex_hash(hex_hash: &str) -> Result<String, Box<dyn Error>> {
+    let hash = hex::decode(hex_hash)
+        .ok()
+        .filter(|hash| hash.len() == Wallet::PUB_KEY_HASH_LEN)
+        .ok_or_else(|| format!("Not a hex pub key hash: {hex_hash:?}"))?;
+    let address = Wallet::address_from_pub_key_hash(&ByteBuf::from(hash));
+    Ok(String::from_utf8(address.into_vec())?)
+}
+
+/// `address` checked for a valid checksum, an error naming it otherwise
+fn parse_address(address: &str) -> Result<Address, Box<dyn Error>> {
+    address
+        .parse()
+        .map_err(|_| format!("Not a valid address: {address:?}").into())
+}
+
+/// Chain in data directory `dir` signing with its wallets, an error instead of a fresh genesis
+/// when there is none
+fn existing_chain(dir: &Path) -> Result<Blockchain<Data>, Box<dyn Error>> {
+    let mut blockchain = Blockchain::<Data>::open_existing(dir.join(Blockchain::<Data>::DB_FILE))?
+        .ok_or("No blockchain found, run --create-blockchain first")?;
+    blockchain.wallets_file = dir.join(Wallets::WALLETS_FILE);
+    Ok(blockchain)
+}
+
+/// `existing_chain` opened with `Blockchain::open_existing_read_only`, nothing is written to
+/// chain or wallets
+fn existing_chain_read_only(dir: &Path) -> Result<Blockchain<Data>, Box<dyn Error>> {
+    let mut blockchain =
+        Blockchain::<Data>::open_existing_read_only(dir.join(Blockchain::<Data>::DB_FILE))?
+            .ok_or("No blockchain found, run --create-blockchain first")?;
+    blockchain.wallets_file = dir.join(Wallets::WALLETS_FILE);
+    Ok(blockchain)
+}
+
+/// Mines pending transactions into a block carrying `data` rewarding `miner`, if one was given
+fn mine_with(
+    blockchain: &mut Blockchain<Data>,
+    miner: Option<String>,
+    data: &str,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(miner) = miner {
+        blockchain.miner_address = Some(miner);
+        let hash = blockchain.mine_block_with_data(Data {
+            field: data.to_string(),
+        })?;
+        println!("Mined block {}", hex::encode(hash));
+    }
+    Ok(())
 }
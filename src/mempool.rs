@@ -0,0 +1,241 @@
+use std::collections::HashSet;
+
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sled::{Db, Tree};
+
+use crate::{error::ChainError, transaction::Transaction};
+
+/// Pending transaction stored together with the fee it pays
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    fee: u64,
+    tx: Transaction,
+}
+
+impl Entry {
+    fn fee_rate(&self) -> f64 {
+        self.fee as f64 / self.tx.size_bytes().max(1) as f64
+    }
+}
+
+/// Transactions waiting to be mined, kept in its own tree so they survive restarts
+pub struct Mempool {
+    tree: Tree,
+    /// Most transactions kept at once
+    max_count: usize,
+    /// Most serialized transaction bytes kept at once
+    max_bytes: usize,
+}
+
+impl Mempool {
+    pub const MEMPOOL_BUCKET: &'static str = "mempool";
+    pub const MAX_COUNT: usize = 5_000;
+    pub const MAX_BYTES: usize = 5_000_000;
+
+    pub fn open(db: &Db) -> Result<Self, ChainError> {
+        Ok(Mempool {
+            tree: db.open_tree(Self::MEMPOOL_BUCKET)?,
+            max_count: Self::MAX_COUNT,
+            max_bytes: Self::MAX_BYTES,
+        })
+    }
+
+    /// Bounds how many transactions and serialized bytes `add` lets the pool hold
+    pub fn with_limits(mut self, max_count: usize, max_bytes: usize) -> Self {
+        self.max_count = max_count;
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Adds transaction paying `fee` unless it spends an output already claimed by a pending one.
+    /// When the pool is full, pending transactions with the lowest fee per byte are evicted
+    /// to make room, or the new one is rejected with `MempoolFull` if it pays less than them
+    pub fn add(&self, tx: Transaction, fee: u64) -> Result<(), ChainError> {
+        if tx.is_coinbase() {
+            return Err(ChainError::UnexpectedCoinbase { txid: tx.id });
+        }
+        let claimed = self.claimed_outputs()?;
+        for vin in &tx.vin {
+            if let Some(vout) = vin.vout {
+                if claimed.contains(&(vin.txid.clone(), vout)) {
+                    return Err(ChainError::DoubleSpend {
+                        txid: vin.txid.clone(),
+                        vout,
+                    });
+                }
+            }
+        }
+
+        let entry = Entry { fee, tx };
+        let size = entry.tx.size_bytes();
+        if size > self.max_bytes || self.max_count == 0 {
+            return Err(ChainError::MempoolFull);
+        }
+
+        let mut entries = self.entries()?;
+        // Lowest fee per byte last, those go first
+        entries.sort_by(|a, b| b.fee_rate().total_cmp(&a.fee_rate()));
+        let mut count = entries.len();
+        let mut bytes: usize = entries.iter().map(|entry| entry.tx.size_bytes()).sum();
+        let mut evict = Vec::new();
+        while count + 1 > self.max_count || bytes + size > self.max_bytes {
+            let Some(lowest) = entries.pop() else { break };
+            if lowest.fee_rate() >= entry.fee_rate() {
+                return Err(ChainError::MempoolFull);
+            }
+            count -= 1;
+            bytes -= lowest.tx.size_bytes();
+            evict.push(lowest.tx.id);
+        }
+
+        for txid in evict {
+            self.tree.remove(txid)?;
+        }
+        self.tree.insert(&entry.tx.id, serialize(&entry)?)?;
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<(), ChainError> {
+        Ok(self.tree.clear()?)
+    }
+
+    pub fn remove(&self, txid: &ByteBuf) -> Result<Option<Transaction>, ChainError> {
+        match self.tree.remove(txid)? {
+            Some(entry) => Ok(Some(deserialize::<Entry>(&entry)?.tx)),
+            None => Ok(None),
+        }
+    }
+
+    /// Up to `max` pending transactions, highest fee per byte first. They stay in the pool
+    /// until removed
+    pub fn collect(&self, max: usize) -> Result<Vec<Transaction>, ChainError> {
+        let mut entries = self.entries()?;
+        entries.sort_by(|a, b| b.fee_rate().total_cmp(&a.fee_rate()));
+        Ok(entries
+            .into_iter()
+            .take(max)
+            .map(|entry| entry.tx)
+            .collect())
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>, ChainError> {
+        let mut entries = Vec::new();
+        for entry in self.tree.iter() {
+            let (_, entry) = entry?;
+            entries.push(deserialize(&entry)?);
+        }
+        Ok(entries)
+    }
+
+    pub fn contains(&self, txid: &ByteBuf) -> Result<bool, ChainError> {
+        Ok(self.tree.contains_key(txid)?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Serialized size of every pending transaction together
+    pub fn size_bytes(&self) -> Result<usize, ChainError> {
+        Ok(self
+            .entries()?
+            .iter()
+            .map(|entry| entry.tx.size_bytes())
+            .sum())
+    }
+
+    /// `(fee per 1000 bytes, serialized size)` of every pending transaction, highest fee per
+    /// byte first
+    pub fn fee_rates(&self) -> Result<Vec<(u64, usize)>, ChainError> {
+        let mut entries = self.entries()?;
+        entries.sort_by(|a, b| b.fee_rate().total_cmp(&a.fee_rate()));
+        Ok(entries
+            .iter()
+            .map(|entry| {
+                let size = entry.tx.size_bytes();
+                (entry.fee.saturating_mul(1000) / size.max(1) as u64, size)
+            })
+            .collect())
+    }
+
+    /// `(txid, vout)` of every output spent by a pending transaction
+    pub fn claimed_outputs(&self) -> Result<HashSet<(ByteBuf, usize)>, ChainError> {
+        let mut claimed = HashSet::new();
+        for tx in self.collect(usize::MAX)? {
+            for vin in tx.vin {
+                if let Some(vout) = vin.vout {
+                    claimed.insert((vin.txid, vout));
+                }
+            }
+        }
+        Ok(claimed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{TXInput, TXOutput};
+
+    const ADDRESS: &str = "1MBnzAR1oDnakY5fKLWvDeFD87PhPH9q1f";
+
+    /// Unsigned transaction spending a made up output `seed`, all of the same size
+    fn tx(seed: u8) -> Transaction {
+        let mut tx = Transaction {
+            id: ByteBuf::new(),
+            vin: vec![TXInput {
+                txid: ByteBuf::from(vec![seed; 32]),
+                vout: Some(0),
+                signature: ByteBuf::new(),
+                pub_key: ByteBuf::new(),
+            }],
+            vout: vec![TXOutput::new(1, ADDRESS).unwrap()],
+        };
+        tx.id = tx.hash();
+        tx
+    }
+
+    #[test]
+    fn full_mempool_keeps_highest_fee_transactions() -> Result<(), ChainError> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let mempool = Mempool::open(&db)?.with_limits(3, Mempool::MAX_BYTES);
+
+        for fee in 1..=5 {
+            mempool.add(tx(fee), fee as u64)?;
+        }
+
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.size_bytes()?, 3 * tx(0).size_bytes());
+        let kept: Vec<_> = mempool.collect(usize::MAX)?;
+        assert_eq!(
+            kept.iter().map(|tx| &tx.id).collect::<Vec<_>>(),
+            [&tx(5).id, &tx(4).id, &tx(3).id]
+        );
+        assert!(matches!(
+            mempool.add(tx(6), 1),
+            Err(ChainError::MempoolFull)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn byte_limit_evicts_lowest_fee_rate_first() -> Result<(), ChainError> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let size = tx(0).size_bytes();
+        let mempool = Mempool::open(&db)?.with_limits(usize::MAX, 2 * size);
+
+        mempool.add(tx(1), 10)?;
+        mempool.add(tx(2), 5)?;
+        mempool.add(tx(3), 20)?;
+
+        assert!(mempool.contains(&tx(1).id)? && mempool.contains(&tx(3).id)?);
+        assert!(!mempool.contains(&tx(2).id)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use bincode::serialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+use crate::transaction::Transaction;
+
+/// Root of a SHA-256 merkle tree over serialized transactions,
+/// last node of a level is duplicated when the level has odd count
+pub fn merkle_root(transactions: &[Transaction]) -> ByteBuf {
+    let mut level: Vec<Vec<u8>> = transactions
+        .iter()
+        .map(|tx| {
+            Sha256::new()
+                .chain_update(serialize(tx).expect("Serialization error!"))
+                .finalize()
+                .to_vec()
+        })
+        .collect();
+
+    if level.is_empty() {
+        return ByteBuf::from(Sha256::digest([]).to_vec());
+    }
+
+    while level.len() > 1 {
+        if level.len() % 2 == 1 {
+            level.push(level[level.len() - 1].clone());
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| {
+                Sha256::new()
+                    .chain_update(&pair[0])
+                    .chain_update(&pair[1])
+                    .finalize()
+                    .to_vec()
+            })
+            .collect();
+    }
+
+    ByteBuf::from(level.remove(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "1MBnzAR1oDnakY5fKLWvDeFD87PhPH9q1f";
+
+    fn txs(count: u64) -> Vec<Transaction> {
+        (0..count)
+            .map(|height| {
+                Transaction::new_coinbase_tx(ADDRESS, "", Transaction::SUBSIDY, height).unwrap()
+            })
+            .collect()
+    }
+
+    fn leaf(tx: &Transaction) -> Vec<u8> {
+        Sha256::digest(serialize(tx).unwrap()).to_vec()
+    }
+
+    fn node(left: &[u8], right: &[u8]) -> Vec<u8> {
+        Sha256::new()
+            .chain_update(left)
+            .chain_update(right)
+            .finalize()
+            .to_vec()
+    }
+
+    #[test]
+    fn single_transaction_root_is_its_leaf() {
+        let txs = txs(1);
+        assert_eq!(merkle_root(&txs).into_vec(), leaf(&txs[0]));
+    }
+
+    #[test]
+    fn even_count_pairs_every_leaf() {
+        let txs = txs(4);
+        let expected = node(
+            &node(&leaf(&txs[0]), &leaf(&txs[1])),
+            &node(&leaf(&txs[2]), &leaf(&txs[3])),
+        );
+        assert_eq!(merkle_root(&txs).into_vec(), expected);
+    }
+
+    #[test]
+    fn odd_count_duplicates_last_leaf() {
+        let txs = txs(3);
+        let expected = node(
+            &node(&leaf(&txs[0]), &leaf(&txs[1])),
+            &node(&leaf(&txs[2]), &leaf(&txs[2])),
+        );
+        assert_eq!(merkle_root(&txs).into_vec(), expected);
+        assert_ne!(merkle_root(&txs), merkle_root(&txs[..2]));
+    }
+}
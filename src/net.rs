@@ -0,0 +1,755 @@
+use std::{
+    collections::HashSet,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use bincode::{serialize, Options};
+use p256::elliptic_curve::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tracing::{debug, info, warn};
+
+use crate::{
+    block::Block, blockchain::Blockchain, error::ChainError, peers::PeerStore, wallet::Wallet,
+    Blockchainable,
+};
+
+/// What peers exchange, each sent as a big-endian `u32` length followed by bincode bytes
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "T: Blockchainable")]
+pub enum Message<T> {
+    /// Newly mined block to append
+    Block(Block<T>),
+    /// Asks for hashes of blocks from height `from` upwards, answered with an `Inv` of at
+    /// most `MAX_INV_HASHES` of them. An empty `Inv` means there are no more
+    GetBlocks { from: u64 },
+    /// Block hashes, oldest first
+    Inv(Vec<ByteBuf>),
+    /// Asks for a single block, answered with `Block` or `NotFound`
+    GetBlock(ByteBuf),
+    /// Requested block is not stored
+    NotFound(ByteBuf),
+    /// Answer to a `Challenge`, signing its `nonce` with `public_key`. Each side challenges
+    /// the other with a fresh nonce, so a `Version` recorded earlier can not be replayed
+    Version {
+        public_key: ByteBuf,
+        nonce: u64,
+        signature: ByteBuf,
+    },
+    /// Fresh nonce the receiver has to sign back in a `Version`, see `Node::connect`
+    Challenge { nonce: u64 },
+}
+
+/// Largest frame `read_message` accepts, room for a block of `Blockchain::MAX_BLOCK_BYTES`
+pub const MAX_MESSAGE_BYTES: usize = 4_000_000;
+/// Most block hashes an `Inv` may carry
+pub const MAX_INV_HASHES: usize = 50_000;
+/// Signed in a `Version` ahead of its nonce, so the signature means nothing anywhere else
+const VERSION_DOMAIN: &[u8] = b"rust-chain version";
+
+/// Keypair a node signs its `Version` messages with
+#[derive(Clone)]
+pub struct NodeIdentity {
+    wallet: Wallet,
+}
+
+impl NodeIdentity {
+    /// Identity with a new random key
+    pub fn generate() -> Self {
+        NodeIdentity {
+            wallet: Wallet::new(),
+        }
+    }
+
+    pub fn from_wallet(wallet: Wallet) -> Self {
+        NodeIdentity { wallet }
+    }
+
+    /// Sec1 encoded public key peers verify this node's signatures with
+    pub fn public_key(&self) -> &ByteBuf {
+        &self.wallet.public_key
+    }
+}
+
+impl Default for NodeIdentity {
+    fn default() -> Self {
+        Self::generate()
+    }
+}
+
+/// Peer connected to this node, as told by the `Version` it answered with
+#[derive(Clone, Debug)]
+pub struct PeerConnection {
+    pub addr: SocketAddr,
+    pub public_key: ByteBuf,
+    /// Whether the peer signed the nonce this node sent it with `public_key` and that key is
+    /// one of the node's trusted peers
+    pub authenticated: bool,
+}
+
+/// Peer serving one chain over TCP, one request per connection
+pub struct Node<T> {
+    blockchain: Arc<Mutex<Blockchain<T>>>,
+    /// Largest frame accepted from peers connecting to this node
+    max_message_bytes: usize,
+    /// Most hashes one `Inv` answering `GetBlocks` carries
+    max_inv_hashes: usize,
+    identity: NodeIdentity,
+    /// Sec1 encoded keys of peers allowed to send blocks
+    trusted_peers: HashSet<ByteBuf>,
+}
+
+impl<T> Clone for Node<T> {
+    fn clone(&self) -> Self {
+        Node {
+            blockchain: Arc::clone(&self.blockchain),
+            max_message_bytes: self.max_message_bytes,
+            max_inv_hashes: self.max_inv_hashes,
+            identity: self.identity.clone(),
+            trusted_peers: self.trusted_peers.clone(),
+        }
+    }
+}
+
+impl<T> Node<T>
+where
+    T: Blockchainable,
+{
+    /// How long a connected peer may stay silent before it is dropped
+    const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new(blockchain: Blockchain<T>) -> Self {
+        Node {
+            blockchain: Arc::new(Mutex::new(blockchain)),
+            max_message_bytes: MAX_MESSAGE_BYTES,
+            max_inv_hashes: MAX_INV_HASHES,
+            identity: NodeIdentity::generate(),
+            trusted_peers: HashSet::new(),
+        }
+    }
+
+    /// Signs handshakes with `identity` instead of a key generated by `new`
+    pub fn with_identity(mut self, identity: NodeIdentity) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Accepts blocks from peers authenticating with any of `keys`, every other peer may only
+    /// ask for blocks
+    pub fn with_trusted_peers(mut self, keys: impl IntoIterator<Item = ByteBuf>) -> Self {
+        self.trusted_peers.extend(keys);
+        self
+    }
+
+    pub fn identity(&self) -> &NodeIdentity {
+        &self.identity
+    }
+
+    /// Signature of `message` by this node's identity
+    pub fn sign_message(&self, message: &[u8]) -> ByteBuf {
+        self.identity.wallet.sign_data(message)
+    }
+
+    /// Checks `sig` is a signature of `message` made by the peer owning `peer_pubkey`
+    pub fn verify_peer_message(peer_pubkey: &ByteBuf, message: &[u8], sig: &ByteBuf) -> bool {
+        Wallet::verify_data(peer_pubkey, message, sig)
+    }
+
+    /// Bounds the frames peers connecting to this node may send, larger ones are refused
+    /// before anything is allocated for them
+    pub fn with_max_message_bytes(mut self, max_message_bytes: usize) -> Self {
+        self.max_message_bytes = max_message_bytes;
+        self
+    }
+
+    /// Pages `Inv` answers to `GetBlocks` by `max_inv_hashes` hashes, at most `MAX_INV_HASHES`
+    pub fn with_max_inv_hashes(mut self, max_inv_hashes: usize) -> Self {
+        self.max_inv_hashes = max_inv_hashes.clamp(1, MAX_INV_HASHES);
+        self
+    }
+
+    pub fn blockchain(&self) -> MutexGuard<'_, Blockchain<T>> {
+        self.blockchain
+            .lock()
+            .expect("Blockchain lock is poisoned!")
+    }
+
+    /// Peers remembered in the chain db
+    pub fn peers(&self) -> Result<PeerStore, ChainError> {
+        PeerStore::open(&self.blockchain().db)
+    }
+
+    /// Syncs from every known peer, meant to be called on startup before `listen`. Peers that
+    /// cannot be reached are only logged, returns number of blocks downloaded
+    pub fn reconnect(&self) -> Result<usize, ChainError> {
+        let mut downloaded = 0;
+        for (peer, _) in self.peers()?.known_peers()? {
+            match self.sync_from(peer) {
+                Ok(blocks) => downloaded += blocks,
+                Err(e) => warn!(%peer, "Reconnecting failed: {e}"),
+            }
+        }
+        Ok(downloaded)
+    }
+
+    /// Binds `addr` and serves peers forever
+    pub fn listen(&self, addr: impl ToSocketAddrs) -> Result<(), ChainError> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serves peers connecting to an already bound listener, failed requests are only logged
+    pub fn serve(&self, listener: TcpListener) -> Result<(), ChainError> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let peer = stream.peer_addr()?;
+            if let Err(e) = self.handle(stream) {
+                warn!(%peer, "Request failed: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers a single request following the handshake, the connection is dropped on any
+    /// error. Peers that did not authenticate may only ask for blocks
+    fn handle(&self, mut stream: TcpStream) -> Result<(), ChainError> {
+        stream.set_read_timeout(Some(Self::READ_TIMEOUT))?;
+        let peer = self.accept(&mut stream)?;
+        match read_message_with_limit::<T>(&mut stream, self.max_message_bytes)? {
+            Message::Block(_) if !peer.authenticated => {
+                return Err(ChainError::UnauthenticatedPeer);
+            }
+            Message::Block(block) => {
+                let height = block.height;
+                let hash = block.hash.clone().unwrap_or_default();
+                if self.blockchain().contains_block(&hash) {
+                    debug!(height, "Already have block from peer");
+                    return Ok(());
+                }
+                if self.blockchain().consider_block(block)? {
+                    info!(height, "Accepted block from peer");
+                }
+            }
+            Message::GetBlocks { from } => {
+                let hashes: Vec<ByteBuf> = self
+                    .blockchain()
+                    .blocks_range(from, self.max_inv_hashes)
+                    .into_iter()
+                    .filter_map(|block| block.hash)
+                    .collect();
+                write_message(&mut stream, &Message::<T>::Inv(hashes))?;
+            }
+            Message::GetBlock(hash) => {
+                let reply = match self.blockchain().get_block(&hash) {
+                    Some(block) => Message::Block(block),
+                    None => Message::NotFound(hash),
+                };
+                write_message(&mut stream, &reply)?;
+            }
+            Message::Inv(hashes) => {
+                info!(blocks = hashes.len(), "Peer announced blocks");
+            }
+            Message::NotFound(_) => {}
+            Message::Version { .. } | Message::Challenge { .. } => {
+                return Err(ChainError::UnexpectedMessage);
+            }
+        }
+        Ok(())
+    }
+
+    /// Server side of the handshake: challenges the peer with a fresh nonce, reads the
+    /// `Version` signing it and the peer's own `Challenge`, then answers that one. The peer is
+    /// authenticated only when it signed the nonce with the key of a trusted peer
+    fn accept(&self, stream: &mut TcpStream) -> Result<PeerConnection, ChainError> {
+        let nonce = OsRng.next_u64();
+        write_message(stream, &Message::<T>::Challenge { nonce })?;
+        let Message::Version {
+            public_key,
+            nonce: signed,
+            signature,
+        } = read_message_with_limit::<T>(stream, self.max_message_bytes)?
+        else {
+            return Err(ChainError::UnexpectedMessage);
+        };
+        let Message::Challenge { nonce: challenge } =
+            read_message_with_limit::<T>(stream, self.max_message_bytes)?
+        else {
+            return Err(ChainError::UnexpectedMessage);
+        };
+        write_message(stream, &self.version(challenge))?;
+
+        let peer = stream.peer_addr()?;
+        let signed = signed == nonce
+            && Self::verify_peer_message(&public_key, &version_payload(nonce), &signature);
+        let trusted = self.trusted_peers.contains(&public_key);
+        if !signed {
+            warn!(%peer, "Peer failed to authenticate");
+        } else if !trusted {
+            debug!(%peer, key = hex::encode(&public_key), "Peer key is not trusted");
+        }
+        Ok(PeerConnection {
+            addr: peer,
+            public_key,
+            authenticated: signed && trusted,
+        })
+    }
+
+    /// Connects to the node listening at `peer`, signs the nonce it challenges with and
+    /// challenges it back with a fresh one. Fails with `UnauthenticatedPeer` when the node does
+    /// not sign that nonce with the key it presents. Its key need not be trusted, everything
+    /// fetched from it is validated anyway
+    fn connect(&self, peer: impl ToSocketAddrs) -> Result<TcpStream, ChainError> {
+        let mut stream = TcpStream::connect(peer)?;
+        stream.set_read_timeout(Some(Self::READ_TIMEOUT))?;
+        let Message::Challenge { nonce } = read_message::<T>(&mut stream)? else {
+            return Err(ChainError::UnexpectedMessage);
+        };
+        write_message(&mut stream, &self.version(nonce))?;
+        let challenge = OsRng.next_u64();
+        write_message(&mut stream, &Message::<T>::Challenge { nonce: challenge })?;
+        match read_message::<T>(&mut stream)? {
+            Message::Version {
+                public_key,
+                nonce,
+                signature,
+            } => {
+                let signed = nonce == challenge
+                    && Self::verify_peer_message(
+                        &public_key,
+                        &version_payload(challenge),
+                        &signature,
+                    );
+                if !signed {
+                    return Err(ChainError::UnauthenticatedPeer);
+                }
+                Ok(stream)
+            }
+            _ => Err(ChainError::UnexpectedMessage),
+        }
+    }
+
+    /// `Version` of this node signing `nonce`
+    fn version(&self, nonce: u64) -> Message<T> {
+        Message::Version {
+            public_key: self.identity.public_key().clone(),
+            nonce,
+            signature: self.sign_message(&version_payload(nonce)),
+        }
+    }
+
+    /// Sends `block` to the node listening at `peer`
+    pub fn send_block(&self, peer: impl ToSocketAddrs, block: Block<T>) -> Result<(), ChainError> {
+        let mut stream = self.connect(peer)?;
+        write_message(&mut stream, &Message::Block(block))
+    }
+
+    /// Catches up with the chain of the node at `peer`, downloading blocks this node is missing
+    /// and switching to the peer's chain if it has more work and is valid, see
+    /// `Blockchain::replace_chain`. Nothing changes when any downloaded block is invalid. A peer that answers is remembered in `peers` as seen.
+    /// Returns number of blocks downloaded
+    pub fn sync_from(&self, peer: impl ToSocketAddrs) -> Result<usize, ChainError> {
+        let peer: Vec<_> = peer.to_socket_addrs()?.collect();
+        let remote = self.get_blocks(&peer[..])?;
+        if let Some(addr) = peer.first() {
+            self.peers()?.add_peer(*addr)?;
+        }
+
+        // Only blocks of the current chain are stored, so the ones we have are a shared prefix
+        let common = remote
+            .iter()
+            .take_while(|hash| self.blockchain().contains_block(hash))
+            .count();
+        if common == remote.len() {
+            return Ok(0);
+        }
+        let mut blocks: Vec<_> = self.blockchain().iter_forward().take(common).collect();
+        for hash in &remote[common..] {
+            let block = self.get_block(&peer[..], hash)?;
+            if block.hash.as_ref() != Some(hash) {
+                return Err(ChainError::UnexpectedMessage);
+            }
+            blocks.push(block);
+        }
+
+        let downloaded = remote.len() - common;
+        self.blockchain().replace_chain(blocks)?;
+        Ok(downloaded)
+    }
+
+    /// Fetches block with given hash from the node listening at `peer`
+    pub fn get_block(
+        &self,
+        peer: impl ToSocketAddrs,
+        hash: &ByteBuf,
+    ) -> Result<Block<T>, ChainError> {
+        let mut stream = self.connect(peer)?;
+        write_message(&mut stream, &Message::<T>::GetBlock(hash.clone()))?;
+        match read_message::<T>(&mut stream)? {
+            Message::Block(block) => Ok(block),
+            _ => Err(ChainError::UnexpectedMessage),
+        }
+    }
+
+    /// Asks the node listening at `peer` for hashes of all its blocks, genesis first, one
+    /// `Inv` page per connection until the peer answers with an empty one
+    pub fn get_blocks(&self, peer: impl ToSocketAddrs) -> Result<Vec<ByteBuf>, ChainError> {
+        let peer: Vec<_> = peer.to_socket_addrs()?.collect();
+        let mut hashes = Vec::new();
+        loop {
+            let mut stream = self.connect(&peer[..])?;
+            let from = hashes.len() as u64;
+            write_message(&mut stream, &Message::<T>::GetBlocks { from })?;
+            match read_message::<T>(&mut stream)? {
+                Message::Inv(page) if page.is_empty() => return Ok(hashes),
+                Message::Inv(page) => hashes.extend(page),
+                _ => return Err(ChainError::UnexpectedMessage),
+            }
+        }
+    }
+}
+
+/// Bytes a `Version` signs for `nonce`
+fn version_payload(nonce: u64) -> Vec<u8> {
+    [VERSION_DOMAIN, &nonce.to_be_bytes()].concat()
+}
+
+pub fn write_message<T>(stream: &mut impl Write, message: &Message<T>) -> Result<(), ChainError>
+where
+    T: Blockchainable,
+{
+    write_frame(stream, &serialize(message)?)
+}
+
+/// `read_message_with_limit` bounded by `MAX_MESSAGE_BYTES`
+pub fn read_message<T>(stream: &mut impl Read) -> Result<Message<T>, ChainError>
+where
+    T: Blockchainable,
+{
+    read_message_with_limit(stream, MAX_MESSAGE_BYTES)
+}
+
+/// Reads one frame, failing with `MessageTooLarge` before allocating when its declared
+/// length is over `max_bytes` and with `InvTooLong` for an `Inv` of over `MAX_INV_HASHES`
+pub fn read_message_with_limit<T>(
+    stream: &mut impl Read,
+    max_bytes: usize,
+) -> Result<Message<T>, ChainError>
+where
+    T: Blockchainable,
+{
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_bytes {
+        return Err(ChainError::MessageTooLarge {
+            len,
+            max: max_bytes,
+        });
+    }
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+
+    // Same encoding as `bincode::deserialize`, but length prefixes inside the payload can not
+    // make it allocate more than the payload holds
+    let message = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(len as u64)
+        .deserialize(&payload)?;
+    if let Message::Inv(hashes) = &message {
+        if hashes.len() > MAX_INV_HASHES {
+            return Err(ChainError::InvTooLong {
+                len: hashes.len(),
+                max: MAX_INV_HASHES,
+            });
+        }
+    }
+    Ok(message)
+}
+
+fn write_frame(stream: &mut impl Write, payload: &[u8]) -> Result<(), ChainError> {
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(payload)?;
+    Ok(stream.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, thread};
+
+    use super::*;
+    use crate::genesis::GenesisConfig;
+
+    const ADDRESS: &str = "1MBnzAR1oDnakY5fKLWvDeFD87PhPH9q1f";
+    const BLOCK_TIME: Duration = Duration::from_millis(50);
+
+    /// Chain from `genesis` retargeting every 2 blocks towards `BLOCK_TIME`, with `blocks`
+    /// blocks on top. A `slow` chain waits before its first block, so its difficulty drops at
+    /// the first retarget where a fast one rises
+    fn chain(
+        genesis: &GenesisConfig,
+        slow: bool,
+        blocks: usize,
+    ) -> Result<Blockchain<String>, ChainError> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let mut blockchain = Blockchain::from_db(db, genesis, 8)?;
+        blockchain.retarget_window = 2;
+        blockchain.target_block_time = BLOCK_TIME;
+        blockchain.miner_address = Some(ADDRESS.to_string());
+        if slow {
+            thread::sleep(BLOCK_TIME * 4);
+        }
+        for _ in 0..blocks {
+            blockchain.mine_block()?;
+        }
+        Ok(blockchain)
+    }
+
+    /// Serves `blockchain` on an ephemeral loopback port
+    fn serve(blockchain: Blockchain<String>) -> Result<SocketAddr, ChainError> {
+        serve_node(Node::new(blockchain))
+    }
+
+    fn serve_node(node: Node<String>) -> Result<SocketAddr, ChainError> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || node.serve(listener));
+        Ok(addr)
+    }
+
+    #[test]
+    fn peer_messages_verify_only_with_signer_key() -> Result<(), ChainError> {
+        let node = Node::new(chain(&GenesisConfig::new(ADDRESS), false, 0)?);
+        let other = NodeIdentity::generate();
+        let signature = node.sign_message(b"hello");
+
+        let key = node.identity().public_key();
+        assert!(Node::<String>::verify_peer_message(
+            key, b"hello", &signature
+        ));
+        assert!(!Node::<String>::verify_peer_message(
+            key, b"hellO", &signature
+        ));
+        assert!(!Node::<String>::verify_peer_message(
+            other.public_key(),
+            b"hello",
+            &signature
+        ));
+        Ok(())
+    }
+
+    /// Node serving a chain of genesis only that trusts `peer`, with the address it serves on
+    fn trusting(
+        genesis: &GenesisConfig,
+        peer: &Node<String>,
+    ) -> Result<(Node<String>, SocketAddr), ChainError> {
+        let remote = Node::new(chain(genesis, false, 0)?)
+            .with_trusted_peers([peer.identity().public_key().clone()]);
+        let addr = serve_node(remote.clone())?;
+        Ok((remote, addr))
+    }
+
+    #[test]
+    fn authenticated_peer_sends_blocks() -> Result<(), ChainError> {
+        let genesis = GenesisConfig::new(ADDRESS);
+        let node = Node::new(chain(&genesis, false, 1)?);
+        let (remote, addr) = trusting(&genesis, &node)?;
+        let block = node.blockchain().tip_block().unwrap();
+
+        node.send_block(addr, block)?;
+
+        // The remote answers one connection at a time, so the block is handled by now
+        assert_eq!(node.get_blocks(addr)?.len(), 2);
+        assert_eq!(remote.blockchain().tip, node.blockchain().tip);
+        Ok(())
+    }
+
+    #[test]
+    fn mined_block_reaches_peer_only_when_valid() -> Result<(), ChainError> {
+        let genesis = GenesisConfig::new(ADDRESS);
+        let node = Node::new(chain(&genesis, false, 2)?);
+        let (remote, addr) = trusting(&genesis, &node)?;
+        let first = node.blockchain().get_block_by_height(1).unwrap();
+        let first_hash = first.hash.clone().unwrap();
+        let mut second = node.blockchain().tip_block().unwrap();
+        second.nonce = second.nonce.map(|nonce| nonce.wrapping_add(1));
+
+        node.send_block(addr, first)?;
+        node.send_block(addr, second)?;
+
+        assert_eq!(node.get_blocks(addr)?.len(), 2);
+        assert_eq!(remote.blockchain().tip, first_hash);
+        remote.blockchain().verify_chain()?;
+        Ok(())
+    }
+
+    #[test]
+    fn untrusted_key_can_not_send_blocks() -> Result<(), ChainError> {
+        let genesis = GenesisConfig::new(ADDRESS);
+        let trusted = Node::new(chain(&genesis, false, 0)?);
+        let (remote, addr) = trusting(&genesis, &trusted)?;
+        // Signs every handshake right, with a key the remote does not know
+        let node = Node::new(chain(&genesis, false, 1)?);
+        let block = node.blockchain().tip_block().unwrap();
+
+        node.send_block(addr, block)?;
+
+        assert_eq!(node.get_blocks(addr)?.len(), 1);
+        assert_eq!(remote.blockchain().height(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn unauthenticated_peer_can_not_send_blocks() -> Result<(), ChainError> {
+        let genesis = GenesisConfig::new(ADDRESS);
+        let node = Node::new(chain(&genesis, false, 1)?);
+        let (_, addr) = trusting(&genesis, &node)?;
+        let block = node.blockchain().tip_block().unwrap();
+
+        let mut stream = TcpStream::connect(addr)?;
+        let Message::Challenge { nonce } = read_message::<String>(&mut stream)? else {
+            panic!("Expected a Challenge to open the connection");
+        };
+        // Trusted key signing right, but another nonce, like one recorded from an earlier
+        // connection
+        write_message(&mut stream, &node.version(nonce.wrapping_add(1)))?;
+        write_message(&mut stream, &Message::<String>::Challenge { nonce: 0 })?;
+        read_message::<String>(&mut stream)?;
+        write_message(&mut stream, &Message::Block(block))?;
+
+        assert_eq!(node.get_blocks(addr)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn replayed_version_of_peer_is_refused() -> Result<(), ChainError> {
+        let genesis = GenesisConfig::new(ADDRESS);
+        let node = Node::new(chain(&genesis, false, 0)?);
+        let impostor = Node::new(chain(&genesis, false, 0)?);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        // Answers every challenge with a `Version` signed for nonce 7, as if recorded earlier
+        thread::spawn(move || -> Result<(), ChainError> {
+            let (mut stream, _) = listener.accept()?;
+            write_message(&mut stream, &Message::<String>::Challenge { nonce: 1 })?;
+            read_message::<String>(&mut stream)?;
+            read_message::<String>(&mut stream)?;
+            write_message(&mut stream, &impostor.version(7))
+        });
+
+        assert!(matches!(
+            node.get_blocks(addr),
+            Err(ChainError::UnauthenticatedPeer)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn sync_switches_to_shorter_chain_with_more_work() -> Result<(), ChainError> {
+        let genesis = GenesisConfig::new(ADDRESS);
+        let remote = chain(&genesis, false, 2)?;
+        let remote_tip = remote.tip.clone();
+        let remote_work = remote.total_work();
+        let node = Node::new(chain(&genesis, true, 3)?);
+        assert!(node.blockchain().total_work() < remote_work);
+
+        assert_eq!(node.sync_from(serve(remote)?)?, 2);
+
+        assert_eq!(node.blockchain().tip, remote_tip);
+        assert_eq!(node.blockchain().height(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_pages_through_inv_replies() -> Result<(), ChainError> {
+        let genesis = GenesisConfig::new(ADDRESS);
+        let remote = chain(&genesis, false, 5)?;
+        let remote_hashes: Vec<ByteBuf> = remote
+            .iter_forward()
+            .filter_map(|block| block.hash)
+            .collect();
+        let addr = serve_node(Node::new(remote).with_max_inv_hashes(2))?;
+        let node = Node::new(chain(&genesis, false, 0)?);
+
+        assert_eq!(node.get_blocks(addr)?, remote_hashes);
+        assert_eq!(node.sync_from(addr)?, 5);
+
+        assert_eq!(node.blockchain().tip, remote_hashes[5]);
+        node.blockchain().verify_chain()?;
+        Ok(())
+    }
+
+    #[test]
+    fn sync_keeps_chain_with_more_work_than_longer_one() -> Result<(), ChainError> {
+        let genesis = GenesisConfig::new(ADDRESS);
+        // Built first, its first block has to follow genesis quickly
+        let node = Node::new(chain(&genesis, false, 2)?);
+        let local_tip = node.blockchain().tip.clone();
+        let remote = chain(&genesis, true, 3)?;
+        assert!(remote.total_work() < node.blockchain().total_work());
+
+        assert_eq!(node.sync_from(serve(remote)?)?, 3);
+
+        assert_eq!(node.blockchain().tip, local_tip);
+        Ok(())
+    }
+
+    #[test]
+    fn reconnect_syncs_from_known_peers() -> Result<(), ChainError> {
+        let genesis = GenesisConfig::new(ADDRESS);
+        let remote = chain(&genesis, false, 2)?;
+        let remote_tip = remote.tip.clone();
+        let peer = serve(remote)?;
+        let node = Node::new(chain(&genesis, false, 0)?);
+        let unreachable = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        node.peers()?.add_peer(unreachable)?;
+        node.peers()?.add_peer(peer)?;
+        let (_, added) = node.peers()?.known_peers()?[0];
+
+        assert_eq!(node.reconnect()?, 2);
+
+        assert_eq!(node.blockchain().tip, remote_tip);
+        let peers = node.peers()?.known_peers()?;
+        assert_eq!(peers[0].0, peer);
+        assert!(peers[0].1 >= added);
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_frames_and_inv_are_refused() -> Result<(), ChainError> {
+        // Only the length prefix is sent, reading it must fail before allocating 4 GiB
+        let prefix = u32::MAX.to_be_bytes();
+        assert!(matches!(
+            read_message::<String>(&mut &prefix[..]),
+            Err(ChainError::MessageTooLarge {
+                len,
+                max: MAX_MESSAGE_BYTES
+            }) if len == u32::MAX as usize
+        ));
+
+        let mut frame = Vec::new();
+        write_message(&mut frame, &Message::<String>::GetBlocks { from: 0 })?;
+        assert!(matches!(
+            read_message_with_limit::<String>(&mut &frame[..], 0),
+            Err(ChainError::MessageTooLarge { max: 0, .. })
+        ));
+        assert!(matches!(
+            read_message::<String>(&mut &frame[..]),
+            Ok(Message::GetBlocks { from: 0 })
+        ));
+
+        let mut frame = Vec::new();
+        let hashes = vec![ByteBuf::new(); MAX_INV_HASHES + 1];
+        write_message(&mut frame, &Message::<String>::Inv(hashes))?;
+        assert!(matches!(
+            read_message::<String>(&mut &frame[..]),
+            Err(ChainError::InvTooLong {
+                max: MAX_INV_HASHES,
+                ..
+            })
+        ));
+        Ok(())
+    }
+}
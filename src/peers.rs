@@ -0,0 +1,111 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+use bincode::{deserialize, serialize};
+use sled::{Db, Tree};
+
+use crate::error::ChainError;
+
+/// Addresses of nodes this one talked to, with when they last answered. Kept in its own tree
+/// next to the chain so they survive restarts
+pub struct PeerStore {
+    tree: Tree,
+}
+
+impl PeerStore {
+    pub const PEERS_BUCKET: &'static str = "peers";
+
+    pub fn open(db: &Db) -> Result<Self, ChainError> {
+        Ok(PeerStore {
+            tree: db.open_tree(Self::PEERS_BUCKET)?,
+        })
+    }
+
+    /// Remembers `addr` as seen just now, refreshing `last_seen` of a known peer
+    pub fn add_peer(&self, addr: SocketAddr) -> Result<(), ChainError> {
+        self.tree
+            .insert(addr.to_string(), serialize(&SystemTime::now())?)?;
+        Ok(())
+    }
+
+    /// Forgets `addr`, `false` when it was not known
+    pub fn remove_peer(&self, addr: SocketAddr) -> Result<bool, ChainError> {
+        Ok(self.tree.remove(addr.to_string())?.is_some())
+    }
+
+    /// Every known peer with when it was last seen, most recent first
+    pub fn known_peers(&self) -> Result<Vec<(SocketAddr, SystemTime)>, ChainError> {
+        let mut peers = Vec::new();
+        for entry in self.tree.iter() {
+            let (addr, last_seen) = entry?;
+            let Some(addr) = std::str::from_utf8(&addr)
+                .ok()
+                .and_then(|addr| addr.parse().ok())
+            else {
+                continue;
+            };
+            peers.push((addr, deserialize::<SystemTime>(&last_seen)?));
+        }
+        peers.sort_by(|(_, a), (_, b)| b.cmp(a));
+        Ok(peers)
+    }
+
+    /// Forgets peers not seen for longer than `older_than`, returns how many
+    pub fn prune(&self, older_than: Duration) -> Result<usize, ChainError> {
+        let cutoff = SystemTime::now()
+            .checked_sub(older_than)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut pruned = 0;
+        for (addr, last_seen) in self.known_peers()? {
+            if last_seen < cutoff && self.remove_peer(addr)? {
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prunes_stale_peers_and_persists_the_rest() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let fresh: SocketAddr = "127.0.0.1:7878".parse().unwrap();
+        let stale: SocketAddr = "127.0.0.1:7879".parse().unwrap();
+        {
+            let peers = PeerStore::open(&sled::open(dir.path())?)?;
+            peers.add_peer(fresh)?;
+            let last_seen = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+            peers
+                .tree
+                .insert(stale.to_string(), serialize(&last_seen)?)?;
+            assert_eq!(peers.len(), 2);
+
+            assert_eq!(peers.prune(Duration::from_secs(60 * 60))?, 1);
+            peers.tree.flush()?;
+        }
+
+        let peers = PeerStore::open(&sled::open(dir.path())?)?;
+        let known: Vec<_> = peers
+            .known_peers()?
+            .into_iter()
+            .map(|(addr, _)| addr)
+            .collect();
+        assert_eq!(known, vec![fresh]);
+        assert!(peers.remove_peer(fresh)?);
+        assert!(peers.is_empty());
+        Ok(())
+    }
+}
@@ -1,63 +1,246 @@
-use bincode::serialize;
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
 use num_bigint::BigUint;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use serde_bytes::ByteBuf;
-use sha2::{Digest, Sha256};
+use tracing::{debug, debug_span};
 
-use crate::{block::Block, Blockchainable};
+use crate::{
+    block::Block,
+    hasher::{Hasher, Sha256Hasher},
+    Blockchainable,
+};
 
-pub struct ProofOfWork<'a, T> {
+pub struct ProofOfWork<'a, T, H = Sha256Hasher> {
     pub block: &'a Block<T>,
     pub target: BigUint,
+    pub difficulty_bits: u64,
+    pub hasher: H,
+    /// Header bytes up to the nonce, serialized once instead of on every attempt
+    header_prefix: Vec<u8>,
 }
 
 impl<'a, T> ProofOfWork<'a, T>
 where
     T: Blockchainable,
 {
-    const SHA_BITS: u64 = 256;
-    const TARGET_BITS: u64 = 24;
+    pub const TARGET_BITS: u64 = 24;
 
+    /// Proof of work at the difficulty the block was (or is to be) mined with
     pub fn new(block: &'a Block<T>) -> Self {
-        //target => 1[0...n] n=SHA_BITS-TARGET_BITS
+        Self::with_difficulty(block, block.difficulty_bits)
+    }
+
+    pub fn with_difficulty(block: &'a Block<T>, difficulty_bits: u64) -> Self {
+        ProofOfWork::with_hasher(block, difficulty_bits, Sha256Hasher)
+    }
+}
+
+impl<'a, T, H> ProofOfWork<'a, T, H>
+where
+    T: Blockchainable,
+    H: Hasher,
+{
+    pub fn with_hasher(block: &'a Block<T>, difficulty_bits: u64, hasher: H) -> Self {
+        //target => 1[0...n] n=digest_bits-difficulty_bits
+        let digest_bits = hasher.digest_bits();
         let mut target = BigUint::new(vec![1]);
-        target = target << (Self::SHA_BITS - Self::TARGET_BITS);
-        ProofOfWork { block, target }
+        target <<= digest_bits - difficulty_bits.min(digest_bits);
+        let mut header = block.header();
+        header.difficulty_bits = difficulty_bits;
+        ProofOfWork {
+            block,
+            target,
+            difficulty_bits,
+            hasher,
+            header_prefix: header.prefix(),
+        }
     }
 
-    fn prepare_data(&self, nonce: u64) -> ByteBuf {
-        let mut buffer = ByteBuf::new();
-        buffer.append(&mut serialize(self.block).expect("Serialization error!"));
-        buffer.append(&mut Self::TARGET_BITS.to_be_bytes().to_vec());
-        buffer.append(&mut nonce.to_be_bytes().to_vec());
+    /// Header bytes with `nonce`, written into `buffer` holding `header_prefix` or a
+    /// previous attempt
+    fn prepare_data<'b>(&self, buffer: &'b mut Vec<u8>, nonce: u64) -> &'b [u8] {
+        buffer.truncate(self.header_prefix.len());
+        buffer.extend_from_slice(&nonce.to_be_bytes());
         buffer
     }
 
+    /// Number of nonce attempts between progress events
+    const PROGRESS_EVERY: u64 = 1 << 20;
+
+    /// Searches the whole nonce space on every available core
     pub fn run(&self) -> Option<(u64, ByteBuf)> {
-        println!("Mining");
-        let mut nonce = 0;
-        while nonce < u64::MAX {
-            let data = self.prepare_data(nonce);
-            let hash = Sha256::new().chain_update(data).finalize();
-            let hashint = BigUint::from_bytes_be(hash.as_slice());
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        self.run_parallel(workers as u64)
+    }
+
+    /// Searches nonces one at a time from 0 upwards on the calling thread, so the smallest
+    /// valid nonce is always the one found, whatever `run` does
+    pub fn run_deterministic(&self) -> Option<(u64, ByteBuf)> {
+        self.run_range(0..u64::MAX, &AtomicBool::new(false))
+    }
+
+    /// Splits the nonce space into `workers` disjoint ranges searched in parallel, the first
+    /// valid nonce found stops all of them. Which valid nonce wins is not deterministic
+    pub fn run_parallel(&self, workers: u64) -> Option<(u64, ByteBuf)> {
+        let span = debug_span!(
+            "mine",
+            height = self.block.height,
+            difficulty_bits = self.difficulty_bits,
+            transactions = self.block.transactions.len(),
+            workers,
+        );
+
+        let workers = workers.max(1);
+        let chunk = u64::MAX / workers;
+        let found = AtomicBool::new(false);
+        (0..workers).into_par_iter().find_map_any(|i| {
+            let start = i * chunk;
+            let end = if i == workers - 1 {
+                u64::MAX
+            } else {
+                start + chunk
+            };
+            span.in_scope(|| self.run_range(start..end, &found))
+        })
+    }
+
+    /// Searches given nonce range, giving up as soon as `found` is set by another worker
+    pub fn run_range(&self, nonces: Range<u64>, found: &AtomicBool) -> Option<(u64, ByteBuf)> {
+        let mut attempts: u64 = 0;
+        let mut buffer = self.header_prefix.clone();
+        for nonce in nonces {
+            if found.load(Ordering::Relaxed) {
+                return None;
+            }
+            let data = self.prepare_data(&mut buffer, nonce);
+            let hash = self.hasher.hash(data);
+            let hashint = BigUint::from_bytes_be(&hash);
+            attempts += 1;
 
             if hashint < self.target {
-                let buffer = ByteBuf::from(hash.to_vec());
-                return Some((nonce, buffer));
+                // Only the first worker to flip the flag gets to submit its result
+                if found.swap(true, Ordering::AcqRel) {
+                    return None;
+                }
+                debug!(attempts, nonce, "Found valid nonce");
+                return Some((nonce, ByteBuf::from(hash)));
+            }
+            if attempts.is_multiple_of(Self::PROGRESS_EVERY) {
+                debug!(attempts, "Still mining");
             }
-            nonce += 1;
         }
+        debug!(attempts, "Nonce range exhausted");
         None
     }
 
+    /// Expected number of hashes needed to meet the target, what a block adds to chain work
+    pub fn work(&self) -> BigUint {
+        (BigUint::from(1u8) << self.hasher.digest_bits()) / &self.target
+    }
+
+    /// Checks the nonce meets the target and, for mined blocks, yields the stored hash
     pub fn validate(&self) -> bool {
         if let Some(nonce) = self.block.nonce {
-            let data = self.prepare_data(nonce);
-            let hash = Sha256::new().chain_update(data).finalize();
-            let hashint = BigUint::from_bytes_be(hash.as_slice());
-            if hashint < self.target {
+            let mut buffer = self.header_prefix.clone();
+            let hash = self.hasher.hash(self.prepare_data(&mut buffer, nonce));
+            let hashint = BigUint::from_bytes_be(&hash);
+            let matches_stored = self
+                .block
+                .hash
+                .as_ref()
+                .is_none_or(|stored| stored.as_slice() == hash.as_slice());
+            if hashint < self.target && matches_stored {
                 return true;
             }
         }
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hasher::Blake3Hasher, transaction::Transaction};
+
+    const ADDRESS: &str = "1MBnzAR1oDnakY5fKLWvDeFD87PhPH9q1f";
+
+    /// Genesis-like block mined at `difficulty_bits` with the smallest valid nonce
+    fn mined(difficulty_bits: u64) -> Block<String> {
+        let coinbase = Transaction::new_coinbase_tx(ADDRESS, "", Transaction::SUBSIDY, 0).unwrap();
+        let mut block = Block::template(vec![coinbase], String::new(), None, 0, difficulty_bits);
+        let (nonce, hash) = ProofOfWork::new(&block).run_deterministic().unwrap();
+        block.nonce = Some(nonce);
+        block.hash = Some(hash);
+        block
+    }
+
+    #[test]
+    fn block_validates_at_the_difficulty_it_was_mined_with() {
+        let block = mined(8);
+        assert_eq!(block.hash.as_ref().unwrap()[0], 0);
+        assert!(ProofOfWork::new(&block).validate());
+        assert_eq!(ProofOfWork::new(&block).difficulty_bits, 8);
+    }
+
+    #[test]
+    fn parallel_nonce_validates_and_serial_finds_the_smallest() {
+        let mut block = mined(12);
+        let serial = ProofOfWork::new(&block).run_deterministic().unwrap();
+        let single = ProofOfWork::new(&block).run_parallel(1).unwrap();
+        let (nonce, hash) = ProofOfWork::new(&block).run_parallel(4).unwrap();
+
+        assert_eq!(single, serial);
+        assert!(nonce >= serial.0);
+        block.nonce = Some(nonce);
+        block.hash = Some(hash);
+        assert!(ProofOfWork::new(&block).validate());
+    }
+
+    #[test]
+    fn deterministic_run_repeats_the_smallest_nonce() {
+        let block = mined(10);
+        let pow = ProofOfWork::new(&block);
+        let first = pow.run_deterministic().unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(
+                ProofOfWork::new(&block).run_deterministic(),
+                Some(first.clone())
+            );
+        }
+        assert_eq!(block.nonce, Some(first.0));
+        let found = AtomicBool::new(false);
+        assert!((0..first.0).all(|nonce| pow.run_range(nonce..nonce + 1, &found).is_none()));
+    }
+
+    #[test]
+    fn raising_difficulty_of_mined_block_invalidates_it() {
+        let mut block = mined(8);
+        // The bits are part of the header, so the stored hash no longer matches
+        block.difficulty_bits = 32;
+        assert!(!ProofOfWork::new(&block).validate());
+    }
+
+    #[test]
+    fn block_mined_with_blake3_validates_only_with_blake3() {
+        let mut coinbase =
+            Transaction::new_coinbase_tx(ADDRESS, "", Transaction::SUBSIDY, 0).unwrap();
+        coinbase.set_id_with(&Blake3Hasher);
+        assert_eq!(coinbase.id, coinbase.hash_with(&Blake3Hasher));
+        assert_ne!(coinbase.id, coinbase.hash());
+
+        let mut block = Block::template(vec![coinbase], String::new(), None, 0, 8);
+        let (nonce, hash) = ProofOfWork::with_hasher(&block, 8, Blake3Hasher)
+            .run_deterministic()
+            .unwrap();
+        block.nonce = Some(nonce);
+        block.hash = Some(hash);
+        assert!(ProofOfWork::with_hasher(&block, 8, Blake3Hasher).validate());
+        assert!(!ProofOfWork::new(&block).validate());
+    }
+}
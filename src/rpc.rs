@@ -0,0 +1,389 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{IpAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use serde_bytes::ByteBuf;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::{blockchain::Blockchain, error::ChainError, wallet::Address, Blockchainable};
+
+/// Request body is not valid JSON
+pub const PARSE_ERROR: i64 = -32700;
+/// Body is JSON but not a JSON-RPC 2.0 request object
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// Chain failed to answer a valid request
+pub const INTERNAL_ERROR: i64 = -32603;
+/// Method spending from the node's wallets called by a client not on a loopback address
+pub const FORBIDDEN: i64 = -32001;
+
+/// Most bytes of request line and headers together the server reads
+pub const MAX_HEAD_BYTES: usize = 8 * 1024;
+/// Most bytes of request body the server reads
+pub const MAX_BODY_BYTES: usize = 1_000_000;
+/// Methods spending from the node's wallets, served to loopback clients only
+const WALLET_METHODS: &[&str] = &["sendtoaddress"];
+
+/// JSON-RPC error object, `code` is one of the constants above
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+}
+
+impl From<ChainError> for RpcError {
+    fn from(e: ChainError) -> Self {
+        let code = match e {
+            ChainError::InvalidAddress | ChainError::Hex(_) => INVALID_PARAMS,
+            _ => INTERNAL_ERROR,
+        };
+        RpcError::new(code, e.to_string())
+    }
+}
+
+impl From<hex::FromHexError> for RpcError {
+    fn from(e: hex::FromHexError) -> Self {
+        ChainError::from(e).into()
+    }
+}
+
+/// JSON-RPC 2.0 server over HTTP, one POSTed request per connection
+///
+/// Methods (params by position or by name):
+/// - `getbalance(address)` - balance of base58check address
+/// - `getblock(hash)` - block with hex hash, `null` when it is not stored
+/// - `getblockcount()` - height of the tip block
+/// - `sendtoaddress(from, to, value, fee = 0)` - queues a transaction, returns its hex id.
+///   Loopback clients only, anyone reaching the port could spend the node's wallets otherwise
+/// - `getrawmempool()` - hex ids of pending transactions
+/// - `estimatefee(target_blocks = 1)` - suggested fee per 1000 bytes to be mined that soon
+pub struct RpcServer<T> {
+    blockchain: Arc<Mutex<Blockchain<T>>>,
+}
+
+impl<T> Clone for RpcServer<T> {
+    fn clone(&self) -> Self {
+        RpcServer {
+            blockchain: Arc::clone(&self.blockchain),
+        }
+    }
+}
+
+impl<T> RpcServer<T>
+where
+    T: Blockchainable,
+{
+    /// How long a connected client may stay silent before it is dropped
+    const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new(blockchain: Blockchain<T>) -> Self {
+        RpcServer {
+            blockchain: Arc::new(Mutex::new(blockchain)),
+        }
+    }
+
+    pub fn blockchain(&self) -> MutexGuard<'_, Blockchain<T>> {
+        self.blockchain
+            .lock()
+            .expect("Blockchain lock is poisoned!")
+    }
+
+    /// Binds `addr` and serves clients forever
+    pub fn listen(&self, addr: impl ToSocketAddrs) -> Result<(), ChainError> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serves clients connecting to an already bound listener, failed requests are only logged
+    pub fn serve(&self, listener: TcpListener) -> Result<(), ChainError> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let client = stream.peer_addr()?;
+            if let Err(e) = self.handle(stream) {
+                warn!(%client, "RPC request failed: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&self, stream: TcpStream) -> Result<(), ChainError> {
+        stream.set_read_timeout(Some(Self::READ_TIMEOUT))?;
+        let client = stream.peer_addr()?.ip();
+        let mut reader = BufReader::new(&stream);
+        let body = match read_http_body(&mut reader) {
+            Ok(Some(body)) => body,
+            Ok(None) => return write_http(&stream, "400 Bad Request", ""),
+            Err(e @ ChainError::RequestTooLarge { .. }) => {
+                write_http(&stream, "413 Payload Too Large", "")?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+
+        match self.respond(&body, client) {
+            Some(response) => write_http(&stream, "200 OK", &response.to_string()),
+            None => write_http(&stream, "204 No Content", ""),
+        }
+    }
+
+    /// Response object for raw request `body` sent by `client`, `None` for a notification
+    pub fn respond(&self, body: &[u8], client: IpAddr) -> Option<Value> {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => {
+                return Some(error_response(
+                    Value::Null,
+                    RpcError::new(PARSE_ERROR, e.to_string()),
+                ))
+            }
+        };
+        let id = request.get("id").cloned();
+        let method = request.get("method").and_then(Value::as_str);
+        let (Some("2.0"), Some(method)) = (request.get("jsonrpc").and_then(Value::as_str), method)
+        else {
+            return Some(error_response(
+                id.unwrap_or_default(),
+                RpcError::new(INVALID_REQUEST, "Not a JSON-RPC 2.0 request"),
+            ));
+        };
+
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+        let result = if WALLET_METHODS.contains(&method) && !client.is_loopback() {
+            Err(RpcError::new(
+                FORBIDDEN,
+                format!("Method {method} is only served to local clients"),
+            ))
+        } else {
+            self.call(method, &params)
+        };
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(e) => error_response(id, e),
+        })
+    }
+
+    /// Runs `method` with `params` against the chain
+    pub fn call(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "getbalance" => {
+                let address: Address = str_param(params, 0, "address")?.parse()?;
+                Ok(json!(self.blockchain().balance_at(&address)?))
+            }
+            "getblock" => {
+                let hash = ByteBuf::from(hex::decode(str_param(params, 0, "hash")?)?);
+                let block = self.blockchain().get_block(&hash);
+                Ok(serde_json::to_value(block).map_err(ChainError::from)?)
+            }
+            "getblockcount" => Ok(json!(self.blockchain().height())),
+            "sendtoaddress" => {
+                let from: Address = str_param(params, 0, "from")?.parse()?;
+                let to: Address = str_param(params, 1, "to")?.parse()?;
+                let value = u64_param(params, 2, "value")?;
+                let fee = match param(params, 3, "fee") {
+                    Some(_) => u64_param(params, 3, "fee")?,
+                    None => 0,
+                };
+                let txid = self.blockchain().send(&from, &to, value, fee)?;
+                Ok(json!(hex::encode(txid)))
+            }
+            "getrawmempool" => {
+                let txs = self.blockchain().mempool()?.collect(usize::MAX)?;
+                Ok(json!(txs
+                    .iter()
+                    .map(|tx| hex::encode(&tx.id))
+                    .collect::<Vec<_>>()))
+            }
+            "estimatefee" => {
+                let target_blocks = match param(params, 0, "target_blocks") {
+                    Some(_) => u64_param(params, 0, "target_blocks")?,
+                    None => 1,
+                };
+                Ok(json!(self
+                    .blockchain()
+                    .estimate_fee(target_blocks as usize)))
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Method not found: {method}"),
+            )),
+        }
+    }
+}
+
+fn error_response(id: Value, e: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": e.code, "message": e.message },
+        "id": id,
+    })
+}
+
+/// Parameter at `idx` of a positional array or under `name` of a named object
+fn param<'a>(params: &'a Value, idx: usize, name: &str) -> Option<&'a Value> {
+    match params {
+        Value::Array(params) => params.get(idx),
+        Value::Object(params) => params.get(name),
+        _ => None,
+    }
+}
+
+fn str_param<'a>(params: &'a Value, idx: usize, name: &str) -> Result<&'a str, RpcError> {
+    param(params, idx, name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::invalid_params(format!("Expected string parameter {name}")))
+}
+
+fn u64_param(params: &Value, idx: usize, name: &str) -> Result<u64, RpcError> {
+    param(params, idx, name)
+        .and_then(Value::as_u64)
+        .ok_or_else(|| RpcError::invalid_params(format!("Expected integer parameter {name}")))
+}
+
+/// Body of an HTTP POST request, `None` when the request is not one. `RequestTooLarge` once
+/// its head passes `MAX_HEAD_BYTES` or it announces a body over `MAX_BODY_BYTES`
+fn read_http_body(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>, ChainError> {
+    let mut head_left = MAX_HEAD_BYTES;
+    let mut line = String::new();
+    read_head_line(reader, &mut line, &mut head_left)?;
+    if !line.starts_with("POST ") {
+        return Ok(None);
+    }
+
+    let mut content_length = None;
+    loop {
+        line.clear();
+        if read_head_line(reader, &mut line, &mut head_left)? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let Some(content_length) = content_length else {
+        return Ok(None);
+    };
+    if content_length > MAX_BODY_BYTES {
+        return Err(ChainError::RequestTooLarge {
+            max: MAX_BODY_BYTES,
+        });
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+/// Appends a line of the request head to `line`, reading no more than `head_left` bytes,
+/// which it is then reduced by
+fn read_head_line(
+    reader: &mut impl BufRead,
+    line: &mut String,
+    head_left: &mut usize,
+) -> Result<usize, ChainError> {
+    let read = reader
+        .by_ref()
+        .take(*head_left as u64 + 1)
+        .read_line(line)?;
+    *head_left = head_left
+        .checked_sub(read)
+        .ok_or(ChainError::RequestTooLarge {
+            max: MAX_HEAD_BYTES,
+        })?;
+    Ok(read)
+}
+
+fn write_http(mut stream: &TcpStream, status: &str, body: &str) -> Result<(), ChainError> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(stream.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, net::Ipv4Addr};
+
+    use super::*;
+    use crate::wallet::Wallet;
+
+    #[test]
+    fn reads_body_of_post_request() -> Result<(), ChainError> {
+        let request = "POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}";
+        let body = read_http_body(&mut Cursor::new(request))?;
+        assert_eq!(body.as_deref(), Some(&b"{}"[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_oversized_body_before_reading_it() {
+        let request = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+        assert!(matches!(
+            read_http_body(&mut Cursor::new(request)),
+            Err(ChainError::RequestTooLarge {
+                max: MAX_BODY_BYTES
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_head() {
+        let header = "a".repeat(MAX_HEAD_BYTES);
+        let request = format!("POST / HTTP/1.1\r\nX-Filler: {header}\r\n\r\n");
+        assert!(matches!(
+            read_http_body(&mut Cursor::new(request)),
+            Err(ChainError::RequestTooLarge {
+                max: MAX_HEAD_BYTES
+            })
+        ));
+    }
+
+    #[test]
+    fn wallet_methods_are_refused_to_remote_clients() -> Result<(), ChainError> {
+        let address = Wallet::new().address();
+        let address = std::str::from_utf8(&address).unwrap();
+        let server = RpcServer::new(Blockchain::<String>::new_test(address)?);
+        let request = json!({
+            "jsonrpc": "2.0",
+            "method": "sendtoaddress",
+            "params": [address, address, 1],
+            "id": 1,
+        })
+        .to_string();
+
+        let response = server
+            .respond(
+                request.as_bytes(),
+                IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)),
+            )
+            .unwrap();
+        assert_eq!(response["error"]["code"], FORBIDDEN);
+        assert!(server.blockchain().mempool()?.is_empty());
+        Ok(())
+    }
+}
@@ -1,15 +1,30 @@
-use std::fmt::Display;
+use std::{collections::HashMap, fmt::Display};
 
 use bincode::serialize;
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
-use sha2::{Digest, Sha256};
 
-use crate::{blockchain::Blockchain, wallet::{Wallet, Wallets}, Blockchainable};
+use crate::{
+    blockchain::Blockchain,
+    error::ChainError,
+    hasher::{Hasher, Sha256Hasher},
+    wallet::{Address, Wallet},
+    Blockchainable,
+};
+
+/// Sum of `values`, `AmountOverflow` instead of wrapping past `u64::MAX`
+pub fn checked_sum(values: impl IntoIterator<Item = u64>) -> Result<u64, ChainError> {
+    values
+        .into_iter()
+        .try_fold(0u64, |acc, value| acc.checked_add(value))
+        .ok_or(ChainError::AmountOverflow)
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction {
     /// Tx id in for of bytes hash
+    #[serde(with = "crate::hex_bytes")]
     pub id: ByteBuf,
     /// Inputs that participate in tx
     pub vin: Vec<TXInput>,
@@ -18,46 +33,113 @@ pub struct Transaction {
 }
 
 impl Transaction {
-    const SUBSIDY: u64 = 10;
+    /// Block reward before any halving
+    pub const SUBSIDY: u64 = 10;
+    /// Longest coinbase input data in bytes, height prefix included, like Bitcoin's scriptSig
+    pub const MAX_COINBASE_DATA: usize = 100;
+    /// Bytes of the little endian block height that starts every coinbase input data
+    const COINBASE_HEIGHT_LEN: usize = 8;
+
+    /// Coinbase of block at `height` paying `value` to `to`, input data is the height
+    /// followed by `data` so coinbases of different blocks never share an id
+    pub fn new_coinbase_tx(
+        to: &str,
+        data: &str,
+        value: u64,
+        height: u64,
+    ) -> Result<Self, ChainError> {
+        let mut pub_key = height.to_le_bytes().to_vec();
+        pub_key.extend_from_slice(data.as_bytes());
+        if pub_key.len() > Self::MAX_COINBASE_DATA {
+            return Err(ChainError::CoinbaseDataTooLong { len: pub_key.len() });
+        }
 
-    pub fn new_coinbase_tx(to: &String, data: &String) -> Self {
         let txin = TXInput {
             txid: ByteBuf::new(),
             vout: None,
             signature: ByteBuf::new(),
-            pub_key: ByteBuf::from(data.clone())
-        };
-
-        let mut txout = TXOutput {
-            value: Self::SUBSIDY,
-            pub_key_hash: ByteBuf::new(),
+            pub_key: ByteBuf::from(pub_key),
         };
-        txout.lock(&ByteBuf::from(to.clone()));
 
         let mut tx = Transaction {
             id: ByteBuf::new(),
             vin: vec![txin],
-            vout: vec![txout],
+            vout: vec![TXOutput::new(value, to)?],
         };
         tx.set_id();
-        tx
+        Ok(tx)
     }
 
-    pub fn new_tx<T>(to: &String, from: &String, value: u64, blockchain: &mut Blockchain<T>) -> Self
+    /// Transaction paying `value` from `from`'s wallet to `to`, with change back to `from`
+    pub fn new_tx<T>(
+        from: &Address,
+        to: &Address,
+        value: u64,
+        fee: u64,
+        blockchain: &Blockchain<T>,
+    ) -> Result<Self, ChainError>
     where
         T: Blockchainable,
     {
-        let mut vin = Vec::new();
-        let mut vout = Vec::new();
+        Self::new_multi(from.as_str(), &[(to.to_string(), value)], fee, blockchain)
+    }
+
+    /// Transaction paying every `(address, value)` of `outputs` from `from`'s wallet,
+    /// with a single change output back to `from`
+    pub fn new_multi<T>(
+        from: &str,
+        outputs: &[(String, u64)],
+        fee: u64,
+        blockchain: &Blockchain<T>,
+    ) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let vout = outputs
+            .iter()
+            .map(|(to, value)| TXOutput::new(*value, to))
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::fund(from, vout, fee, blockchain)
+    }
+
+    /// Transaction embedding `data` in an unspendable zero value output, paid from `from`'s
+    /// wallet with change back to `from`
+    pub fn new_anchor<T>(
+        from: &str,
+        data: &[u8],
+        fee: u64,
+        blockchain: &Blockchain<T>,
+    ) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        Self::fund(from, vec![TXOutput::op_return(data)], fee, blockchain)
+    }
 
-        let wallets = Wallets::fetch_wallets();
-        let wallet = wallets.get(&ByteBuf::from(from.clone())).expect("Wallet with address not found!");
+    /// Transaction creating `vout` plus `fee`, spending outputs of `from`'s wallet and sending
+    /// the change back to `from`
+    fn fund<T>(
+        from: &str,
+        mut vout: Vec<TXOutput>,
+        fee: u64,
+        blockchain: &Blockchain<T>,
+    ) -> Result<Self, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let wallets = blockchain.wallets()?;
+        let wallet = wallets
+            .get(&ByteBuf::from(from))
+            .ok_or(ChainError::WalletNotFound)?;
         let pub_key_hash = Wallet::hash_pub_key(&wallet.public_key);
-        let (all, valid_outputs) = blockchain.find_spendable_outputs(&pub_key_hash, value);
-        if all < value {
-            panic!("Not enough coins!")
+        let need = checked_sum(vout.iter().map(|out| out.value).chain([fee]))?;
+        let (all, valid_outputs) = blockchain.find_spendable_outputs(&pub_key_hash, need)?;
+        // Even a transaction moving no value has to spend something, or it could be replayed
+        if all < need || valid_outputs.is_empty() {
+            return Err(ChainError::NotEnoughFunds { have: all, need });
         }
 
+        let mut vin = Vec::new();
         for (txid, out_idx) in valid_outputs {
             out_idx.iter().for_each(|idx| {
                 vin.push(TXInput {
@@ -69,14 +151,8 @@ impl Transaction {
             })
         }
 
-        let mut txout_th = TXOutput { value, pub_key_hash: ByteBuf::new() };
-        txout_th.lock(&ByteBuf::from(to.clone()));
-        vout.push(txout_th);
-
-        if all > value {
-            let mut txout_rest = TXOutput { value: all - value, pub_key_hash: ByteBuf::new() };
-            txout_rest.lock(&ByteBuf::from(from.clone()));
-            vout.push(txout_rest);
+        if all > need {
+            vout.push(TXOutput::new(all - need, from)?);
         }
 
         let mut tx = Self {
@@ -85,17 +161,236 @@ impl Transaction {
             vout,
         };
         tx.set_id();
-        tx
+        Ok(tx)
+    }
+
+    /// What is left for the miner, `total_input_value` minus `total_output_value`
+    pub fn fee(&self, prev_txs: &HashMap<ByteBuf, Transaction>) -> Result<u64, ChainError> {
+        if self.is_coinbase() {
+            return Ok(0);
+        }
+        self.total_input_value(prev_txs)?
+            .checked_sub(self.total_output_value())
+            .ok_or(ChainError::NegativeFee {
+                txid: self.id.clone(),
+            })
+    }
+
+    /// Sum of the outputs spent by inputs, looked up in `prev_txs`. `MissingPrevTx` when an
+    /// input's transaction is not there, `MissingPrevOutput` when it has no output at the
+    /// input's index. Coinbases spend nothing
+    pub fn total_input_value(
+        &self,
+        prev_txs: &HashMap<ByteBuf, Transaction>,
+    ) -> Result<u64, ChainError> {
+        if self.is_coinbase() {
+            return Ok(0);
+        }
+
+        let mut inputs = 0;
+        for vin in &self.vin {
+            let prev = prev_txs.get(&vin.txid).ok_or(ChainError::MissingPrevTx {
+                txid: vin.txid.clone(),
+            })?;
+            let prev_out = vin.vout.and_then(|vout| prev.vout.get(vout)).ok_or(
+                ChainError::MissingPrevOutput {
+                    txid: vin.txid.clone(),
+                    vout: vin.vout,
+                },
+            )?;
+            inputs = checked_sum([inputs, prev_out.value])?;
+        }
+        Ok(inputs)
+    }
+
+    /// Sum of created outputs, saturating at `u64::MAX`. No inputs can cover that much, so
+    /// `fee` still rejects outputs overflowing it
+    pub fn total_output_value(&self) -> u64 {
+        self.vout
+            .iter()
+            .fold(0u64, |acc, out| acc.saturating_add(out.value))
+    }
+
+    /// Size of serialized transaction, what fee rates are measured against
+    pub fn size_bytes(&self) -> usize {
+        serialize(self).map(|tx| tx.len()).unwrap_or_default()
     }
 
     fn set_id(&mut self) {
-        let serialized = serialize(&self).expect("Serialization error!");
-        let hash = Sha256::new().chain_update(serialized).finalize();
-        self.id = ByteBuf::from(hash.to_vec());
+        self.set_id_with(&Sha256Hasher);
+    }
+
+    /// Sets id to `hash_with(hasher)`
+    pub fn set_id_with(&mut self, hasher: &impl Hasher) {
+        self.id = self.hash_with(hasher);
+    }
+
+    /// SHA-256 id of the transaction, see `hash_with`
+    pub fn hash(&self) -> ByteBuf {
+        self.hash_with(&Sha256Hasher)
+    }
+
+    /// `hasher`'s digest of the serialized transaction with id and input signatures cleared,
+    /// so the id is the same before and after signing
+    pub fn hash_with(&self, hasher: &impl Hasher) -> ByteBuf {
+        let mut copy = self.clone();
+        copy.id = ByteBuf::new();
+        for vin in &mut copy.vin {
+            vin.signature = ByteBuf::new();
+        }
+        let serialized = serialize(&copy).expect("Serialization error!");
+        ByteBuf::from(hasher.hash(&serialized))
+    }
+
+    /// Signs every input with given key for chain `chain_id` in low-S form, `prev_txs` must hold
+    /// all txs referenced by inputs
+    pub fn sign(
+        &mut self,
+        private_key: &SigningKey,
+        prev_txs: &HashMap<ByteBuf, Transaction>,
+        chain_id: u32,
+    ) -> Result<(), ChainError> {
+        if self.is_coinbase() {
+            return Ok(());
+        }
+
+        let mut trimmed = self.trimmed_copy();
+        for idx in 0..self.vin.len() {
+            let digest = trimmed.signing_digest(idx, prev_txs, chain_id)?;
+            let signature: Signature = private_key.sign(&digest);
+            let signature = signature.normalize_s().unwrap_or(signature);
+            self.vin[idx].signature = ByteBuf::from(signature.to_bytes().to_vec());
+        }
+        Ok(())
+    }
+
+    /// Checks every input is signed, for chain `chain_id`, by the owner of the output it spends.
+    /// Signatures must be in low-S form, so nobody but the signer can produce another valid
+    /// variant of a signed transaction
+    pub fn verify(&self, prev_txs: &HashMap<ByteBuf, Transaction>, chain_id: u32) -> bool {
+        if self.is_coinbase() {
+            return true;
+        }
+
+        let mut trimmed = self.trimmed_copy();
+        for (idx, vin) in self.vin.iter().enumerate() {
+            // A valid signature only proves ownership of the key it was made with
+            let owns_output = prev_txs
+                .get(&vin.txid)
+                .and_then(|prev| prev.vout.get(vin.vout?))
+                .and_then(TXOutput::pub_key_hash)
+                .is_some_and(|pub_key_hash| vin.uses_key(pub_key_hash));
+            if !owns_output {
+                return false;
+            }
+            let is_low_s = Signature::from_slice(&vin.signature)
+                .is_ok_and(|signature| signature.normalize_s().is_none());
+            if !is_low_s {
+                return false;
+            }
+            let Ok(digest) = trimmed.signing_digest(idx, prev_txs, chain_id) else {
+                return false;
+            };
+            if !Wallet::verify_data(&vin.pub_key, &digest, &vin.signature) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Copy with signatures and pub keys of inputs cleared, base for signing preimages
+    fn trimmed_copy(&self) -> Self {
+        let vin = self
+            .vin
+            .iter()
+            .map(|vin| TXInput {
+                txid: vin.txid.clone(),
+                vout: vin.vout,
+                signature: ByteBuf::new(),
+                pub_key: ByteBuf::new(),
+            })
+            .collect();
+
+        Transaction {
+            id: self.id.clone(),
+            vin,
+            vout: self.vout.clone(),
+        }
+    }
+
+    /// Hash of `chain_id` and the trimmed tx where only input at `idx` carries the referenced
+    /// output's pub key hash
+    fn signing_digest(
+        &mut self,
+        idx: usize,
+        prev_txs: &HashMap<ByteBuf, Transaction>,
+        chain_id: u32,
+    ) -> Result<ByteBuf, ChainError> {
+        let txid = self.vin[idx].txid.clone();
+        let prev_out = prev_txs
+            .get(&txid)
+            .and_then(|prev| prev.vout.get(self.vin[idx].vout?))
+            .ok_or(ChainError::MissingPrevTx { txid })?;
+
+        self.vin[idx].pub_key = prev_out.pub_key_hash().cloned().unwrap_or_default();
+        let hash = self.hash();
+        self.vin[idx].pub_key = ByteBuf::new();
+
+        let preimage = [&chain_id.to_be_bytes()[..], &hash].concat();
+        Ok(ByteBuf::from(Sha256Hasher.hash(&preimage)))
+    }
+
+    /// Multi-line listing of every input and output, first line is the tx id
+    pub fn describe(&self) -> String {
+        let mut out = format!("ID: {}", hex::encode(&self.id));
+        for (idx, vin) in self.vin.iter().enumerate() {
+            if self.is_coinbase() {
+                let height = self
+                    .coinbase_height()
+                    .map_or("NONE".to_string(), |height| height.to_string());
+                let data = vin
+                    .pub_key
+                    .get(Self::COINBASE_HEIGHT_LEN..)
+                    .map(String::from_utf8_lossy)
+                    .unwrap_or_default();
+                out.push_str(&format!(
+                    "\n\tVIN {idx}: COINBASE, HEIGHT: {height}, DATA: {data}"
+                ));
+                continue;
+            }
+            out.push_str(&format!(
+                "\n\tVIN {idx}: TXID: {}, VOUT: {}, PUB: {}...",
+                hex::encode(&vin.txid),
+                vin.vout.map_or("NONE".to_string(), |vout| vout.to_string()),
+                hex::encode(&vin.pub_key)
+                    .chars()
+                    .take(40)
+                    .collect::<String>()
+            ));
+        }
+        for (idx, vout) in self.vout.iter().enumerate() {
+            let script = match &vout.script {
+                ScriptType::P2PKH(pub_key_hash) => {
+                    format!("PUB KEY HASH: {}", hex::encode(pub_key_hash))
+                }
+                ScriptType::OpReturn(data) => format!("OP_RETURN: {}", hex::encode(data)),
+            };
+            out.push_str(&format!("\n\tVOUT {idx}: VALUE: {}, {script}", vout.value));
+        }
+        out
     }
 
     pub fn is_coinbase(&self) -> bool {
-        self.vin.len() == 1 && self.vin[0].txid.len() == 0 && self.vin[0].vout.is_none()
+        self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout.is_none()
+    }
+
+    /// Block height a coinbase commits to, `None` for other transactions or short data
+    pub fn coinbase_height(&self) -> Option<u64> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let bytes = self.vin[0].pub_key.get(..Self::COINBASE_HEIGHT_LEN)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
     }
 }
 
@@ -111,12 +406,15 @@ impl Display for Transaction {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TXInput {
     /// Id of tx that this input connects output
+    #[serde(with = "crate::hex_bytes")]
     pub txid: ByteBuf,
     /// Index of output reference in connected tx
     pub vout: Option<usize>,
     /// TODO
+    #[serde(with = "crate::hex_bytes")]
     pub signature: ByteBuf,
     /// TODO
+    #[serde(with = "crate::hex_bytes")]
     pub pub_key: ByteBuf,
 }
 
@@ -125,29 +423,281 @@ impl TXInput {
         let locking_hash = Wallet::hash_pub_key(&self.pub_key);
         locking_hash == *pub_key_hash
     }
+
+    /// Whether this input's key is the owner of outputs locked to `pub_key_hash`
+    pub fn can_unlock_output_with(&self, pub_key_hash: &ByteBuf) -> bool {
+        self.uses_key(pub_key_hash)
+    }
+}
+
+/// Condition an output is locked with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ScriptType {
+    /// Spendable by the key hashing to given pub key hash
+    P2PKH(#[serde(with = "crate::hex_bytes")] ByteBuf),
+    /// Never spendable, only carries data anchored in chain
+    OpReturn(#[serde(with = "crate::hex_bytes")] ByteBuf),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TXOutput {
     /// Like quantity of coins in the outputting tx
     pub value: u64,
-    /// TODO
-    pub pub_key_hash: ByteBuf,
+    /// Who can spend the output
+    pub script: ScriptType,
 }
 
 impl TXOutput {
+    /// Output of `value` spendable by the owner of base58check `address`
+    pub fn new(value: u64, address: &str) -> Result<Self, ChainError> {
+        let mut txout = TXOutput {
+            value,
+            script: ScriptType::P2PKH(ByteBuf::new()),
+        };
+        txout.lock(&ByteBuf::from(address))?;
+        Ok(txout)
+    }
+
+    /// Unspendable zero value output carrying `data`
+    pub fn op_return(data: &[u8]) -> Self {
+        TXOutput {
+            value: 0,
+            script: ScriptType::OpReturn(ByteBuf::from(data.to_vec())),
+        }
+    }
+
+    /// Hash of the key allowed to spend this output, `None` for an unspendable one
+    pub fn pub_key_hash(&self) -> Option<&ByteBuf> {
+        match &self.script {
+            ScriptType::P2PKH(pub_key_hash) => Some(pub_key_hash),
+            ScriptType::OpReturn(_) => None,
+        }
+    }
+
+    /// Whether any key can ever spend this output
+    pub fn is_spendable(&self) -> bool {
+        self.pub_key_hash().is_some()
+    }
+
     pub fn is_locked_with(&self, pub_key_hash: &ByteBuf) -> bool {
-        self.pub_key_hash == *pub_key_hash
+        self.pub_key_hash() == Some(pub_key_hash)
     }
 
-    pub fn lock(&mut self, address: &ByteBuf) {
-        let pub_key_hash = bs58::decode(address)
-            .with_alphabet(bs58::Alphabet::BITCOIN)
-            .into_vec()
-            .expect("Address could not be decoded to base58!");
-        let pub_key_hash =
-            ByteBuf::from(&pub_key_hash[1..pub_key_hash.len() - Wallet::CHECKSUM_LEN]);
+    /// Whether a key hashing to `pub_key_hash` can spend this output
+    pub fn can_be_unlocked_with(&self, pub_key_hash: &ByteBuf) -> bool {
+        self.is_locked_with(pub_key_hash)
+    }
 
-        self.pub_key_hash = pub_key_hash;
+    pub fn lock(&mut self, address: &ByteBuf) -> Result<(), ChainError> {
+        self.script = ScriptType::P2PKH(Wallet::address_to_pub_key_hash(address)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "1MBnzAR1oDnakY5fKLWvDeFD87PhPH9q1f";
+    /// `ADDRESS` with one character changed, its checksum no longer matches
+    const MUTATED: &str = "1MBnzAR1oDnakY5fKLWvDeFD87PhPH9q1g";
+
+    fn address(wallet: &Wallet) -> String {
+        String::from_utf8(wallet.address().into_vec()).unwrap()
+    }
+
+    /// Transaction signed by `signer` spending output 0 of `prev` with `signer`'s key
+    fn spend(prev: &Transaction, signer: &Wallet) -> Result<Transaction, ChainError> {
+        let mut tx = Transaction {
+            id: ByteBuf::new(),
+            vin: vec![TXInput {
+                txid: prev.id.clone(),
+                vout: Some(0),
+                signature: ByteBuf::new(),
+                pub_key: signer.public_key.clone(),
+            }],
+            vout: vec![TXOutput::new(prev.vout[0].value, &address(signer))?],
+        };
+        tx.set_id();
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+        tx.sign(&signer.signing_key()?, &prev_txs, 0)?;
+        Ok(tx)
+    }
+
+    #[test]
+    fn spending_output_locked_to_another_wallet_fails_verification() -> Result<(), ChainError> {
+        let alice = Wallet::new();
+        let mallory = Wallet::new();
+        let prev = Transaction::new_coinbase_tx(&address(&alice), "", Transaction::SUBSIDY, 0)?;
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+
+        assert!(spend(&prev, &alice)?.verify(&prev_txs, 0));
+        // Validly signed, but by a key that does not hash to the output's owner
+        assert!(!spend(&prev, &mallory)?.verify(&prev_txs, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_wallet_spends_its_outputs() -> Result<(), ChainError> {
+        let alice = Wallet::new_compressed();
+        let prev = Transaction::new_coinbase_tx(&address(&alice), "", Transaction::SUBSIDY, 0)?;
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+
+        assert!(spend(&prev, &alice)?.verify(&prev_txs, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn high_s_variant_of_valid_signature_is_rejected() -> Result<(), ChainError> {
+        let alice = Wallet::new();
+        let prev = Transaction::new_coinbase_tx(&address(&alice), "", Transaction::SUBSIDY, 0)?;
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+        let mut tx = spend(&prev, &alice)?;
+        assert!(tx.verify(&prev_txs, 0));
+
+        let signature = Signature::from_slice(&tx.vin[0].signature)?;
+        let (r, s) = signature.split_scalars();
+        let high_s = Signature::from_scalars(r, -s)?;
+        assert!(high_s.normalize_s().is_some());
+        tx.vin[0].signature = ByteBuf::from(high_s.to_bytes().to_vec());
+
+        // Still a valid ECDSA signature of the same digest, only its form is refused
+        let digest = tx.trimmed_copy().signing_digest(0, &prev_txs, 0)?;
+        assert!(Wallet::verify_data(
+            &alice.public_key,
+            &digest,
+            &tx.vin[0].signature
+        ));
+        assert!(!tx.verify(&prev_txs, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn signing_does_not_change_id() -> Result<(), ChainError> {
+        let alice = Wallet::new();
+        let prev = Transaction::new_coinbase_tx(&address(&alice), "", Transaction::SUBSIDY, 0)?;
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+        let mut tx = spend(&prev, &alice)?;
+        let id = tx.id.clone();
+        let signature = tx.vin[0].signature.clone();
+
+        tx.sign(&alice.signing_key()?, &prev_txs, 7)?;
+
+        assert_ne!(tx.vin[0].signature, signature);
+        assert_eq!(tx.hash(), id);
+        assert_eq!(tx.id, id);
+        Ok(())
+    }
+
+    #[test]
+    fn signature_for_one_chain_id_fails_on_another() -> Result<(), ChainError> {
+        let alice = Wallet::new();
+        let prev = Transaction::new_coinbase_tx(&address(&alice), "", Transaction::SUBSIDY, 0)?;
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+        let mut tx = spend(&prev, &alice)?;
+        tx.sign(&alice.signing_key()?, &prev_txs, 7)?;
+
+        assert!(tx.verify(&prev_txs, 7));
+        assert!(!tx.verify(&prev_txs, 8));
+        assert!(!tx.verify(&prev_txs, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn input_and_output_totals_give_fee() -> Result<(), ChainError> {
+        let alice = Wallet::new();
+        let prev = Transaction::new_coinbase_tx(&address(&alice), "", Transaction::SUBSIDY, 0)?;
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+        let mut tx = spend(&prev, &alice)?;
+        tx.vout[0].value = Transaction::SUBSIDY - 3;
+        tx.vout.push(TXOutput::new(1, ADDRESS)?);
+
+        assert_eq!(tx.total_input_value(&prev_txs)?, Transaction::SUBSIDY);
+        assert_eq!(tx.total_output_value(), Transaction::SUBSIDY - 2);
+        assert_eq!(tx.fee(&prev_txs)?, 2);
+        assert_eq!(prev.total_input_value(&prev_txs)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn dangling_inputs_fail_to_total() -> Result<(), ChainError> {
+        let alice = Wallet::new();
+        let prev = Transaction::new_coinbase_tx(&address(&alice), "", Transaction::SUBSIDY, 0)?;
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+        let mut tx = spend(&prev, &alice)?;
+
+        tx.vin[0].vout = Some(prev.vout.len());
+        assert!(matches!(
+            tx.total_input_value(&prev_txs),
+            Err(ChainError::MissingPrevOutput { vout: Some(vout), .. }) if vout == prev.vout.len()
+        ));
+        assert!(matches!(
+            tx.total_input_value(&HashMap::new()),
+            Err(ChainError::MissingPrevTx { txid }) if txid == prev.id
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn amounts_near_u64_max_overflow_instead_of_wrapping() -> Result<(), ChainError> {
+        assert_eq!(checked_sum([u64::MAX - 1, 1])?, u64::MAX);
+        assert!(matches!(
+            checked_sum([u64::MAX, 1]),
+            Err(ChainError::AmountOverflow)
+        ));
+
+        // Inputs spending two outputs of `u64::MAX` between them overflow
+        let alice = Wallet::new();
+        let mut prev = Transaction::new_coinbase_tx(&address(&alice), "", u64::MAX, 0)?;
+        prev.vout.push(TXOutput::new(1, &address(&alice))?);
+        let mut tx = spend(&prev, &alice)?;
+        tx.vin.push(TXInput {
+            vout: Some(1),
+            ..tx.vin[0].clone()
+        });
+        let prev_txs = HashMap::from([(prev.id.clone(), prev)]);
+        assert!(matches!(tx.fee(&prev_txs), Err(ChainError::AmountOverflow)));
+        Ok(())
+    }
+
+    #[test]
+    fn json_writes_byte_fields_as_hex_and_reads_them_back() -> Result<(), ChainError> {
+        let alice = Wallet::new();
+        let prev = Transaction::new_coinbase_tx(&address(&alice), "", Transaction::SUBSIDY, 0)?;
+        let tx = spend(&prev, &alice)?;
+
+        let json = serde_json::to_value(&tx)?;
+        assert_eq!(json["id"], hex::encode(&tx.id));
+        assert_eq!(json["vin"][0]["txid"], hex::encode(&prev.id));
+        let back: Transaction = serde_json::from_value(json.clone())?;
+        assert_eq!(back.id, tx.id);
+        assert_eq!(serde_json::to_value(&back)?, json);
+        // Bincode keeps the raw bytes
+        let raw: Transaction = bincode::deserialize(&bincode::serialize(&tx)?)?;
+        assert_eq!(raw.vin[0].signature, tx.vin[0].signature);
+        Ok(())
+    }
+
+    #[test]
+    fn checksum_tells_valid_address_from_mutated_one() {
+        assert!(Wallet::validate_address(&ByteBuf::from(ADDRESS)));
+        assert!(!Wallet::validate_address(&ByteBuf::from(MUTATED)));
+    }
+
+    #[test]
+    fn outputs_refuse_to_lock_to_invalid_address() -> Result<(), ChainError> {
+        let output = TXOutput::new(5, ADDRESS)?;
+        let pub_key_hash = Wallet::address_to_pub_key_hash(&ByteBuf::from(ADDRESS))?;
+        assert!(output.is_locked_with(&pub_key_hash));
+
+        assert!(matches!(
+            TXOutput::new(5, MUTATED),
+            Err(ChainError::InvalidAddress)
+        ));
+        assert!(matches!(
+            Transaction::new_coinbase_tx(MUTATED, "", Transaction::SUBSIDY, 0),
+            Err(ChainError::InvalidAddress)
+        ));
+        Ok(())
     }
 }
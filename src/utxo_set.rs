@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sled::{
+    transaction::{ConflictableTransactionResult, TransactionalTree},
+    Db, Tree,
+};
+
+use crate::{
+    block::Block,
+    blockchain::Blockchain,
+    error::{abort, ChainError},
+    transaction::{checked_sum, TXOutput, Transaction},
+    Blockchainable,
+};
+
+/// Unspent outputs of a single transaction keyed by their index in `vout`
+#[derive(Serialize, Deserialize, Default)]
+pub struct TXOutputs(pub BTreeMap<usize, TXOutput>);
+
+/// Set of all currently unspent outputs, kept in its own tree next to blocks
+pub struct UTXOSet {
+    tree: Tree,
+}
+
+impl UTXOSet {
+    pub const UTXO_BUCKET: &'static str = "utxos";
+
+    pub fn open(db: &Db) -> Result<Self, ChainError> {
+        Ok(UTXOSet {
+            tree: db.open_tree(Self::UTXO_BUCKET)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn clear(&self) -> Result<(), ChainError> {
+        Ok(self.tree.clear()?)
+    }
+
+    /// Drops the whole set and rebuilds it from every block in chain in one atomic batch,
+    /// returns number of utxos
+    pub fn reindex<T>(&self, blockchain: &Blockchain<T>) -> Result<usize, ChainError>
+    where
+        T: Blockchainable,
+    {
+        let mut spent: HashSet<(ByteBuf, usize)> = HashSet::new();
+        let mut unspent: HashMap<ByteBuf, TXOutputs> = HashMap::new();
+
+        // Walking from the tip, spending inputs are always seen before outputs they spend
+        let mut hash = blockchain.last_hash()?;
+        while let Some(block) = blockchain.read_block(&hash)? {
+            for tx in block.transactions.iter().rev() {
+                for (idx, vout) in tx.vout.iter().enumerate() {
+                    if vout.is_spendable() && !spent.contains(&(tx.id.clone(), idx)) {
+                        unspent
+                            .entry(tx.id.clone())
+                            .or_default()
+                            .0
+                            .insert(idx, vout.clone());
+                    }
+                }
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        if let Some(vout) = vin.vout {
+                            spent.insert((vin.txid.clone(), vout));
+                        }
+                    }
+                }
+            }
+            match block.previous_block_hash {
+                Some(prev) => hash = prev,
+                None => break,
+            }
+        }
+
+        let mut batch = sled::Batch::default();
+        for key in self.tree.iter().keys() {
+            batch.remove(key?);
+        }
+        let mut count = 0;
+        for (txid, outs) in unspent {
+            count += outs.0.len();
+            batch.insert(txid.to_vec(), serialize(&outs)?);
+        }
+        self.tree.apply_batch(batch)?;
+        Ok(count)
+    }
+
+    /// Applies newly added block, removing outputs it spends and adding ones it creates.
+    /// Either the whole block is applied or nothing is
+    pub fn update<T>(&self, block: &Block<T>) -> Result<(), ChainError> {
+        Ok(self.tree.transaction(|utxos| Self::apply(utxos, block))?)
+    }
+
+    /// `update` as part of a transaction spanning other trees, such as storing the block
+    pub(crate) fn apply<T>(
+        utxos: &TransactionalTree,
+        block: &Block<T>,
+    ) -> ConflictableTransactionResult<(), ChainError> {
+        for tx in &block.transactions {
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    let mut outs: TXOutputs = match utxos.get(&vin.txid)? {
+                        Some(outs) => deserialize(&outs).map_err(abort)?,
+                        None => TXOutputs::default(),
+                    };
+                    if let Some(vout) = vin.vout {
+                        outs.0.remove(&vout);
+                    }
+                    if outs.0.is_empty() {
+                        utxos.remove(vin.txid.as_ref())?;
+                    } else {
+                        utxos.insert(vin.txid.as_ref(), serialize(&outs).map_err(abort)?)?;
+                    }
+                }
+            }
+            Self::insert_tx(utxos, tx)?;
+        }
+        Ok(())
+    }
+
+    /// Adds spendable outputs of `tx`, unspendable ones never enter the set
+    fn insert_tx(
+        utxos: &TransactionalTree,
+        tx: &Transaction,
+    ) -> ConflictableTransactionResult<(), ChainError> {
+        let outs = TXOutputs(
+            tx.vout
+                .iter()
+                .cloned()
+                .enumerate()
+                .filter(|(_, out)| out.is_spendable())
+                .collect(),
+        );
+        if !outs.0.is_empty() {
+            utxos.insert(tx.id.as_ref(), serialize(&outs).map_err(abort)?)?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, txid: &ByteBuf) -> Result<TXOutputs, ChainError> {
+        match self.tree.get(txid)? {
+            Some(outs) => Ok(deserialize(&outs)?),
+            None => Ok(TXOutputs::default()),
+        }
+    }
+
+    /// Whether output `vout` of transaction `txid` is unspent
+    pub fn contains(&self, txid: &ByteBuf, vout: usize) -> Result<bool, ChainError> {
+        Ok(self.get(txid)?.0.contains_key(&vout))
+    }
+
+    /// Sum of values of every unspent output, all coins that can still be spent
+    pub fn total_value(&self) -> Result<u64, ChainError> {
+        checked_sum(self.all()?.iter().map(|(_, _, out)| out.value))
+    }
+
+    /// Every unspent output as `(txid, index, output)`
+    fn all(&self) -> Result<Vec<(ByteBuf, usize, TXOutput)>, ChainError> {
+        let mut all = Vec::new();
+        for entry in self.tree.iter() {
+            let (txid, outs) = entry?;
+            let outs: TXOutputs = deserialize(&outs)?;
+            let txid = ByteBuf::from(txid.to_vec());
+            all.extend(
+                outs.0
+                    .into_iter()
+                    .map(|(idx, out)| (txid.clone(), idx, out)),
+            );
+        }
+        Ok(all)
+    }
+
+    pub fn find_utxo(&self, pub_key_hash: &ByteBuf) -> Result<Vec<TXOutput>, ChainError> {
+        Ok(self
+            .all()?
+            .into_iter()
+            .map(|(_, _, out)| out)
+            .filter(|out| out.is_locked_with(pub_key_hash))
+            .collect())
+    }
+
+    /// Every unspent output locked with `pub_key_hash` as `(txid, index, output)`
+    pub fn find_utxo_refs(
+        &self,
+        pub_key_hash: &ByteBuf,
+    ) -> Result<Vec<(ByteBuf, usize, TXOutput)>, ChainError> {
+        Ok(self
+            .all()?
+            .into_iter()
+            .filter(|(_, _, out)| out.can_be_unlocked_with(pub_key_hash))
+            .collect())
+    }
+
+    /// Collects outputs locked with `pub_key_hash`, except `exclude`d ones, until they add up
+    /// to at least `value`
+    pub fn find_spendable_outputs(
+        &self,
+        pub_key_hash: &ByteBuf,
+        value: u64,
+        exclude: &HashSet<(ByteBuf, usize)>,
+    ) -> Result<(u64, HashMap<ByteBuf, Vec<usize>>), ChainError> {
+        let mut unspent_outputs: HashMap<ByteBuf, Vec<usize>> = HashMap::new();
+        let mut all = 0;
+
+        for (txid, idx, out) in self.all()? {
+            if out.is_locked_with(pub_key_hash) && !exclude.contains(&(txid.clone(), idx)) {
+                all = checked_sum([all, out.value])?;
+                unspent_outputs.entry(txid).or_default().push(idx);
+                if all >= value {
+                    break;
+                }
+            }
+        }
+
+        Ok((all, unspent_outputs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::{Address, Wallet, Wallets};
+
+    /// Stores `wallet` in the wallets file of `blockchain`, returning its address
+    fn store_wallet(
+        blockchain: &Blockchain<String>,
+        wallet: &Wallet,
+    ) -> Result<Address, ChainError> {
+        Wallets::insert(&blockchain.wallets_file, wallet)?;
+        Ok(Address::from_pub_key_hash(&Wallet::hash_pub_key(
+            &wallet.public_key,
+        )))
+    }
+
+    /// Every unspent output as sorted `(txid, index, value)`
+    fn snapshot(utxo_set: &UTXOSet) -> Result<Vec<(ByteBuf, usize, u64)>, ChainError> {
+        let mut all: Vec<_> = utxo_set
+            .all()?
+            .into_iter()
+            .map(|(txid, idx, out)| (txid, idx, out.value))
+            .collect();
+        all.sort();
+        Ok(all)
+    }
+
+    #[test]
+    fn incremental_updates_match_full_reindex() -> Result<(), ChainError> {
+        let wallet = Wallet::new();
+        let address = Address::from_pub_key_hash(&Wallet::hash_pub_key(&wallet.public_key));
+        let mut blockchain = Blockchain::<String>::new_test(address.as_str())?;
+        let alice = store_wallet(&blockchain, &wallet)?;
+        let bob = store_wallet(&blockchain, &Wallet::new())?;
+        blockchain.miner_address = Some(alice.to_string());
+        blockchain.mine_block()?;
+        blockchain.send(&alice, &bob, 7, 1)?;
+        blockchain.mine_block()?;
+        blockchain.send(&bob, &alice, 3, 1)?;
+        blockchain.send(&alice, &bob, 2, 2)?;
+        blockchain.mine_block()?;
+
+        let utxo_set = blockchain.utxo_set()?;
+        let incremental = snapshot(&utxo_set)?;
+        let count = utxo_set.reindex(&blockchain)?;
+
+        assert_eq!(snapshot(&utxo_set)?, incremental);
+        assert_eq!(count, incremental.len());
+        assert_eq!(blockchain.balance_at(&bob)?, 7 - 3 - 1 + 2);
+        Ok(())
+    }
+
+    #[test]
+    fn reindex_replaces_stale_entries() -> Result<(), ChainError> {
+        let wallet = Wallet::new();
+        let address = Address::from_pub_key_hash(&Wallet::hash_pub_key(&wallet.public_key));
+        let blockchain = Blockchain::<String>::new_test(address.as_str())?;
+        let utxo_set = blockchain.utxo_set()?;
+        let indexed = snapshot(&utxo_set)?;
+        utxo_set
+            .tree
+            .insert(b"stale", serialize(&TXOutputs::default())?)?;
+
+        assert_eq!(utxo_set.reindex(&blockchain)?, indexed.len());
+        assert_eq!(snapshot(&utxo_set)?, indexed);
+        assert!(!utxo_set.tree.contains_key(b"stale")?);
+        Ok(())
+    }
+}
@@ -2,23 +2,63 @@ use std::{
     collections::HashMap,
     fmt::Display,
     fs::File,
-    io::{Read, Write},
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
 };
 
 use bincode::{deserialize, serialize};
+use bip39::Mnemonic;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
 use p256::{
-    ecdsa::{SigningKey, VerifyingKey},
-    elliptic_curve::rand_core::OsRng,
+    ecdsa::{
+        signature::{Signer, Verifier},
+        Signature, SigningKey, VerifyingKey,
+    },
+    elliptic_curve::rand_core::{OsRng, RngCore},
 };
+use pbkdf2::pbkdf2_hmac;
 use ripemd::Ripemd160;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use sha2::{Digest, Sha256};
 
-const WALLETS_FILE: &'static str = "wallets.dat";
+use crate::error::ChainError;
+
+/// Wallets store format
+///
+/// Wallets live in the `WALLETS_BUCKET` tree of a sled database next to the legacy wallets
+/// file, keyed by address. Each value is either:
+/// Plaintext: bincode serialized `Wallet`.
+/// Encrypted: `ENCRYPTED_MAGIC`, format byte `ENCRYPTED_FORMAT`, `SALT_LEN` bytes of PBKDF2 salt,
+/// `NONCE_LEN` bytes of ChaCha20-Poly1305 nonce and the encrypted bincode serialized `Wallet`.
+/// A plaintext value can never start with the magic, it would mean a key of billions of bytes.
+///
+/// Legacy `wallets.dat` files hold the whole bincode serialized `Wallets` in the same two
+/// formats and are imported into an empty store when it is opened.
+const ENCRYPTED_MAGIC: &[u8] = b"RCWE";
+const ENCRYPTED_FORMAT: u8 = 0x01;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const PBKDF2_ROUNDS: u32 = 100_000;
+/// HKDF info prefix for keys derived by `Wallet::from_seed`, followed by index and attempt
+const SEED_KEY_INFO: &[u8] = b"rust-chain wallet key";
+/// Key in the default tree of the wallets store set once the legacy file has been imported
+const LEGACY_IMPORTED: &[u8] = b"legacy_imported";
+/// Entropy of mnemonics made by `Wallet::generate_mnemonic`, 12 words
+const MNEMONIC_ENTROPY_LEN: usize = 16;
+
+/// Wallet stores this process opened, by path. Sled keeps a store locked until its flusher
+/// thread lets go of it, a moment after it is dropped, so opening it anew right away can fail
+static STORES: OnceLock<Mutex<HashMap<PathBuf, sled::Db>>> = OnceLock::new();
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Wallet {
+    /// Sec1 encoded point, its first byte tells whether it is compressed
     #[serde(with = "serde_bytes")]
     pub public_key: ByteBuf,
     #[serde(with = "serde_bytes")]
@@ -28,27 +68,152 @@ pub struct Wallet {
 impl Wallet {
     pub const VERSION: [u8; 1] = [0x0];
     pub const CHECKSUM_LEN: usize = 4;
+    /// Version byte of exported private keys, the one Bitcoin uses for mainnet keys
+    pub const WIF_VERSION: [u8; 1] = [0x80];
+    /// Length of a raw big endian P-256 private key scalar
+    pub const PRIVATE_KEY_LEN: usize = 32;
+    /// Length of a RIPEMD-160 pub key hash, what an address encodes
+    pub const PUB_KEY_HASH_LEN: usize = 20;
+    /// Byte following the key in WIFs of wallets with a compressed public key, as in Bitcoin
+    pub const WIF_COMPRESSED_FLAG: u8 = 0x01;
+    /// Sec1 tag of an uncompressed point, compressed ones start with 0x02 or 0x03
+    const SEC1_UNCOMPRESSED_TAG: u8 = 0x04;
 
     pub fn new() -> Self {
-        let private_key = SigningKey::random(&mut OsRng);
-        let public_key = VerifyingKey::from(&private_key);
+        Self::from_signing_key(&SigningKey::random(&mut OsRng), false)
+    }
+
+    /// Like `new`, but the public key is stored compressed, 33 bytes instead of 65. The same
+    /// key compressed hashes to a different address than uncompressed
+    pub fn new_compressed() -> Self {
+        Self::from_signing_key(&SigningKey::random(&mut OsRng), true)
+    }
+
+    /// Whether `public_key` is stored in compressed sec1 form
+    pub fn is_compressed(&self) -> bool {
+        self.public_key.first() != Some(&Self::SEC1_UNCOMPRESSED_TAG)
+    }
+
+    /// Wallet with the `index`th key derived from `seed` with HKDF-SHA256, always the same
+    /// key for the same seed and index
+    pub fn from_seed(seed: &[u8], index: u32) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(None, seed);
+        // Derived bytes outside the curve order are not a valid key, retry with the next attempt
+        (0u32..)
+            .find_map(|attempt| {
+                let info = [SEED_KEY_INFO, &index.to_be_bytes(), &attempt.to_be_bytes()].concat();
+                let mut bytes = [0u8; Self::PRIVATE_KEY_LEN];
+                hkdf.expand(&info, &mut bytes)
+                    .expect("32 bytes is a valid HKDF-SHA256 output length!");
+                Self::from_private_key_bytes(&bytes).ok()
+            })
+            .expect("No valid key derived from seed!")
+    }
+
+    /// Fresh random 12 word BIP39 phrase to back up and restore wallets with `from_mnemonic`
+    pub fn generate_mnemonic() -> String {
+        let mut entropy = [0u8; MNEMONIC_ENTROPY_LEN];
+        OsRng.fill_bytes(&mut entropy);
+        Mnemonic::from_entropy(&entropy)
+            .expect("16 bytes is a valid mnemonic entropy length!")
+            .to_string()
+    }
+
+    /// Wallet with the `index`th key derived from the seed of BIP39 `phrase`
+    pub fn from_mnemonic(phrase: &str, index: u32) -> Result<Self, ChainError> {
+        let mnemonic = Mnemonic::parse(phrase).map_err(|_| ChainError::InvalidMnemonic)?;
+        Ok(Self::from_seed(&mnemonic.to_seed(""), index))
+    }
+
+    /// Private key as base58check `WIF_VERSION` byte followed by the raw 32 byte scalar and,
+    /// for compressed wallets, `WIF_COMPRESSED_FLAG`
+    pub fn export_wif(&self) -> String {
+        let mut versioned = Self::WIF_VERSION.to_vec();
+        versioned.extend_from_slice(&self.private_key);
+        if self.is_compressed() {
+            versioned.push(Self::WIF_COMPRESSED_FLAG);
+        }
+        let checksum = Self::checksum(&versioned);
+        versioned.extend(checksum);
+        bs58::encode(versioned)
+            .with_alphabet(bs58::Alphabet::BITCOIN)
+            .into_string()
+    }
+
+    /// Wallet of a private key made by `export_wif`, public key is derived from it and
+    /// compressed when the WIF carries `WIF_COMPRESSED_FLAG`
+    pub fn import_wif(wif: &str) -> Result<Self, ChainError> {
+        let decoded = bs58::decode(wif)
+            .with_alphabet(bs58::Alphabet::BITCOIN)
+            .into_vec()
+            .map_err(|_| ChainError::InvalidWif {
+                reason: "not base58",
+            })?;
+        if decoded.len() <= Self::WIF_VERSION.len() + Self::CHECKSUM_LEN {
+            return Err(ChainError::InvalidWif {
+                reason: "too short",
+            });
+        }
+
+        let (payload, checksum) = decoded.split_at(decoded.len() - Self::CHECKSUM_LEN);
+        if Self::checksum(payload) != checksum {
+            return Err(ChainError::InvalidWif {
+                reason: "bad checksum",
+            });
+        }
+        let private_key =
+            payload
+                .strip_prefix(&Self::WIF_VERSION)
+                .ok_or(ChainError::InvalidWif {
+                    reason: "wrong version byte",
+                })?;
+        match private_key.split_last() {
+            Some((&Self::WIF_COMPRESSED_FLAG, key)) if key.len() == Self::PRIVATE_KEY_LEN => {
+                Self::from_private_key(key, true)
+            }
+            _ => Self::from_private_key(private_key, false),
+        }
+    }
+
+    /// Wallet of a raw 32 byte private key scalar, as stored in `private_key`, public key
+    /// is derived from it. Zero and scalars outside the curve order are rejected
+    pub fn from_private_key_bytes(bytes: &[u8]) -> Result<Self, ChainError> {
+        Self::from_private_key(bytes, false)
+    }
+
+    /// `from_private_key_bytes` storing the public key `compressed` or not
+    fn from_private_key(bytes: &[u8], compressed: bool) -> Result<Self, ChainError> {
+        if bytes.len() != Self::PRIVATE_KEY_LEN {
+            return Err(ChainError::InvalidPrivateKey {
+                reason: "not 32 bytes",
+            });
+        }
+        let private_key =
+            SigningKey::from_slice(bytes).map_err(|_| ChainError::InvalidPrivateKey {
+                reason: "zero or not below the curve order",
+            })?;
+        Ok(Self::from_signing_key(&private_key, compressed))
+    }
+
+    fn from_signing_key(private_key: &SigningKey, compressed: bool) -> Self {
+        let public_key = VerifyingKey::from(private_key);
         Self {
-            public_key: ByteBuf::from(public_key.to_encoded_point(false).to_bytes()),
+            public_key: ByteBuf::from(public_key.to_encoded_point(compressed).to_bytes()),
             private_key: ByteBuf::from(private_key.to_bytes().to_vec()),
         }
     }
 
     pub fn address(&self) -> ByteBuf {
-        let hash_pub = Self::hash_pub_key(&self.public_key);
+        Self::address_from_pub_key_hash(&Self::hash_pub_key(&self.public_key))
+    }
 
+    /// Base58check address of a pub key hash, inverse of `address_to_pub_key_hash`
+    pub fn address_from_pub_key_hash(pub_key_hash: &ByteBuf) -> ByteBuf {
         let mut versioned = ByteBuf::from(Self::VERSION);
-        versioned.append(&mut hash_pub.to_vec());
+        versioned.append(&mut pub_key_hash.to_vec());
 
-        let checksum = Sha256::new()
-            .chain_update(&Sha256::new().chain_update(&versioned).finalize())
-            .finalize();
-
-        versioned.append(&mut checksum[..Self::CHECKSUM_LEN].to_vec());
+        let mut checksum = Self::checksum(&versioned);
+        versioned.append(&mut checksum);
         ByteBuf::from(
             bs58::encode(versioned)
                 .with_alphabet(bs58::Alphabet::BITCOIN)
@@ -56,52 +221,403 @@ impl Wallet {
         )
     }
 
+    pub fn signing_key(&self) -> Result<SigningKey, ChainError> {
+        Ok(SigningKey::from_slice(&self.private_key)?)
+    }
+
+    pub fn verifying_key(&self) -> Result<VerifyingKey, ChainError> {
+        Ok(VerifyingKey::from_sec1_bytes(&self.public_key)?)
+    }
+
+    /// Fixed size ECDSA signature of `msg` made with this wallet's private key
+    pub fn sign_data(&self, msg: &[u8]) -> ByteBuf {
+        let signature: Signature = self
+            .signing_key()
+            .expect("Wallet private key is not valid!")
+            .sign(msg);
+        ByteBuf::from(signature.to_bytes().to_vec())
+    }
+
+    /// Checks `sig` is a signature of `msg` made with the private key of sec1 encoded `pub_key`,
+    /// compressed or not
+    pub fn verify_data(pub_key: &ByteBuf, msg: &[u8], sig: &ByteBuf) -> bool {
+        let Ok(public_key) = VerifyingKey::from_sec1_bytes(pub_key) else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_slice(sig) else {
+            return false;
+        };
+        public_key.verify(msg, &signature).is_ok()
+    }
+
+    /// Checks base58 decoding, version byte and checksum of an address
+    pub fn validate_address(address: &ByteBuf) -> bool {
+        Self::address_to_pub_key_hash(address).is_ok()
+    }
+
+    /// Pub key hash encoded in a base58check address, `InvalidAddress` unless decoding,
+    /// version byte and checksum are all valid
+    pub fn address_to_pub_key_hash(address: &ByteBuf) -> Result<ByteBuf, ChainError> {
+        let decoded = bs58::decode(address)
+            .with_alphabet(bs58::Alphabet::BITCOIN)
+            .into_vec()
+            .map_err(|_| ChainError::InvalidAddress)?;
+        if decoded.len() <= Self::VERSION.len() + Self::CHECKSUM_LEN {
+            return Err(ChainError::InvalidAddress);
+        }
+
+        let (payload, checksum) = decoded.split_at(decoded.len() - Self::CHECKSUM_LEN);
+        match payload.strip_prefix(&Self::VERSION) {
+            Some(pub_key_hash) if Self::checksum(payload) == checksum => {
+                Ok(ByteBuf::from(pub_key_hash))
+            }
+            _ => Err(ChainError::InvalidAddress),
+        }
+    }
+
+    /// First `CHECKSUM_LEN` bytes of double SHA-256
+    fn checksum(payload: &[u8]) -> Vec<u8> {
+        let hash = Sha256::new()
+            .chain_update(Sha256::new().chain_update(payload).finalize())
+            .finalize();
+        hash[..Self::CHECKSUM_LEN].to_vec()
+    }
+
     pub fn hash_pub_key(public_key: &ByteBuf) -> ByteBuf {
-        let sha_public = Sha256::new().chain_update(&public_key).finalize();
-        let ripemd_public = Ripemd160::new().chain_update(&sha_public).finalize();
+        let sha_public = Sha256::new().chain_update(public_key).finalize();
+        let ripemd_public = Ripemd160::new().chain_update(sha_public).finalize();
         ByteBuf::from(ripemd_public.to_vec())
     }
 }
 
+impl Default for Wallet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Base58check address whose version byte and checksum were checked on construction
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Address {
+    encoded: String,
+    pub_key_hash: ByteBuf,
+}
+
+impl Address {
+    /// Address paying to `pub_key_hash`
+    pub fn from_pub_key_hash(pub_key_hash: &ByteBuf) -> Self {
+        let encoded = Wallet::address_from_pub_key_hash(pub_key_hash);
+        Address {
+            encoded: String::from_utf8(encoded.into_vec()).expect("Base58 is always ascii"),
+            pub_key_hash: pub_key_hash.clone(),
+        }
+    }
+
+    /// Pub key hash outputs paying this address are locked to
+    pub fn pub_key_hash(&self) -> ByteBuf {
+        self.pub_key_hash.clone()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.encoded
+    }
+}
+
+impl std::str::FromStr for Address {
+    type Err = ChainError;
+
+    /// `InvalidAddress` unless base58 decoding, version byte and checksum are all valid
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let pub_key_hash = Wallet::address_to_pub_key_hash(&ByteBuf::from(address))?;
+        Ok(Address {
+            encoded: address.to_string(),
+            pub_key_hash,
+        })
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.encoded)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
-pub struct Wallets(HashMap<ByteBuf, Wallet>);
+pub struct Wallets {
+    wallets: HashMap<ByteBuf, Wallet>,
+    /// Legacy wallets file, the store lives next to it with a `kv` extension
+    #[serde(skip)]
+    path: PathBuf,
+}
 
 impl Wallets {
-    pub fn save_wallet(wallet: &Wallet) {
-        let mut wallets = Self::fetch_wallets();
+    pub const WALLETS_FILE: &'static str = "wallets.dat";
+    pub const WALLETS_BUCKET: &'static str = "wallets";
+
+    /// Stores a single wallet in the default store
+    pub fn save_wallet(wallet: &Wallet) -> Result<(), ChainError> {
+        Self::insert(Self::WALLETS_FILE, wallet)
+    }
+
+    /// Adds `wallet` to the store of `path` in plaintext with a single insert. `Decryption`
+    /// when the store holds encrypted wallets, which `open_encrypted` and `save_encrypted`
+    /// add to instead, so it never mixes both formats
+    pub fn insert(path: impl AsRef<Path>, wallet: &Wallet) -> Result<(), ChainError> {
+        let store = Self::store(path.as_ref(), None)?;
+        for entry in store.iter().values() {
+            if entry?.starts_with(ENCRYPTED_MAGIC) {
+                return Err(ChainError::Decryption);
+            }
+        }
+        store.insert(wallet.address(), Self::encode_entry(wallet, None)?)?;
+        store.flush()?;
+        Ok(())
+    }
+
+    /// Forgets the wallet of `address` in the default store, returns whether there was one
+    pub fn remove_wallet(address: &ByteBuf) -> Result<bool, ChainError> {
+        Self::remove(Self::WALLETS_FILE, address)
+    }
+
+    /// Removes the wallet of `address` from the store of `path` with a single removal,
+    /// returns whether there was one
+    pub fn remove(path: impl AsRef<Path>, address: &ByteBuf) -> Result<bool, ChainError> {
+        let store = Self::store(path.as_ref(), None)?;
+        let removed = store.remove(address)?.is_some();
+        store.flush()?;
+        Ok(removed)
+    }
+
+    pub fn fetch_wallets() -> Result<Self, ChainError> {
+        Self::open(Self::WALLETS_FILE)
+    }
+
+    /// Reads plaintext wallets of the store of `path`, empty set when there is none yet.
+    /// `Decryption` when they are encrypted and need `open_encrypted`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ChainError> {
+        let path = path.as_ref();
+        let store = Self::store(path, None)?;
+        Self::read_store(&store, path, None)
+    }
+
+    /// Like `open`, but never writes: a legacy file not imported yet is only read and a
+    /// missing store is not created
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self, ChainError> {
+        let path = path.as_ref();
+        let store_path = path.with_extension("kv");
+        if store_path.exists() {
+            let db = Self::open_db(&store_path)?;
+            let has_store = db
+                .tree_names()
+                .iter()
+                .any(|name| name.as_ref() == Self::WALLETS_BUCKET.as_bytes());
+            if has_store {
+                let store = db.open_tree(Self::WALLETS_BUCKET)?;
+                if db.contains_key(LEGACY_IMPORTED)? || !store.is_empty() {
+                    return Self::read_store(&store, path, None);
+                }
+            }
+        }
+        let mut wallets = Self::read_legacy(path, None)?.unwrap_or_else(|| Wallets {
+            wallets: HashMap::new(),
+            path: PathBuf::new(),
+        });
+        wallets.path = path.to_path_buf();
+        Ok(wallets)
+    }
+
+    pub fn add(&mut self, wallet: &Wallet) {
+        self.wallets.insert(wallet.address(), wallet.clone());
+    }
+
+    /// Writes wallets in plaintext to the store they were opened from, replacing what it held
+    pub fn save(&self) -> Result<(), ChainError> {
+        self.write_store(None)
+    }
+
+    /// Like `save_wallet`, but every wallet is encrypted with a key derived from `passphrase`.
+    /// Plaintext wallets already stored are upgraded to encrypted ones.
+    pub fn save_wallet_encrypted(wallet: &Wallet, passphrase: &str) -> Result<(), ChainError> {
+        let mut wallets = Self::fetch_wallets_encrypted(passphrase)?;
+        wallets.add(wallet);
+        wallets.save_encrypted(passphrase)
+    }
 
-        let wallet_addr = wallet.address();
-        wallets.0.insert(wallet_addr, wallet.clone());
+    pub fn fetch_wallets_encrypted(passphrase: &str) -> Result<Self, ChainError> {
+        Self::open_encrypted(Self::WALLETS_FILE, passphrase)
+    }
+
+    /// Reads wallets of the store of `path` stored in either format, `passphrase` is only used
+    /// for encrypted ones
+    pub fn open_encrypted(path: impl AsRef<Path>, passphrase: &str) -> Result<Self, ChainError> {
+        let path = path.as_ref();
+        let store = Self::store(path, Some(passphrase))?;
+        Self::read_store(&store, path, Some(passphrase))
+    }
+
+    /// Writes wallets encrypted with `passphrase` to the store they were opened from
+    pub fn save_encrypted(&self, passphrase: &str) -> Result<(), ChainError> {
+        self.write_store(Some(passphrase))
+    }
+
+    /// Sled tree holding wallets for legacy file `path`, keyed by address. The legacy file is
+    /// imported into it once, if the tree is empty then
+    fn store(path: &Path, passphrase: Option<&str>) -> Result<sled::Tree, ChainError> {
+        let db = Self::open_db(&path.with_extension("kv"))?;
+        let store = db.open_tree(Self::WALLETS_BUCKET)?;
+        // Removing every wallet must not bring back the legacy ones
+        if db.contains_key(LEGACY_IMPORTED)? {
+            return Ok(store);
+        }
+        if store.is_empty() {
+            if let Some(legacy) = Self::read_legacy(path, passphrase)? {
+                let mut batch = sled::Batch::default();
+                for (address, wallet) in &legacy.wallets {
+                    batch.insert(address.to_vec(), Self::encode_entry(wallet, passphrase)?);
+                }
+                store.apply_batch(batch)?;
+            }
+        }
+        db.insert(LEGACY_IMPORTED, &[])?;
+        db.flush()?;
+        Ok(store)
+    }
+
+    /// Sled database at `path`, opened once per process and shared from then on
+    fn open_db(path: &Path) -> Result<sled::Db, ChainError> {
+        let mut stores = STORES
+            .get_or_init(Default::default)
+            .lock()
+            .expect("Wallet stores lock is poisoned!");
+        if let Some(db) = stores.get(path) {
+            return Ok(db.clone());
+        }
+        let db = sled::open(path)?;
+        stores.insert(path.to_path_buf(), db.clone());
+        Ok(db)
+    }
+
+    fn read_store(
+        store: &sled::Tree,
+        path: &Path,
+        passphrase: Option<&str>,
+    ) -> Result<Self, ChainError> {
+        let mut wallets = HashMap::new();
+        for entry in store.iter() {
+            let (address, wallet) = entry?;
+            wallets.insert(
+                ByteBuf::from(address.to_vec()),
+                Self::decode_entry(&wallet, passphrase)?,
+            );
+        }
+        Ok(Wallets {
+            wallets,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Atomically replaces everything in the store with these wallets
+    fn write_store(&self, passphrase: Option<&str>) -> Result<(), ChainError> {
+        let store = Self::store(&self.path, passphrase)?;
+        let mut batch = sled::Batch::default();
+        for entry in store.iter() {
+            let (address, _) = entry?;
+            if !self.wallets.contains_key(serde_bytes::Bytes::new(&address)) {
+                batch.remove(address);
+            }
+        }
+        for (address, wallet) in &self.wallets {
+            batch.insert(address.to_vec(), Self::encode_entry(wallet, passphrase)?);
+        }
+        store.apply_batch(batch)?;
+        store.flush()?;
+        Ok(())
+    }
+
+    /// Wallets of a `wallets.dat` written before the store existed, `None` when there is none
+    fn read_legacy(path: &Path, passphrase: Option<&str>) -> Result<Option<Self>, ChainError> {
+        let mut buffer = Vec::new();
+        match File::open(path) {
+            Ok(mut file) => file.read_to_end(&mut buffer)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let plaintext = match (buffer.starts_with(ENCRYPTED_MAGIC), passphrase) {
+            (false, _) => buffer,
+            (true, Some(passphrase)) => Self::decrypt(&buffer, passphrase)?,
+            (true, None) => return Err(ChainError::Decryption),
+        };
+        Ok(Some(deserialize(&plaintext)?))
+    }
+
+    fn encode_entry(wallet: &Wallet, passphrase: Option<&str>) -> Result<Vec<u8>, ChainError> {
+        let plaintext = serialize(wallet)?;
+        match passphrase {
+            Some(passphrase) => Self::encrypt(&plaintext, passphrase),
+            None => Ok(plaintext),
+        }
+    }
+
+    fn decode_entry(entry: &[u8], passphrase: Option<&str>) -> Result<Wallet, ChainError> {
+        if !entry.starts_with(ENCRYPTED_MAGIC) {
+            return Ok(deserialize(entry)?);
+        }
+        let passphrase = passphrase.ok_or(ChainError::Decryption)?;
+        Ok(deserialize(&Self::decrypt(entry, passphrase)?)?)
+    }
+
+    fn encrypt(plaintext: &[u8], passphrase: &str) -> Result<Vec<u8>, ChainError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = Self::cipher(passphrase, &salt)
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| ChainError::Encryption)?;
 
-        let mut file = File::create(WALLETS_FILE).expect("Open file error!");
-        file.write_all(&serialize(&wallets).expect("Serialization error!"))
-            .expect("File write error!");
-        file.flush().expect("File flush error!");
+        Ok([
+            ENCRYPTED_MAGIC,
+            &[ENCRYPTED_FORMAT],
+            &salt,
+            &nonce,
+            &ciphertext,
+        ]
+        .concat())
     }
 
-    pub fn fetch_wallets() -> Self {
-        if let Ok(ref mut file) = File::open(WALLETS_FILE) {
-            let mut buffer = Vec::new();
-            file.read_to_end(&mut buffer).expect("Read to end error!");
-            deserialize::<Wallets>(&buffer).expect("Deserialization error!")
-        } else {
-            Wallets(HashMap::new())
+    fn decrypt(encrypted: &[u8], passphrase: &str) -> Result<Vec<u8>, ChainError> {
+        let Some([ENCRYPTED_FORMAT, rest @ ..]) = encrypted.strip_prefix(ENCRYPTED_MAGIC) else {
+            return Err(ChainError::Decryption);
+        };
+        if rest.len() < SALT_LEN + NONCE_LEN {
+            return Err(ChainError::Decryption);
         }
+        let (salt, rest) = rest.split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        Self::cipher(passphrase, salt)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| ChainError::Decryption)
+    }
+
+    fn cipher(passphrase: &str, salt: &[u8]) -> ChaCha20Poly1305 {
+        let mut key = Key::default();
+        pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+        ChaCha20Poly1305::new(&key)
     }
 
     pub fn get(&self, address: &ByteBuf) -> Option<&Wallet> {
-        self.0.get(address)
+        self.wallets.get(address)
     }
 }
 
 impl Display for Wallets {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for (addr, wallet) in &self.0 {
+        for (addr, wallet) in &self.wallets {
             writeln!(f, "WALLET:")?;
             write!(
                 f,
                 "\tADDR: {}, ",
-                std::str::from_utf8(&addr).expect("Could not convert bytes to string!")
+                std::str::from_utf8(addr).expect("Could not convert bytes to string!")
             )?;
             write!(
                 f,
@@ -123,3 +639,272 @@ impl Display for Wallets {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_and_uncompressed_keys_differ_in_address_but_both_sign() -> Result<(), ChainError>
+    {
+        let uncompressed = Wallet::new();
+        let compressed = Wallet::from_private_key(&uncompressed.private_key, true)?;
+        assert!(!uncompressed.is_compressed() && compressed.is_compressed());
+        assert_eq!(compressed.public_key.len(), 33);
+        assert_ne!(compressed.address(), uncompressed.address());
+
+        let msg = b"message";
+        for wallet in [&uncompressed, &compressed, &Wallet::new_compressed()] {
+            let signature = wallet.sign_data(msg);
+            assert!(Wallet::verify_data(&wallet.public_key, msg, &signature));
+            assert!(!Wallet::verify_data(
+                &wallet.public_key,
+                b"other",
+                &signature
+            ));
+            let stored: Wallet = bincode::deserialize(&bincode::serialize(wallet)?)?;
+            assert_eq!(stored.is_compressed(), wallet.is_compressed());
+            assert_eq!(stored.address(), wallet.address());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn wif_round_trips_and_rejects_bad_checksum_or_version() -> Result<(), ChainError> {
+        for wallet in [Wallet::new(), Wallet::new_compressed()] {
+            let imported = Wallet::import_wif(&wallet.export_wif())?;
+            assert_eq!(imported.private_key, wallet.private_key);
+            assert_eq!(imported.public_key, wallet.public_key);
+            assert_eq!(imported.is_compressed(), wallet.is_compressed());
+        }
+
+        let encode = |bytes: &[u8]| {
+            bs58::encode(bytes)
+                .with_alphabet(bs58::Alphabet::BITCOIN)
+                .into_string()
+        };
+        let wallet = Wallet::new_compressed();
+        let mut decoded = bs58::decode(wallet.export_wif())
+            .with_alphabet(bs58::Alphabet::BITCOIN)
+            .into_vec()
+            .unwrap();
+        *decoded.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            Wallet::import_wif(&encode(&decoded)),
+            Err(ChainError::InvalidWif {
+                reason: "bad checksum"
+            })
+        ));
+
+        // Testnet version byte with a valid checksum
+        let mut versioned = vec![0xef];
+        versioned.extend_from_slice(&wallet.private_key);
+        versioned.extend(Wallet::checksum(&versioned));
+        assert!(matches!(
+            Wallet::import_wif(&encode(&versioned)),
+            Err(ChainError::InvalidWif {
+                reason: "wrong version byte"
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn address_decodes_to_known_pub_key_hash() -> Result<(), ChainError> {
+        // Address of the key Bitcoin's genesis block pays and its pub key hash
+        let address = ByteBuf::from("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+        let hash = hex::decode("62e907b15cbf27d5425399ebf6f0fb50ebb88f18").unwrap();
+        assert_eq!(Wallet::address_to_pub_key_hash(&address)?, hash);
+
+        for malformed in [
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb",
+            "1A1zP1eP5QGefi2D0",
+            "",
+        ] {
+            assert!(matches!(
+                Wallet::address_to_pub_key_hash(&ByteBuf::from(malformed)),
+                Err(ChainError::InvalidAddress)
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn address_to_hash_and_back_gives_original_address() -> Result<(), ChainError> {
+        for _ in 0..8 {
+            let address = Wallet::new().address();
+            let hash = Wallet::address_to_pub_key_hash(&address)?;
+            assert_eq!(hash.len(), Wallet::PUB_KEY_HASH_LEN);
+            assert_eq!(Wallet::address_from_pub_key_hash(&hash), address);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn address_parses_valid_and_rejects_malformed() -> Result<(), ChainError> {
+        let wallet = Wallet::new();
+        let encoded = String::from_utf8(wallet.address().into_vec()).unwrap();
+        let address: Address = encoded.parse()?;
+        assert_eq!(address.to_string(), encoded);
+        assert_eq!(
+            address.pub_key_hash(),
+            Wallet::hash_pub_key(&wallet.public_key)
+        );
+        assert_eq!(Address::from_pub_key_hash(&address.pub_key_hash()), address);
+
+        // Flipping the last character breaks the checksum
+        let mut tampered = encoded.clone();
+        let last = tampered.pop().unwrap();
+        tampered.push(if last == '1' { '2' } else { '1' });
+        for malformed in ["", "0OIl", &encoded[..encoded.len() - 1], &tampered] {
+            assert!(matches!(
+                malformed.parse::<Address>(),
+                Err(ChainError::InvalidAddress)
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn stores_at_different_paths_keep_their_own_wallets() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let first = dir.path().join("first").join(Wallets::WALLETS_FILE);
+        let second = dir.path().join("second").join(Wallets::WALLETS_FILE);
+        std::fs::create_dir_all(first.parent().unwrap())?;
+        std::fs::create_dir_all(second.parent().unwrap())?;
+        let wallet = Wallet::new();
+
+        Wallets::insert(&first, &wallet)?;
+
+        assert!(Wallets::open(&first)?.get(&wallet.address()).is_some());
+        assert!(Wallets::open(&second)?.get(&wallet.address()).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn mnemonic_and_index_always_derive_same_address() -> Result<(), ChainError> {
+        let phrase = Wallet::generate_mnemonic();
+        let first = Wallet::from_mnemonic(&phrase, 0)?;
+
+        assert_eq!(
+            Wallet::from_mnemonic(&phrase, 0)?.address(),
+            first.address()
+        );
+        assert_ne!(
+            Wallet::from_mnemonic(&phrase, 1)?.address(),
+            first.address()
+        );
+        assert_ne!(
+            Wallet::from_mnemonic(&Wallet::generate_mnemonic(), 0)?.address(),
+            first.address()
+        );
+        assert_eq!(
+            Wallet::from_seed(b"seed", 7).private_key,
+            Wallet::from_seed(b"seed", 7).private_key
+        );
+        assert!(matches!(
+            Wallet::from_mnemonic("not a valid phrase", 0),
+            Err(ChainError::InvalidMnemonic)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn removing_one_of_two_wallets_keeps_the_other() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(Wallets::WALLETS_FILE);
+        let (kept, removed) = (Wallet::new(), Wallet::new());
+        Wallets::insert(&path, &kept)?;
+        Wallets::insert(&path, &removed)?;
+
+        assert!(Wallets::remove(&path, &removed.address())?);
+
+        let wallets = Wallets::open(&path)?;
+        assert_eq!(wallets.wallets.len(), 1);
+        assert!(wallets.get(&kept.address()).is_some());
+        assert!(wallets.get(&removed.address()).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn open_fails_on_encrypted_store() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(Wallets::WALLETS_FILE);
+        let mut wallets = Wallets::open(&path)?;
+        wallets.add(&Wallet::new());
+        wallets.save_encrypted("passphrase")?;
+
+        assert!(matches!(Wallets::open(&path), Err(ChainError::Decryption)));
+        assert_eq!(
+            Wallets::open_encrypted(&path, "passphrase")?.wallets.len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn open_encrypted_fails_on_wrong_passphrase() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(Wallets::WALLETS_FILE);
+        let mut wallets = Wallets::open(&path)?;
+        wallets.add(&Wallet::new());
+        wallets.save_encrypted("passphrase")?;
+
+        assert!(matches!(
+            Wallets::open_encrypted(&path, "wrong"),
+            Err(ChainError::Decryption)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn plaintext_insert_into_encrypted_store_is_refused() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(Wallets::WALLETS_FILE);
+        let mut wallets = Wallets::open(&path)?;
+        wallets.add(&Wallet::new());
+        wallets.save_encrypted("passphrase")?;
+
+        assert!(matches!(
+            Wallets::insert(&path, &Wallet::new()),
+            Err(ChainError::Decryption)
+        ));
+        assert_eq!(
+            Wallets::open_encrypted(&path, "passphrase")?.wallets.len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn insert_and_remove_report_through_result() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(Wallets::WALLETS_FILE);
+        let wallet = Wallet::new();
+
+        Wallets::insert(&path, &wallet)?;
+        assert!(Wallets::open(&path)?.get(&wallet.address()).is_some());
+        assert!(Wallets::remove(&path, &wallet.address())?);
+        assert!(!Wallets::remove(&path, &wallet.address())?);
+        Ok(())
+    }
+
+    #[test]
+    fn open_read_only_reads_legacy_file_without_importing_it() -> Result<(), ChainError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(Wallets::WALLETS_FILE);
+        let wallet = Wallet::new();
+        let mut legacy = Wallets {
+            wallets: HashMap::new(),
+            path: path.clone(),
+        };
+        legacy.add(&wallet);
+        std::fs::write(&path, serialize(&legacy)?)?;
+
+        let wallets = Wallets::open_read_only(&path)?;
+
+        assert!(wallets.get(&wallet.address()).is_some());
+        assert!(!path.with_extension("kv").exists());
+        Ok(())
+    }
+}
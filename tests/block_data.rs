@@ -0,0 +1,79 @@
+use std::fmt::Display;
+
+use rust_chain::{
+    block::Block, blockchain::Blockchain, error::ChainError, genesis::GenesisConfig, Blockchainable,
+};
+use serde::{Deserialize, Serialize};
+
+const ADDRESS: &str = "1MBnzAR1oDnakY5fKLWvDeFD87PhPH9q1f";
+
+/// Same shape as the example data of the CLI
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Data {
+    field: String,
+}
+
+impl Blockchainable for Data {
+    fn genesis_data() -> Self {
+        Data {
+            field: "GENESIS DATA".to_string(),
+        }
+    }
+
+    fn default_data() -> Self {
+        Data {
+            field: String::new(),
+        }
+    }
+}
+
+impl Display for Data {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.field)
+    }
+}
+
+fn chain() -> Result<Blockchain<Data>, ChainError> {
+    let db = sled::Config::new().temporary(true).open()?;
+    let mut blockchain = Blockchain::<Data>::from_db(db, &GenesisConfig::new(ADDRESS), 1)?;
+    blockchain.miner_address = Some(ADDRESS.to_string());
+    Ok(blockchain)
+}
+
+#[test]
+fn data_rides_in_blocks_and_back() -> Result<(), ChainError> {
+    let mut blockchain = chain()?;
+    let genesis = blockchain.get_block_by_height(0).unwrap();
+    assert_eq!(genesis.data, Data::genesis_data());
+
+    let hash = blockchain.mine_block_with_data(Data {
+        field: "hello".to_string(),
+    })?;
+    blockchain.mine_block()?;
+
+    let block = blockchain.get_block(&hash).unwrap();
+    assert_eq!(block.data.field, "hello");
+    let decoded: Block<Data> = bincode::deserialize(&bincode::serialize(&block)?)?;
+    assert_eq!(decoded.data, block.data);
+    assert_eq!(decoded.hash, block.hash);
+    assert_eq!(
+        blockchain.get_block_by_height(2).unwrap().data,
+        Data::default_data()
+    );
+    Ok(())
+}
+
+#[test]
+fn changing_data_breaks_the_seal() -> Result<(), ChainError> {
+    let mut blockchain = chain()?;
+    let genesis = blockchain.get_block_by_height(0).unwrap();
+    let hash = blockchain.mine_block_with_data(Data {
+        field: "hello".to_string(),
+    })?;
+
+    let mut block = blockchain.get_block(&hash).unwrap();
+    assert!(block.validate(Some(&genesis)).is_ok());
+    block.data.field = "tampered".to_string();
+    assert!(block.validate(Some(&genesis)).is_err());
+    Ok(())
+}
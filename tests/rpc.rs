@@ -0,0 +1,138 @@
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+};
+
+use rust_chain::{
+    blockchain::Blockchain,
+    genesis::GenesisConfig,
+    rpc::{RpcServer, MAX_BODY_BYTES},
+    transaction::Transaction,
+    wallet::{Wallet, Wallets},
+    Blockchainable,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+#[derive(Serialize, Deserialize)]
+struct Data(String);
+
+impl Display for Data {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Blockchainable for Data {
+    fn genesis_data() -> Self {
+        Data("Genesis".to_string())
+    }
+}
+
+fn address(wallet: &Wallet) -> String {
+    String::from_utf8(wallet.address().into_vec()).unwrap()
+}
+
+/// Serves a chain at 1 difficulty bit on an ephemeral loopback port, returns its address along
+/// with the address genesis pays, which also mines, the hex hash of genesis and a handle to
+/// the served chain
+fn start_server(wallets_dir: &tempfile::TempDir) -> (SocketAddr, String, String, RpcServer<Data>) {
+    let wallet = Wallet::new();
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let mut blockchain =
+        Blockchain::<Data>::from_db(db, &GenesisConfig::new(&address(&wallet)), 1).unwrap();
+    blockchain.wallets_file = wallets_dir.path().join(Wallets::WALLETS_FILE);
+    Wallets::insert(&blockchain.wallets_file, &wallet).unwrap();
+    blockchain.miner_address = Some(address(&wallet));
+
+    let genesis = hex::encode(&blockchain.tip);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = RpcServer::new(blockchain);
+    let served = server.clone();
+    thread::spawn(move || served.serve(listener));
+    (addr, address(&wallet), genesis, server)
+}
+
+/// Status line and body of the response to a raw HTTP request
+fn send_raw(addr: SocketAddr, request: &[u8]) -> (String, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+fn call(addr: SocketAddr, method: &str, params: Value) -> Value {
+    let body = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 }).to_string();
+    let request = format!(
+        "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    );
+    let (status, body) = send_raw(addr, request.as_bytes());
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let response: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(response["id"], 1);
+    response
+}
+
+#[test]
+fn serves_every_method() {
+    let wallets_dir = tempfile::tempdir().unwrap();
+    let (addr, funded, genesis, server) = start_server(&wallets_dir);
+    let to = address(&Wallet::new());
+
+    assert_eq!(call(addr, "getblockcount", json!([]))["result"], 0);
+    assert_eq!(
+        call(addr, "getbalance", json!([funded]))["result"],
+        Transaction::SUBSIDY
+    );
+    assert_eq!(
+        call(addr, "getbalance", json!({ "address": to }))["result"],
+        0
+    );
+    assert!(call(addr, "estimatefee", json!([]))["result"].is_u64());
+
+    let txid = call(addr, "sendtoaddress", json!([funded, to, 3, 1]))["result"].clone();
+    assert!(txid.is_string());
+    assert_eq!(
+        call(addr, "getrawmempool", json!([]))["result"],
+        json!([txid])
+    );
+
+    server.blockchain().mine_block().unwrap();
+    assert_eq!(call(addr, "getblockcount", json!([]))["result"], 1);
+    assert_eq!(call(addr, "getbalance", json!([to]))["result"], 3);
+    assert_eq!(call(addr, "getrawmempool", json!([]))["result"], json!([]));
+
+    let block = call(addr, "getblock", json!([genesis]))["result"].clone();
+    assert_eq!(block["height"], 0);
+    assert_eq!(call(addr, "getblock", json!(["00"]))["result"], Value::Null);
+    for (method, params) in [
+        ("getblock", json!(["not hex"])),
+        ("getbalance", json!(["1nvalid"])),
+    ] {
+        let malformed = call(addr, method, params);
+        assert_eq!(malformed["error"]["code"], rust_chain::rpc::INVALID_PARAMS);
+    }
+    let unknown = call(addr, "getnothing", json!([]));
+    assert_eq!(unknown["error"]["code"], rust_chain::rpc::METHOD_NOT_FOUND);
+}
+
+#[test]
+fn rejects_oversized_requests() {
+    let wallets_dir = tempfile::tempdir().unwrap();
+    let (addr, _, _, _) = start_server(&wallets_dir);
+    let request = format!(
+        "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+        MAX_BODY_BYTES + 1
+    );
+    let (status, _) = send_raw(addr, request.as_bytes());
+    assert_eq!(status, "HTTP/1.1 413 Payload Too Large");
+
+    // Server keeps serving after rejecting one
+    assert_eq!(call(addr, "getblockcount", json!([]))["result"], 0);
+}